@@ -25,7 +25,10 @@ pub mod opcodes;
 use opcodes::*;
 use rppal::{gpio, spi};
 use std::fmt;
-use std::{thread::sleep, time::Duration};
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -145,6 +148,70 @@ impl Lora {
         Ok(len)
     }
 
+    // Wait for the end of the transmission, false on timeout
+    pub fn wait_tx_done(&mut self, timeout: Duration) -> Result<bool> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if self.dio0.is_high() {
+                self.single_write(Reg::IrqFlags, IrqFlag::TxDone as u8)?;
+                return Ok(true);
+            }
+            sleep(Duration::from_millis(1));
+        }
+        Ok(false)
+    }
+
+    // Back to continuous reception, e.g. after a transmission
+    pub fn receive_continuous(&mut self) -> Result<()> {
+        self.op_mode(Mode::Stdby)?;
+        self.single_write(
+            Reg::DioMapping1,
+            DioMapping1 {
+                dio0_mapping: Dio0::RxDone,
+                dio1_mapping: Dio1::RxTimeout,
+                dio2_mapping: Dio2::FhssChangeChannel,
+                dio3_mapping: Dio3::CadDone,
+            }
+            .serialize(),
+        )?;
+        // clear and unmask all radio IRQs
+        self.single_write(Reg::IrqFlags, 0xFF)?;
+        self.single_write(Reg::IrqFlagsMask, 0x00)?;
+        self.copy_reg(Reg::FifoRxBaseAddr, Reg::FifoAddrPtr)?;
+        self.op_mode(Mode::RxContinuous)
+    }
+
+    // Move to another channel (125 kHz bandwidth), with the IQ polarity of
+    // LoRaWAN downlinks if invert_iq. Leaves the radio in standby.
+    pub fn set_channel(&mut self, freq: u32, sf: SpreadingFactor, invert_iq: bool) -> Result<()> {
+        self.op_mode(Mode::Stdby)?;
+
+        let frf = Frf { freq }.serialize().map_err(Error::OpCode)?;
+        self.single_write(Reg::FrfMsb, frf.0)?;
+        self.single_write(Reg::FrfMid, frf.1)?;
+        self.single_write(Reg::FrfLsb, frf.2)?;
+
+        let modem_config2 = self.single_read(Reg::ModemConfig2)?;
+        self.single_write(Reg::ModemConfig2, (sf as u8) << 4 | (modem_config2 & 0x0F))?;
+        // low data rate optimization, mandated for symbols longer than 16 ms
+        let low_data_rate_optimize = matches!(sf, SpreadingFactor::SF11 | SpreadingFactor::SF12);
+        let modem_config3 = self.single_read(Reg::ModemConfig3)?;
+        self.single_write(
+            Reg::ModemConfig3,
+            (modem_config3 & !0x08) | (low_data_rate_optimize as u8) << 3,
+        )?;
+
+        // RegInvertIQ(2) values of the Semtech LoRaMac-node SX1276 driver
+        let invert = self.single_read(Reg::InvertIQ)? & 0xBE;
+        if invert_iq {
+            self.single_write(Reg::InvertIQ, invert | 0x01)?;
+            self.single_write(Reg::InvertIQ2, 0x19)
+        } else {
+            self.single_write(Reg::InvertIQ, invert)?;
+            self.single_write(Reg::InvertIQ2, 0x1D)
+        }
+    }
+
     pub fn try_receive(&mut self) -> Result<Option<Reception>> {
         if self.dio0.is_low() {
             return Ok(None);
@@ -157,7 +224,7 @@ impl Lora {
             if reg_value & 0x80 != 0 {
                 // The SNR sign bit is 1
                 // Invert and divide by 4
-                -((!reg_value + 1 >> 2) as i32)
+                -(((!reg_value + 1) >> 2) as i32)
             } else {
                 // Divide by 4
                 (reg_value >> 2) as i32
@@ -214,13 +281,13 @@ impl Lora {
             PaConfig {
                 pa_select_boost: true,
                 max_power: 0x00,
-                output_power: (if pw < 2 {
+                output_power: if pw < 2 {
                     0
                 } else if pw > 17 {
                     15
                 } else {
                     pw - 2
-                }) as u8,
+                },
             }
             .serialize()
             .map_err(Error::OpCode)?,
@@ -202,7 +202,7 @@ impl Frf {
         if frf > (1u64 << 24) - 1 {
             return Err(Error::FrequencyOutOfRange(self.freq));
         }
-        Ok(((frf >> 16) as u8, (frf >> 8) as u8, (frf >> 0) as u8))
+        Ok(((frf >> 16) as u8, (frf >> 8) as u8, frf as u8))
     }
 }
 
@@ -223,9 +223,7 @@ impl PaConfig {
         if self.max_power > 0x7 {
             return Err(Error::MaxPowerOverflow(self.max_power));
         }
-        Ok((self.pa_select_boost as u8) << 7
-            | (self.max_power as u8) << 4
-            | (self.output_power & 0xF))
+        Ok((self.pa_select_boost as u8) << 7 | self.max_power << 4 | (self.output_power & 0xF))
     }
 }
 
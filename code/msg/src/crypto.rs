@@ -0,0 +1,231 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// End-to-end AES-CTR payload encryption between an end device and its
+// virtual device. Only the payload is encrypted: gateways keep routing on
+// addr/fcnt without ever holding the application key.
+//
+// Keystream blocks follow the LoRaWAN FRMPayload scheme (see [LoRaWAN 1.0.4,
+// Sec. 4.3.3]), widened to the 64 bit CLUES device address:
+//
+//   A_i = 0x01 | dir | addr (8, LE) | fcnt (4, LE) | i (2, BE)
+//
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+use crate::{Error, Result};
+
+pub const KEY_LEN: usize = 16;
+
+const BLOCK_LEN: usize = 16;
+
+// Frame direction, part of the counter block so that uplink and downlink
+// frames with the same fcnt never share a keystream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dir {
+    Up = 0x00,
+    Down = 0x01,
+}
+
+// Per-device 128 bit application key
+#[derive(Clone, PartialEq, Eq)]
+pub struct AppKey([u8; KEY_LEN]);
+
+impl AppKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    // Parse a key from its 32 hex digits representation
+    pub fn from_hex(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.len() != 2 * KEY_LEN || !s.is_ascii() {
+            return Err(Error::BadKey);
+        }
+        let mut key = [0u8; KEY_LEN];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| Error::BadKey)?;
+        }
+        Ok(Self(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    // XOR the payload with the keystream for (dir, addr, fcnt).
+    // CTR mode is symmetric: the same call encrypts and decrypts.
+    pub fn apply(&self, dir: Dir, addr: u64, fcnt: u32, payload: &mut [u8]) {
+        let cipher = Aes128::new(GenericArray::from_slice(&self.0));
+
+        for (i, chunk) in payload.chunks_mut(BLOCK_LEN).enumerate() {
+            let mut block = [0u8; BLOCK_LEN];
+            block[0] = 0x01;
+            block[1] = dir as u8;
+            block[2..10].copy_from_slice(&addr.to_le_bytes());
+            block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+            block[14..16].copy_from_slice(&(i as u16 + 1).to_be_bytes());
+
+            let mut block = GenericArray::from(block);
+            cipher.encrypt_block(&mut block);
+
+            for (b, k) in chunk.iter_mut().zip(block.iter()) {
+                *b ^= k;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for AppKey {
+    // Never leak key material in logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AppKey(..)")
+    }
+}
+
+// Application keys of a set of end devices, one "<addr> <key>" pair of hex
+// strings per line. Empty lines and lines starting with '#' are skipped, an
+// address listed twice is an error.
+#[derive(Clone, Debug, Default)]
+pub struct AppKeys(HashMap<u64, AppKey>);
+
+impl AppKeys {
+    pub fn parse(text: &str) -> Result<Self> {
+        parse_key_table(text, |addr, key| {
+            Some((
+                u64::from_str_radix(addr, 16).ok()?,
+                AppKey::from_hex(key).ok()?,
+            ))
+        })
+        .map(Self)
+    }
+
+    pub fn get(&self, addr: u64) -> Option<&AppKey> {
+        self.0.get(&addr)
+    }
+}
+
+// Key table of "<id> <key>" pairs of strings, one per line, as read by AppKeys,
+// the LoRaWAN NwkSKeys and the trusted publishers of the gateway. Empty lines
+// and lines starting with '#' are skipped. entry parses a pair, None if it is
+// invalid; an id listed twice is an error too.
+pub fn parse_key_table<K, V, F>(text: &str, mut entry: F) -> Result<HashMap<K, V>>
+where
+    K: Eq + Hash,
+    F: FnMut(&str, &str) -> Option<(K, V)>,
+{
+    let mut table = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(id), Some(key), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(Error::BadKeyTable(n + 1));
+        };
+        let (id, key) = entry(id, key).ok_or(Error::BadKeyTable(n + 1))?;
+        if table.insert(id, key).is_some() {
+            return Err(Error::BadKeyTable(n + 1));
+        }
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: u64 = 0x0102030405060708;
+    const FCNT: u32 = 0x0a0b0c0d;
+
+    fn key() -> AppKey {
+        AppKey::from_hex("000102030405060708090a0b0c0d0e0f").unwrap()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn aes_known_answer() {
+        // FIPS-197, appendix C.1
+        let cipher = Aes128::new(GenericArray::from_slice(key().as_bytes()));
+        let mut block = GenericArray::from(0x00112233445566778899aabbccddeeffu128.to_be_bytes());
+        cipher.encrypt_block(&mut block);
+        assert_eq!(hex(&block), "69c4e0d86a7b0430d8cdb78070b4c55a");
+    }
+
+    #[test]
+    fn ctr_known_answer() {
+        // two blocks, the second one partial
+        let plain: Vec<u8> = (0..20).collect();
+        let mut payload = plain.clone();
+        key().apply(Dir::Up, ADDR, FCNT, &mut payload);
+        assert_eq!(hex(&payload), "05f8f116a2c42b528370766a4df3343187658550");
+
+        let mut payload = plain;
+        key().apply(Dir::Down, ADDR, FCNT, &mut payload);
+        assert_eq!(hex(&payload), "669a513108b2d15576f8e11c6fe99b30b595a9be");
+    }
+
+    #[test]
+    fn apply_twice() {
+        let plain: Vec<u8> = (0..100).collect();
+        let mut payload = plain.clone();
+        key().apply(Dir::Up, ADDR, FCNT, &mut payload);
+        assert_ne!(payload, plain);
+        key().apply(Dir::Up, ADDR, FCNT, &mut payload);
+        assert_eq!(payload, plain);
+
+        // another fcnt is another keystream
+        key().apply(Dir::Up, ADDR, FCNT, &mut payload);
+        key().apply(Dir::Up, ADDR, FCNT + 1, &mut payload);
+        assert_ne!(payload, plain);
+    }
+
+    #[test]
+    fn key_table() {
+        let keys = AppKeys::parse(
+            "# addr key\n\n\
+             1 000102030405060708090a0b0c0d0e0f\n\
+             \t00000002  ffffffffffffffffffffffffffffffff \n",
+        )
+        .unwrap();
+        assert_eq!(keys.get(1), Some(&key()));
+        assert_eq!(keys.get(2).unwrap().as_bytes(), &[0xff; KEY_LEN]);
+        assert!(keys.get(3).is_none());
+    }
+
+    #[test]
+    fn bad_key_tables() {
+        let bad = |text: &str, line: usize| match AppKeys::parse(text) {
+            Err(Error::BadKeyTable(n)) => assert_eq!(n, line, "{text}"),
+            other => panic!("{text}: {other:?}"),
+        };
+        let key = "000102030405060708090a0b0c0d0e0f";
+        bad(&format!("1 {}", &key[1..]), 1);
+        bad(&format!("1 {key}0"), 1);
+        bad("1 000102030405060708090a0b0c0d0e0g", 1);
+        bad(&format!("# comment\nx1 {key}"), 2);
+        bad(&format!("1 {key} extra"), 1);
+        bad("1", 1);
+        bad(&format!("1 {key}\n01 {key}"), 2);
+    }
+}
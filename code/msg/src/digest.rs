@@ -0,0 +1,49 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Reception digest exchanged between neighbouring gateways, so that a single
+// one of them delivers an uplink heard by many
+
+use serde::{Deserialize, Serialize};
+
+use crate::uplink::{RxMeta, Uplink};
+use crate::{Error, Result};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Digest {
+    pub addr: u64,
+    pub fcnt: u32,
+    pub meta: RxMeta, // reception at the sending gateway
+    pub home: bool,   // the sending gateway is the home of the end device
+}
+
+impl Digest {
+    pub fn new(up: &Uplink, home: bool) -> Self {
+        Self {
+            addr: up.msg.addr,
+            fcnt: up.msg.fcnt,
+            meta: up.meta.clone(),
+            home,
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        bincode::serialize(&self).map_err(Error::Fmt)
+    }
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<Digest> {
+    bincode::deserialize(bytes).map_err(Error::Fmt)
+}
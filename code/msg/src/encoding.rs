@@ -0,0 +1,191 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Wire encodings of messages and packets
+//
+// Bincode is the compact default, JSON and CBOR let external tools (scripts,
+// other end device stacks) produce and inspect frames.
+//
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Error, Result};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Bincode,
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    // Guess the encoding of a serialized Packet from its first byte: JSON
+    // objects start with '{', CBOR maps have major type 5, while bincode
+    // starts with the (small) enum variant index
+    pub fn detect(bytes: &[u8]) -> Self {
+        match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => Encoding::Json,
+            Some(0xA0..=0xBF) => Encoding::Cbor,
+            _ => Encoding::Bincode,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Bincode => "bincode",
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bincode" => Ok(Encoding::Bincode),
+            "json" => Ok(Encoding::Json),
+            "cbor" => Ok(Encoding::Cbor),
+            _ => Err(Error::UnknownEncoding(s.to_string())),
+        }
+    }
+}
+
+pub fn encode<T>(value: &T, encoding: Encoding) -> Result<Vec<u8>>
+where
+    T: Serialize,
+{
+    match encoding {
+        Encoding::Bincode => bincode::serialize(value).map_err(Error::Fmt),
+        Encoding::Json => serde_json::to_vec(value).map_err(Error::Json),
+        Encoding::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(value, &mut bytes)
+                .map_err(|e| Error::Cbor(e.to_string()))?;
+            Ok(bytes)
+        }
+    }
+}
+
+pub fn decode<T>(bytes: &[u8], encoding: Encoding) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    match encoding {
+        Encoding::Bincode => bincode::deserialize(bytes).map_err(Error::Fmt),
+        Encoding::Json => serde_json::from_slice(bytes).map_err(Error::Json),
+        Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|e| Error::Cbor(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::DevCmd;
+    use crate::{frag, Msg, Packet};
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Bincode, Encoding::Json, Encoding::Cbor];
+
+    fn packets() -> Vec<Packet> {
+        let msg = Msg {
+            addr: 0xabcd,
+            fcnt: 7,
+            port: 1,
+            payload: (0..40).collect(),
+        };
+        let fragment = frag::fragment(&msg, 16, 2).unwrap().remove(0);
+        vec![
+            Packet::Msg(msg.clone()),
+            Packet::Confirmed(msg.clone()),
+            Packet::Fragment(fragment.clone()),
+            Packet::Downlink(msg),
+            Packet::Ack { addr: 1, fcnt: 2 },
+            Packet::DevCtrl {
+                addr: 1,
+                cmd: DevCmd::Custom(vec![0, 255]),
+            },
+            Packet::ConfirmedFragment(fragment),
+        ]
+    }
+
+    #[test]
+    fn round_trips() {
+        for packet in packets() {
+            // Packet has no PartialEq, bincode is canonical
+            let expected = packet.serialize().unwrap();
+            for encoding in ENCODINGS {
+                let bytes = packet.serialize_as(encoding).unwrap();
+                assert_eq!(Encoding::detect(&bytes), encoding, "{packet:?}");
+                let decoded = crate::deserialize_packet_auto(&bytes).unwrap();
+                assert_eq!(decoded.serialize().unwrap(), expected, "{encoding}");
+                let decoded: Packet = decode(&bytes, encoding).unwrap();
+                assert_eq!(decoded.serialize().unwrap(), expected, "{encoding}");
+            }
+        }
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Encoding::detect(b" \n\t{\"Ack\":{}}"), Encoding::Json);
+        // maps of 0 to 23 entries, and of a length that follows
+        assert_eq!(Encoding::detect(&[0xa1, 0x63]), Encoding::Cbor);
+        assert_eq!(Encoding::detect(&[0xbf]), Encoding::Cbor);
+        // variant index of bincode
+        assert_eq!(Encoding::detect(&[0x04, 0, 0, 0]), Encoding::Bincode);
+        assert_eq!(Encoding::detect(b""), Encoding::Bincode);
+        assert_eq!(Encoding::detect(b"   "), Encoding::Bincode);
+    }
+
+    #[test]
+    fn wrong_encodings() {
+        let packet = &packets()[0];
+        let json = packet.serialize_as(Encoding::Json).unwrap();
+        let cbor = packet.serialize_as(Encoding::Cbor).unwrap();
+        assert!(matches!(
+            decode::<Packet>(&json, Encoding::Cbor),
+            Err(Error::Cbor(_))
+        ));
+        assert!(matches!(
+            decode::<Packet>(&cbor, Encoding::Json),
+            Err(Error::Json(_))
+        ));
+        assert!(decode::<Packet>(&json[..json.len() - 1], Encoding::Json).is_err());
+        assert!(decode::<Packet>(&cbor[..cbor.len() - 1], Encoding::Cbor).is_err());
+        let bincode = packet.serialize().unwrap();
+        assert!(decode::<Packet>(&bincode[..bincode.len() - 1], Encoding::Bincode).is_err());
+    }
+
+    #[test]
+    fn names() {
+        for encoding in ENCODINGS {
+            assert_eq!(encoding.to_string().parse::<Encoding>().unwrap(), encoding);
+        }
+        assert!(matches!(
+            "protobuf".parse::<Encoding>(),
+            Err(Error::UnknownEncoding(_))
+        ));
+    }
+}
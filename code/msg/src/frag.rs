@@ -0,0 +1,290 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Fragmentation of messages larger than one LoRa frame
+//
+// A message payload is split in `count` data fragments of `chunk` bytes (the
+// last one may be shorter). Optionally, every `group` consecutive data
+// fragments are followed by one parity fragment (their XOR, zero padded to
+// `chunk` bytes), so that one lost fragment per group is recovered without
+// retransmission.
+//
+//   index: 0 .. count                data fragments
+//   index: count .. count + parities parity fragments, one per group
+//
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Msg, Result};
+
+// Bytes added by the fragment header to each chunk once encoded as a bincode
+// Packet::Fragment (variant tag, addr, fcnt, port, index, count, group, len, data len)
+pub const PACKET_OVERHEAD: usize = 4 + 8 + 4 + 1 + 1 + 1 + 1 + 2 + 8;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Fragment {
+    pub addr: u64,
+    pub fcnt: u32, // frame counter of the whole message
+    pub port: u8,
+    pub index: u8,
+    pub count: u8, // number of data fragments
+    pub group: u8, // data fragments covered by each parity fragment, 0: no FEC
+    pub len: u16,  // whole payload length
+    pub data: Vec<u8>,
+}
+
+impl Fragment {
+    pub fn is_parity(&self) -> bool {
+        self.index >= self.count
+    }
+}
+
+fn parities(count: usize, group: usize) -> usize {
+    match group {
+        0 => 0,
+        _ => count.div_ceil(group),
+    }
+}
+
+fn xor_into(acc: &mut [u8], data: &[u8]) {
+    for (a, d) in acc.iter_mut().zip(data.iter()) {
+        *a ^= d;
+    }
+}
+
+// Split a message in fragments carrying at most `chunk` payload bytes each,
+// adding a parity fragment every `group` data fragments (0 disables FEC)
+pub fn fragment(msg: &Msg, chunk: usize, group: u8) -> Result<Vec<Fragment>> {
+    if chunk == 0 {
+        return Err(Error::BadChunkSize);
+    }
+    if msg.payload.len() > u16::MAX as usize {
+        return Err(Error::PayloadTooLarge(msg.payload.len()));
+    }
+
+    let chunks: Vec<&[u8]> = msg.payload.chunks(chunk).collect();
+    let count = chunks.len().max(1);
+    if count + parities(count, group as usize) > u8::MAX as usize {
+        return Err(Error::PayloadTooLarge(msg.payload.len()));
+    }
+
+    let frag = |index: usize, data: Vec<u8>| Fragment {
+        addr: msg.addr,
+        fcnt: msg.fcnt,
+        port: msg.port,
+        index: index as u8,
+        count: count as u8,
+        group,
+        len: msg.payload.len() as u16,
+        data,
+    };
+
+    let mut frags: Vec<Fragment> = (0..count)
+        .map(|i| frag(i, chunks.get(i).map(|c| c.to_vec()).unwrap_or_default()))
+        .collect();
+
+    if group > 0 {
+        for (p, members) in chunks.chunks(group as usize).enumerate() {
+            let mut parity = vec![0u8; chunk];
+            for data in members {
+                xor_into(&mut parity, data);
+            }
+            frags.push(frag(count + p, parity));
+        }
+    }
+
+    Ok(frags)
+}
+
+// Reassembly buffer of a single fragmented message
+pub struct Assembly {
+    addr: u64,
+    fcnt: u32,
+    port: u8,
+    count: u8,
+    group: u8,
+    len: u16,
+    data: Vec<Option<Vec<u8>>>,
+    parity: Vec<Option<Vec<u8>>>,
+}
+
+impl Assembly {
+    pub fn new(first: &Fragment) -> Self {
+        let count = first.count.max(1) as usize;
+        Self {
+            addr: first.addr,
+            fcnt: first.fcnt,
+            port: first.port,
+            count: first.count,
+            group: first.group,
+            len: first.len,
+            data: vec![None; count],
+            parity: vec![None; parities(count, first.group as usize)],
+        }
+    }
+
+    // Store a fragment, returning the whole message once it can be rebuilt.
+    // Fragments not matching the message layout of the first one are ignored.
+    pub fn push(&mut self, frag: Fragment) -> Option<Msg> {
+        if frag.addr != self.addr
+            || frag.fcnt != self.fcnt
+            || frag.port != self.port
+            || frag.count != self.count
+            || frag.group != self.group
+            || frag.len != self.len
+        {
+            return None;
+        }
+
+        let index = frag.index as usize;
+        if frag.is_parity() {
+            *self.parity.get_mut(index.checked_sub(self.data.len())?)? = Some(frag.data);
+        } else {
+            *self.data.get_mut(index)? = Some(frag.data);
+        }
+
+        self.recover();
+
+        if self.data.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let mut payload: Vec<u8> = self.data.iter().flatten().flatten().copied().collect();
+        payload.truncate(self.len as usize);
+        Some(Msg {
+            addr: self.addr,
+            fcnt: self.fcnt,
+            port: self.port,
+            payload,
+        })
+    }
+
+    // Rebuild the single missing data fragment of any group whose parity is known
+    fn recover(&mut self) {
+        let group = self.group as usize;
+        for (p, parity) in self.parity.iter().enumerate() {
+            let Some(parity) = parity else { continue };
+            let members = p * group..((p + 1) * group).min(self.data.len());
+
+            let missing: Vec<usize> = members
+                .clone()
+                .filter(|&i| self.data[i].is_none())
+                .collect();
+            if missing.len() != 1 {
+                continue;
+            }
+
+            let mut data = parity.clone();
+            for i in members {
+                if let Some(ref d) = self.data[i] {
+                    xor_into(&mut data, d);
+                }
+            }
+
+            // Only the last data fragment may be shorter than the chunk size
+            let missing = missing[0];
+            if missing == self.data.len() - 1 {
+                let tail = (self.len as usize).saturating_sub(missing * parity.len());
+                data.truncate(tail);
+            }
+            self.data[missing] = Some(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(len: usize) -> Msg {
+        Msg {
+            addr: 0x1,
+            fcnt: 7,
+            port: 1,
+            payload: (0..len).map(|i| (i * 31 % 251) as u8).collect(),
+        }
+    }
+
+    fn reassemble(frags: impl IntoIterator<Item = Fragment>) -> Option<Msg> {
+        let mut frags = frags.into_iter().peekable();
+        let mut assembly = Assembly::new(frags.peek()?);
+        frags.find_map(|frag| assembly.push(frag))
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, 16, 17, 100] {
+            let msg = msg(len);
+            let frags = fragment(&msg, 16, 0).unwrap();
+            assert_eq!(frags.len(), len.div_ceil(16).max(1));
+            assert!(frags.iter().all(|f| !f.is_parity()));
+            assert_eq!(reassemble(frags.clone()).unwrap().payload, msg.payload);
+            // out of order
+            assert_eq!(
+                reassemble(frags.into_iter().rev()).unwrap().payload,
+                msg.payload
+            );
+        }
+    }
+
+    #[test]
+    fn parity_recovers_one_loss_per_group() {
+        let msg = msg(100); // 7 data fragments (the last one of 4 bytes), 2 parities
+        let frags = fragment(&msg, 16, 4).unwrap();
+        assert_eq!(frags.len(), 7 + 2);
+        assert_eq!(frags.iter().filter(|f| f.is_parity()).count(), 2);
+        for (a, b) in [(0, 4), (3, 6), (1, 5)] {
+            let kept = frags
+                .iter()
+                .filter(|f| f.index != a && f.index != b)
+                .cloned();
+            assert_eq!(
+                reassemble(kept).unwrap().payload,
+                msg.payload,
+                "lost {a}, {b}"
+            );
+        }
+    }
+
+    #[test]
+    fn parity_cannot_recover_two_losses_in_a_group() {
+        let msg = msg(100);
+        let frags = fragment(&msg, 16, 4).unwrap();
+        let kept = frags.into_iter().filter(|f| f.index != 0 && f.index != 1);
+        assert!(reassemble(kept).is_none());
+    }
+
+    #[test]
+    fn foreign_fragments_are_ignored() {
+        let msg = msg(40);
+        let mut frags = fragment(&msg, 16, 0).unwrap();
+        let mut assembly = Assembly::new(&frags[0]);
+        let mut other = frags[1].clone();
+        other.fcnt += 1;
+        assert!(assembly.push(other).is_none());
+        let last = frags.pop().unwrap();
+        assert!(frags.into_iter().all(|f| assembly.push(f).is_none()));
+        assert_eq!(assembly.push(last).unwrap().payload, msg.payload);
+    }
+
+    #[test]
+    fn bad_sizes() {
+        assert!(matches!(fragment(&msg(10), 0, 0), Err(Error::BadChunkSize)));
+        assert!(matches!(
+            fragment(&msg(300), 1, 0),
+            Err(Error::PayloadTooLarge(300))
+        ));
+    }
+}
@@ -13,6 +13,18 @@
 // limitations under the License.
 //
 
+pub mod crypto;
+pub mod digest;
+pub mod encoding;
+pub mod frag;
+pub mod lorawan;
+pub mod lpp;
+pub mod measurement;
+pub mod message;
+pub mod senml;
+pub mod stream;
+pub mod uplink;
+
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -21,12 +33,48 @@ type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     Fmt(bincode::Error),
+    BadKey,
+    BadKeyTable(usize),
+    FrameTooShort(usize),
+    UnsupportedMType(u8),
+    FOptsTooLong(usize),
+    BadMic,
+    PayloadTooLarge(usize),
+    BadChunkSize,
+    BadLpp(usize),
+    UnknownLppType(u8),
+    LppOutOfRange(&'static str),
+    BadSenml,
+    Json(serde_json::Error),
+    Cbor(String),
+    Io(std::io::Error),
+    FrameTooLarge(usize),
+    Closed,
+    UnknownEncoding(String),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Error::Fmt(ref err) => write!(f, "Bad msg fmt: {err}"),
+            Error::BadKey => write!(f, "Bad key: expected {} hex digits", 2 * crypto::KEY_LEN),
+            Error::BadKeyTable(line) => write!(f, "Bad key table entry at line {line}"),
+            Error::FrameTooShort(len) => write!(f, "Frame too short ({len} bytes)"),
+            Error::UnsupportedMType(v) => write!(f, "Unsupported LoRaWAN MType: {v:03b}"),
+            Error::FOptsTooLong(len) => write!(f, "LoRaWAN FOpts too long ({len} bytes)"),
+            Error::BadMic => write!(f, "LoRaWAN MIC mismatch"),
+            Error::PayloadTooLarge(len) => write!(f, "Payload too large to fragment ({len} bytes)"),
+            Error::BadChunkSize => write!(f, "Fragment chunk size must not be 0"),
+            Error::BadLpp(offset) => write!(f, "Truncated LPP reading at byte {offset}"),
+            Error::UnknownLppType(v) => write!(f, "Unknown LPP data type: {v}"),
+            Error::LppOutOfRange(name) => write!(f, "LPP {name} reading out of range"),
+            Error::BadSenml => write!(f, "Bad SenML record"),
+            Error::Json(ref err) => write!(f, "JSON error: {err}"),
+            Error::Cbor(ref err) => write!(f, "CBOR error: {err}"),
+            Error::Io(ref err) => write!(f, "Stream I/O error: {err}"),
+            Error::FrameTooLarge(len) => write!(f, "Stream frame too large ({len} bytes)"),
+            Error::Closed => write!(f, "Stream closed"),
+            Error::UnknownEncoding(ref name) => write!(f, "Unknown encoding: {name}"),
         }
     }
 }
@@ -35,6 +83,7 @@ impl fmt::Display for Error {
 pub struct Msg {
     pub addr: u64,
     pub fcnt: u32,
+    pub port: u8, // multiplexes applications on the same device
     pub payload: Vec<u8>,
 }
 
@@ -49,15 +98,129 @@ impl Msg {
     {
         bincode::serialize_into(writer, &self).map_err(Error::Fmt)
     }
+
+    pub fn serialize_as(&self, encoding: encoding::Encoding) -> Result<Vec<u8>> {
+        encoding::encode(self, encoding)
+    }
+
+    // Encrypt the payload in place, addr and fcnt stay in clear for routing
+    pub fn encrypt(&mut self, key: &crypto::AppKey, dir: crypto::Dir) {
+        key.apply(dir, self.addr, self.fcnt, &mut self.payload)
+    }
+
+    pub fn decrypt(&mut self, key: &crypto::AppKey, dir: crypto::Dir) {
+        key.apply(dir, self.addr, self.fcnt, &mut self.payload)
+    }
 }
 
 pub fn deserialize(bytes: &[u8]) -> Result<Msg> {
     bincode::deserialize(bytes).map_err(Error::Fmt)
 }
 
+pub fn deserialize_as(bytes: &[u8], encoding: encoding::Encoding) -> Result<Msg> {
+    encoding::decode(bytes, encoding)
+}
+
 pub fn deserialize_from<R>(reader: R) -> Result<Msg>
 where
     R: std::io::Read,
 {
     bincode::deserialize_from(reader).map_err(Error::Fmt)
 }
+
+// Borrowed view of a serialized Msg: same wire format, but the payload points
+// into the input buffer instead of being copied
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct MsgRef<'a> {
+    pub addr: u64,
+    pub fcnt: u32,
+    pub port: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> MsgRef<'a> {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        bincode::serialize(&self).map_err(Error::Fmt)
+    }
+
+    pub fn to_msg(&self) -> Msg {
+        Msg {
+            addr: self.addr,
+            fcnt: self.fcnt,
+            port: self.port,
+            payload: self.payload.to_vec(),
+        }
+    }
+}
+
+impl<'a> From<&'a Msg> for MsgRef<'a> {
+    fn from(msg: &'a Msg) -> Self {
+        Self {
+            addr: msg.addr,
+            fcnt: msg.fcnt,
+            port: msg.port,
+            payload: &msg.payload,
+        }
+    }
+}
+
+pub fn deserialize_ref(bytes: &[u8]) -> Result<MsgRef<'_>> {
+    bincode::deserialize(bytes).map_err(Error::Fmt)
+}
+
+// Radio frame: a whole message or a fragment of a larger one
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Packet {
+    Msg(Msg),
+    // Uplink to be acknowledged by the gateway
+    Confirmed(Msg),
+    Fragment(frag::Fragment),
+    // Gateway to end device, in a receive window after an uplink
+    Downlink(Msg),
+    // Acknowledgement of a confirmed uplink
+    Ack { addr: u64, fcnt: u32 },
+    // Configuration command for the end device
+    DevCtrl { addr: u64, cmd: message::DevCmd },
+    // Fragment of an uplink to be acknowledged once reassembled
+    ConfirmedFragment(frag::Fragment),
+}
+
+impl Packet {
+    // End device the packet is from or to
+    pub fn addr(&self) -> u64 {
+        match self {
+            Packet::Msg(msg) | Packet::Confirmed(msg) | Packet::Downlink(msg) => msg.addr,
+            Packet::Fragment(frag) | Packet::ConfirmedFragment(frag) => frag.addr,
+            Packet::Ack { addr, .. } | Packet::DevCtrl { addr, .. } => *addr,
+        }
+    }
+
+    // Sent by a gateway, not by an end device
+    pub fn is_downlink(&self) -> bool {
+        matches!(
+            self,
+            Packet::Downlink(_) | Packet::Ack { .. } | Packet::DevCtrl { .. }
+        )
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        bincode::serialize(&self).map_err(Error::Fmt)
+    }
+
+    pub fn serialize_as(&self, encoding: encoding::Encoding) -> Result<Vec<u8>> {
+        encoding::encode(self, encoding)
+    }
+}
+
+pub fn deserialize_packet(bytes: &[u8]) -> Result<Packet> {
+    bincode::deserialize(bytes).map_err(Error::Fmt)
+}
+
+pub fn deserialize_packet_as(bytes: &[u8], encoding: encoding::Encoding) -> Result<Packet> {
+    encoding::decode(bytes, encoding)
+}
+
+// Decode a packet in any of the supported encodings
+pub fn deserialize_packet_auto(bytes: &[u8]) -> Result<Packet> {
+    deserialize_packet_as(bytes, encoding::Encoding::detect(bytes))
+}
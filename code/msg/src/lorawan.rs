@@ -0,0 +1,480 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// LoRaWAN 1.0.x PHYPayload codec for data frames (ABP sessions)
+//
+// Sources:
+//  - [LoRaWAN 1.0.4 Specification, Sec. 4]
+//
+//  PHYPayload: MHDR (1) | MACPayload | MIC (4)
+//  MACPayload: FHDR (7..22) | FPort (0..1) | FRMPayload (0..N)
+//  FHDR:       DevAddr (4, LE) | FCtrl (1) | FCnt (2, LE) | FOpts (0..15)
+//
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+
+use crate::crypto::{AppKey, Dir};
+use crate::{Error, Msg, Result};
+
+const MHDR_LEN: usize = 1;
+const FHDR_MIN_LEN: usize = 7;
+const MIC_LEN: usize = 4;
+const MAX_FOPTS_LEN: usize = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MType {
+    JoinRequest = 0b000,
+    JoinAccept = 0b001,
+    UnconfirmedDataUp = 0b010,
+    UnconfirmedDataDown = 0b011,
+    ConfirmedDataUp = 0b100,
+    ConfirmedDataDown = 0b101,
+    RejoinRequest = 0b110,
+    Proprietary = 0b111,
+}
+
+impl MType {
+    fn deserialize(value: u8) -> Self {
+        match value & 0b111 {
+            0b000 => MType::JoinRequest,
+            0b001 => MType::JoinAccept,
+            0b010 => MType::UnconfirmedDataUp,
+            0b011 => MType::UnconfirmedDataDown,
+            0b100 => MType::ConfirmedDataUp,
+            0b101 => MType::ConfirmedDataDown,
+            0b110 => MType::RejoinRequest,
+            _ => MType::Proprietary,
+        }
+    }
+
+    pub fn is_data(&self) -> bool {
+        matches!(
+            self,
+            MType::UnconfirmedDataUp
+                | MType::UnconfirmedDataDown
+                | MType::ConfirmedDataUp
+                | MType::ConfirmedDataDown
+        )
+    }
+
+    pub fn dir(&self) -> Dir {
+        match self {
+            MType::UnconfirmedDataDown | MType::ConfirmedDataDown | MType::JoinAccept => Dir::Down,
+            _ => Dir::Up,
+        }
+    }
+}
+
+// MAC header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mhdr {
+    pub mtype: MType,
+    pub major: u8, // 0b00: LoRaWAN R1
+}
+
+impl Mhdr {
+    pub fn serialize(&self) -> u8 {
+        (self.mtype as u8) << 5 | (self.major & 0b11)
+    }
+
+    pub fn deserialize(value: u8) -> Self {
+        Self {
+            mtype: MType::deserialize(value >> 5),
+            major: value & 0b11,
+        }
+    }
+}
+
+// Frame control octet, uplink and downlink share the layout except for bit 4
+// (ClassB in uplinks, FPending in downlinks)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FCtrl {
+    pub adr: bool,
+    pub adr_ack_req: bool,
+    pub ack: bool,
+    pub class_b_or_fpending: bool,
+    pub fopts_len: u8,
+}
+
+impl FCtrl {
+    pub fn serialize(&self) -> u8 {
+        (self.adr as u8) << 7
+            | (self.adr_ack_req as u8) << 6
+            | (self.ack as u8) << 5
+            | (self.class_b_or_fpending as u8) << 4
+            | (self.fopts_len & 0x0F)
+    }
+
+    pub fn deserialize(value: u8) -> Self {
+        Self {
+            adr: value & 0x80 != 0,
+            adr_ack_req: value & 0x40 != 0,
+            ack: value & 0x20 != 0,
+            class_b_or_fpending: value & 0x10 != 0,
+            fopts_len: value & 0x0F,
+        }
+    }
+}
+
+// Frame header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fhdr {
+    pub dev_addr: u32,
+    pub fctrl: FCtrl,
+    pub fcnt: u16,
+    pub fopts: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MacPayload {
+    pub fhdr: Fhdr,
+    pub fport: Option<u8>,
+    pub frm_payload: Vec<u8>, // encrypted with AppSKey (NwkSKey if FPort is 0)
+}
+
+// LoRaWAN data frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PhyPayload {
+    pub mhdr: Mhdr,
+    pub mac_payload: MacPayload,
+    pub mic: [u8; MIC_LEN],
+}
+
+// ABP session keys of a device
+#[derive(Clone, Debug)]
+pub struct SessionKeys {
+    pub nwk_skey: AppKey,
+    pub app_skey: AppKey,
+}
+
+impl PhyPayload {
+    // Build a data frame, encrypting the payload and signing it with the session keys
+    pub fn new_data(
+        mtype: MType,
+        dev_addr: u32,
+        fcnt: u32,
+        fport: u8,
+        payload: &[u8],
+        keys: &SessionKeys,
+    ) -> Result<Self> {
+        if !mtype.is_data() {
+            return Err(Error::UnsupportedMType(mtype as u8));
+        }
+
+        let mut frm_payload = payload.to_vec();
+        let key = if fport == 0 {
+            &keys.nwk_skey
+        } else {
+            &keys.app_skey
+        };
+        crypt_frm_payload(key, mtype.dir(), dev_addr, fcnt, &mut frm_payload);
+
+        let mut phy = PhyPayload {
+            mhdr: Mhdr { mtype, major: 0 },
+            mac_payload: MacPayload {
+                fhdr: Fhdr {
+                    dev_addr,
+                    fctrl: FCtrl::default(),
+                    fcnt: fcnt as u16,
+                    fopts: Vec::new(),
+                },
+                fport: Some(fport),
+                frm_payload,
+            },
+            mic: [0u8; MIC_LEN],
+        };
+        phy.mic = phy.compute_mic(&keys.nwk_skey, fcnt)?;
+        Ok(phy)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < MHDR_LEN + FHDR_MIN_LEN + MIC_LEN {
+            return Err(Error::FrameTooShort(bytes.len()));
+        }
+
+        let mhdr = Mhdr::deserialize(bytes[0]);
+        if !mhdr.mtype.is_data() {
+            return Err(Error::UnsupportedMType(mhdr.mtype as u8));
+        }
+
+        let (body, mic) = bytes.split_at(bytes.len() - MIC_LEN);
+        let mac = &body[MHDR_LEN..];
+
+        let fctrl = FCtrl::deserialize(mac[4]);
+        let fopts_end = FHDR_MIN_LEN + fctrl.fopts_len as usize;
+        if mac.len() < fopts_end {
+            return Err(Error::FrameTooShort(bytes.len()));
+        }
+
+        let fhdr = Fhdr {
+            dev_addr: u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]),
+            fctrl,
+            fcnt: u16::from_le_bytes([mac[5], mac[6]]),
+            fopts: mac[FHDR_MIN_LEN..fopts_end].to_vec(),
+        };
+
+        let (fport, frm_payload) = match mac.get(fopts_end) {
+            Some(&port) => (Some(port), mac[fopts_end + 1..].to_vec()),
+            None => (None, Vec::new()),
+        };
+
+        Ok(PhyPayload {
+            mhdr,
+            mac_payload: MacPayload {
+                fhdr,
+                fport,
+                frm_payload,
+            },
+            mic: [mic[0], mic[1], mic[2], mic[3]],
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = self.signed_bytes()?;
+        bytes.extend_from_slice(&self.mic);
+        Ok(bytes)
+    }
+
+    // MHDR | MACPayload, i.e. the portion covered by the MIC
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let mac = &self.mac_payload;
+        let fopts_len = mac.fhdr.fopts.len();
+        if fopts_len > MAX_FOPTS_LEN {
+            return Err(Error::FOptsTooLong(fopts_len));
+        }
+        let fctrl = FCtrl {
+            fopts_len: fopts_len as u8,
+            ..mac.fhdr.fctrl
+        };
+
+        let mut bytes = vec![self.mhdr.serialize()];
+        bytes.extend_from_slice(&mac.fhdr.dev_addr.to_le_bytes());
+        bytes.push(fctrl.serialize());
+        bytes.extend_from_slice(&mac.fhdr.fcnt.to_le_bytes());
+        bytes.extend_from_slice(&mac.fhdr.fopts);
+        if let Some(port) = mac.fport {
+            bytes.push(port);
+            bytes.extend_from_slice(&mac.frm_payload);
+        }
+        Ok(bytes)
+    }
+
+    // The frame only carries the 16 LSBs of the counter,
+    // the full 32 bit value is needed to compute the MIC
+    pub fn compute_mic(&self, nwk_skey: &AppKey, fcnt: u32) -> Result<[u8; MIC_LEN]> {
+        let msg = self.signed_bytes()?;
+
+        let mut b0 = [0u8; 16];
+        b0[0] = 0x49;
+        b0[5] = self.mhdr.mtype.dir() as u8;
+        b0[6..10].copy_from_slice(&self.mac_payload.fhdr.dev_addr.to_le_bytes());
+        b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        b0[15] = msg.len() as u8;
+
+        let mut mac = <Cmac<Aes128> as KeyInit>::new(GenericArray::from_slice(nwk_skey.as_bytes()));
+        mac.update(&b0);
+        mac.update(&msg);
+        let cmac = mac.finalize().into_bytes();
+        Ok([cmac[0], cmac[1], cmac[2], cmac[3]])
+    }
+
+    pub fn verify_mic(&self, nwk_skey: &AppKey, fcnt: u32) -> Result<()> {
+        if self.compute_mic(nwk_skey, fcnt)? == self.mic {
+            Ok(())
+        } else {
+            Err(Error::BadMic)
+        }
+    }
+
+    pub fn dev_addr(&self) -> u32 {
+        self.mac_payload.fhdr.dev_addr
+    }
+
+    // Decrypted FRMPayload, with the key selected by FPort
+    pub fn decrypt_frm_payload(&self, keys: &SessionKeys, fcnt: u32) -> Vec<u8> {
+        let mac = &self.mac_payload;
+        let key = match mac.fport {
+            Some(0) => &keys.nwk_skey,
+            _ => &keys.app_skey,
+        };
+        let mut payload = mac.frm_payload.clone();
+        crypt_frm_payload(
+            key,
+            self.mhdr.mtype.dir(),
+            mac.fhdr.dev_addr,
+            fcnt,
+            &mut payload,
+        );
+        payload
+    }
+
+    // Map the frame into the CLUES pipeline keyed by DevAddr.
+    // FRMPayload is left encrypted: only the virtual device holds the AppSKey.
+    pub fn into_msg(self) -> Msg {
+        Msg {
+            addr: self.mac_payload.fhdr.dev_addr as u64,
+            fcnt: self.mac_payload.fhdr.fcnt as u32,
+            port: self.mac_payload.fport.unwrap_or(0),
+            payload: self.mac_payload.frm_payload,
+        }
+    }
+}
+
+// FRMPayload encryption, see [LoRaWAN 1.0.4, Sec. 4.3.3].
+// Symmetric, the same call decrypts.
+pub fn crypt_frm_payload(key: &AppKey, dir: Dir, dev_addr: u32, fcnt: u32, payload: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key.as_bytes()));
+
+    for (i, chunk) in payload.chunks_mut(16).enumerate() {
+        let mut block = [0u8; 16];
+        block[0] = 0x01;
+        block[5] = dir as u8;
+        block[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+        block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        block[15] = (i + 1) as u8;
+
+        let mut block = GenericArray::from(block);
+        cipher.encrypt_block(&mut block);
+
+        for (b, k) in chunk.iter_mut().zip(block.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unconfirmed uplink of DevAddr 49be7df1, FCnt 2, FPort 1, FRMPayload
+    // "test", as published with the lora-packet decoder
+    const FRAME: &str = "40f17dbe4900020001954378762b11ff0d";
+    const DEV_ADDR: u32 = 0x49be7df1;
+
+    fn keys() -> SessionKeys {
+        SessionKeys {
+            nwk_skey: AppKey::from_hex("44024241ed4ce9a68c6a8bc055233fd3").unwrap(),
+            app_skey: AppKey::from_hex("ec925802ae430ca77fd3dd73cb2cc588").unwrap(),
+        }
+    }
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn known_uplink() {
+        let frame = bytes(FRAME);
+        let phy = PhyPayload::parse(&frame).unwrap();
+        assert_eq!(phy.mhdr.mtype, MType::UnconfirmedDataUp);
+        assert_eq!(phy.dev_addr(), DEV_ADDR);
+        assert_eq!(phy.mac_payload.fhdr.fcnt, 2);
+        assert_eq!(phy.mac_payload.fport, Some(1));
+        assert_eq!(phy.mac_payload.frm_payload, bytes("95437876"));
+        assert_eq!(phy.mic, [0x2b, 0x11, 0xff, 0x0d]);
+        assert_eq!(phy.compute_mic(&keys().nwk_skey, 2).unwrap(), phy.mic);
+        assert!(phy.verify_mic(&keys().nwk_skey, 2).is_ok());
+        assert!(phy.verify_mic(&keys().nwk_skey, 0x1_0002).is_err());
+        assert_eq!(phy.decrypt_frm_payload(&keys(), 2), b"test");
+
+        // built from scratch, byte for byte
+        let built =
+            PhyPayload::new_data(MType::UnconfirmedDataUp, DEV_ADDR, 2, 1, b"test", &keys())
+                .unwrap();
+        assert_eq!(built, phy);
+        assert_eq!(built.to_bytes().unwrap(), frame);
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut phy = PhyPayload::new_data(
+            MType::ConfirmedDataUp,
+            DEV_ADDR,
+            0x1_0203,
+            0,
+            &[7; 40],
+            &keys(),
+        )
+        .unwrap();
+        let bytes = phy.to_bytes().unwrap();
+        assert_eq!(PhyPayload::parse(&bytes).unwrap(), phy);
+        // FPort 0 is encrypted with the NwkSKey
+        assert_eq!(phy.decrypt_frm_payload(&keys(), 0x1_0203), [7; 40]);
+
+        // with FOpts and without FPort
+        phy.mac_payload.fhdr.fopts = vec![0x02, 0x03];
+        phy.mac_payload.fhdr.fctrl = FCtrl {
+            adr: true,
+            ack: true,
+            fopts_len: 2,
+            ..FCtrl::default()
+        };
+        phy.mac_payload.fport = None;
+        phy.mac_payload.frm_payload = Vec::new();
+        phy.mic = phy.compute_mic(&keys().nwk_skey, 0x1_0203).unwrap();
+        let bytes = phy.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 1 + 7 + 2 + 4);
+        let parsed = PhyPayload::parse(&bytes).unwrap();
+        assert_eq!(parsed, phy);
+        assert!(parsed.verify_mic(&keys().nwk_skey, 0x1_0203).is_ok());
+    }
+
+    #[test]
+    fn bad_frames() {
+        let frame = bytes(FRAME);
+        assert!(matches!(
+            PhyPayload::parse(&frame[..11]),
+            Err(Error::FrameTooShort(11))
+        ));
+        // join request
+        let mut join = frame.clone();
+        join[0] = 0x00;
+        assert!(matches!(
+            PhyPayload::parse(&join),
+            Err(Error::UnsupportedMType(0))
+        ));
+        // FOpts longer than the frame
+        let mut fopts = frame.clone();
+        fopts[5] = 0x0f;
+        assert!(matches!(
+            PhyPayload::parse(&fopts),
+            Err(Error::FrameTooShort(_))
+        ));
+        let mut tampered = PhyPayload::parse(&frame).unwrap();
+        tampered.mac_payload.frm_payload[0] ^= 1;
+        assert!(matches!(
+            tampered.verify_mic(&keys().nwk_skey, 2),
+            Err(Error::BadMic)
+        ));
+    }
+
+    #[test]
+    fn fopts_too_long() {
+        let mut phy = PhyPayload::parse(&bytes(FRAME)).unwrap();
+        phy.mac_payload.fhdr.fopts = vec![0; MAX_FOPTS_LEN + 1];
+        assert!(matches!(phy.to_bytes(), Err(Error::FOptsTooLong(16))));
+        assert!(matches!(
+            phy.compute_mic(&keys().nwk_skey, 2),
+            Err(Error::FOptsTooLong(16))
+        ));
+        phy.mac_payload.fhdr.fopts.pop();
+        assert!(phy.to_bytes().is_ok());
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Cayenne Low Power Payload (LPP) codec
+//
+// Sources:
+//  - [https://docs.mydevices.com/docs/lorawan/cayenne-lpp]
+//
+// A payload is a sequence of readings: channel (1) | type (1) | data (N),
+// multi-byte values are big endian. Decoded readings are named
+// "<channel>/<type>" (plus "/<axis>" for multi-axis sensors) and carry
+// SenML units: accelerations (g on the air) are converted to m/s2 and
+// angular rates (deg/s on the air) to rad/s.
+//
+
+use crate::measurement::{Measurement, Value};
+use crate::{Error, Result};
+
+// Standard gravity, in m/s2
+const G: f64 = 9.80665;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Type {
+    DigitalInput = 0,
+    DigitalOutput = 1,
+    AnalogInput = 2,
+    AnalogOutput = 3,
+    Illuminance = 101,
+    Presence = 102,
+    Temperature = 103,
+    Humidity = 104,
+    Accelerometer = 113,
+    Barometer = 115,
+    Gyrometer = 134,
+    Gps = 136,
+}
+
+impl Type {
+    pub fn deserialize(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Type::DigitalInput),
+            1 => Ok(Type::DigitalOutput),
+            2 => Ok(Type::AnalogInput),
+            3 => Ok(Type::AnalogOutput),
+            101 => Ok(Type::Illuminance),
+            102 => Ok(Type::Presence),
+            103 => Ok(Type::Temperature),
+            104 => Ok(Type::Humidity),
+            113 => Ok(Type::Accelerometer),
+            115 => Ok(Type::Barometer),
+            134 => Ok(Type::Gyrometer),
+            136 => Ok(Type::Gps),
+            v => Err(Error::UnknownLppType(v)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::DigitalInput => "digital_input",
+            Type::DigitalOutput => "digital_output",
+            Type::AnalogInput => "analog_input",
+            Type::AnalogOutput => "analog_output",
+            Type::Illuminance => "illuminance",
+            Type::Presence => "presence",
+            Type::Temperature => "temperature",
+            Type::Humidity => "humidity",
+            Type::Accelerometer => "accelerometer",
+            Type::Barometer => "barometer",
+            Type::Gyrometer => "gyrometer",
+            Type::Gps => "gps",
+        }
+    }
+
+    // Data size in bytes
+    fn size(&self) -> usize {
+        match self {
+            Type::DigitalInput | Type::DigitalOutput | Type::Presence | Type::Humidity => 1,
+            Type::AnalogInput | Type::AnalogOutput | Type::Illuminance => 2,
+            Type::Temperature | Type::Barometer => 2,
+            Type::Accelerometer | Type::Gyrometer => 6,
+            Type::Gps => 9,
+        }
+    }
+}
+
+fn be_signed(bytes: &[u8]) -> i32 {
+    // sign extend big endian integers of 1 to 3 bytes
+    let raw = bytes.iter().fold(0i32, |acc, b| acc << 8 | *b as i32);
+    let shift = 32 - 8 * bytes.len() as u32;
+    raw << shift >> shift
+}
+
+fn be_unsigned(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0u32, |acc, b| acc << 8 | *b as u32)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Measurement>> {
+    let mut measurements = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if i + 2 > bytes.len() {
+            return Err(Error::BadLpp(i));
+        }
+        let channel = bytes[i];
+        let typ = Type::deserialize(bytes[i + 1])?;
+        let data = bytes
+            .get(i + 2..i + 2 + typ.size())
+            .ok_or(Error::BadLpp(i))?;
+        i += 2 + typ.size();
+
+        let name = format!("{channel}/{}", typ.name());
+        let mut push = |name: &str, unit: Option<&str>, value: Value| {
+            measurements.push(Measurement::new(name, unit, value))
+        };
+        let num = |v: f64| Value::Num(v);
+
+        match typ {
+            Type::DigitalInput | Type::DigitalOutput => push(&name, None, num(data[0] as f64)),
+            Type::Presence => push(&name, None, Value::Bool(data[0] != 0)),
+            Type::AnalogInput | Type::AnalogOutput => {
+                push(&name, None, num(be_signed(data) as f64 / 100.0))
+            }
+            Type::Illuminance => push(&name, Some("lx"), num(be_unsigned(data) as f64)),
+            Type::Temperature => push(&name, Some("Cel"), num(be_signed(data) as f64 / 10.0)),
+            Type::Humidity => push(&name, Some("%RH"), num(data[0] as f64 / 2.0)),
+            // 0.1 hPa steps, i.e. 10 Pa: SenML has no hPa unit
+            Type::Barometer => push(&name, Some("Pa"), num(be_unsigned(data) as f64 * 10.0)),
+            Type::Accelerometer | Type::Gyrometer => {
+                let (unit, scale) = match typ {
+                    Type::Accelerometer => ("m/s2", G / 1000.0),
+                    _ => ("rad/s", std::f64::consts::PI / 180.0 / 100.0),
+                };
+                for (axis, v) in ["x", "y", "z"].iter().zip(data.chunks(2)) {
+                    let v = be_signed(v) as f64 * scale;
+                    push(&format!("{name}/{axis}"), Some(unit), num(v));
+                }
+            }
+            Type::Gps => {
+                push(
+                    &format!("{name}/lat"),
+                    Some("lat"),
+                    num(be_signed(&data[0..3]) as f64 / 10000.0),
+                );
+                push(
+                    &format!("{name}/lon"),
+                    Some("lon"),
+                    num(be_signed(&data[3..6]) as f64 / 10000.0),
+                );
+                push(
+                    &format!("{name}/alt"),
+                    Some("m"),
+                    num(be_signed(&data[6..9]) as f64 / 100.0),
+                );
+            }
+        }
+    }
+
+    Ok(measurements)
+}
+
+// Payload builder for end devices. Readings that do not fit their LPP type
+// (e.g. a temperature above 3276.7 Cel) are refused.
+#[derive(Default)]
+pub struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    fn header(&mut self, channel: u8, typ: Type) -> &mut Self {
+        self.buffer.push(channel);
+        self.buffer.push(typ as u8);
+        self
+    }
+
+    // Big endian, truncated to the lowest `len` bytes
+    fn int(&mut self, v: i32, len: usize) -> &mut Self {
+        self.buffer.extend_from_slice(&v.to_be_bytes()[4 - len..]);
+        self
+    }
+
+    // Values of typ are `v * scale` on `len` bytes, signed or not as decode
+    // reads them
+    fn fixed(typ: Type, v: f64, scale: f64, len: usize, signed: bool) -> Result<i32> {
+        let v = (v * scale).round();
+        let bits = 8 * len as u32;
+        let (min, max) = if signed {
+            (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1)
+        } else {
+            (0, (1i64 << bits) - 1)
+        };
+        if v.is_finite() && v >= min as f64 && v <= max as f64 {
+            Ok(v as i32)
+        } else {
+            Err(Error::LppOutOfRange(typ.name()))
+        }
+    }
+
+    pub fn digital_input(&mut self, channel: u8, v: u8) -> Result<&mut Self> {
+        Ok(self.header(channel, Type::DigitalInput).int(v as i32, 1))
+    }
+
+    pub fn digital_output(&mut self, channel: u8, v: u8) -> Result<&mut Self> {
+        Ok(self.header(channel, Type::DigitalOutput).int(v as i32, 1))
+    }
+
+    pub fn analog_input(&mut self, channel: u8, v: f64) -> Result<&mut Self> {
+        let v = Self::fixed(Type::AnalogInput, v, 100.0, 2, true)?;
+        Ok(self.header(channel, Type::AnalogInput).int(v, 2))
+    }
+
+    pub fn analog_output(&mut self, channel: u8, v: f64) -> Result<&mut Self> {
+        let v = Self::fixed(Type::AnalogOutput, v, 100.0, 2, true)?;
+        Ok(self.header(channel, Type::AnalogOutput).int(v, 2))
+    }
+
+    pub fn illuminance(&mut self, channel: u8, lux: u16) -> Result<&mut Self> {
+        Ok(self.header(channel, Type::Illuminance).int(lux as i32, 2))
+    }
+
+    pub fn presence(&mut self, channel: u8, present: bool) -> Result<&mut Self> {
+        Ok(self.header(channel, Type::Presence).int(present as i32, 1))
+    }
+
+    pub fn temperature(&mut self, channel: u8, celsius: f64) -> Result<&mut Self> {
+        let v = Self::fixed(Type::Temperature, celsius, 10.0, 2, true)?;
+        Ok(self.header(channel, Type::Temperature).int(v, 2))
+    }
+
+    pub fn humidity(&mut self, channel: u8, rh: f64) -> Result<&mut Self> {
+        let v = Self::fixed(Type::Humidity, rh, 2.0, 1, false)?;
+        Ok(self.header(channel, Type::Humidity).int(v, 1))
+    }
+
+    pub fn barometer(&mut self, channel: u8, hpa: f64) -> Result<&mut Self> {
+        let v = Self::fixed(Type::Barometer, hpa, 10.0, 2, false)?;
+        Ok(self.header(channel, Type::Barometer).int(v, 2))
+    }
+
+    pub fn accelerometer(&mut self, channel: u8, g: [f64; 3]) -> Result<&mut Self> {
+        self.axes(channel, Type::Accelerometer, g, 1000.0)
+    }
+
+    pub fn gyrometer(&mut self, channel: u8, deg_s: [f64; 3]) -> Result<&mut Self> {
+        self.axes(channel, Type::Gyrometer, deg_s, 100.0)
+    }
+
+    fn axes(&mut self, channel: u8, typ: Type, v: [f64; 3], scale: f64) -> Result<&mut Self> {
+        let mut axes = [0; 3];
+        for (axis, v) in axes.iter_mut().zip(v) {
+            *axis = Self::fixed(typ, v, scale, 2, true)?;
+        }
+        self.header(channel, typ);
+        for v in axes {
+            self.int(v, 2);
+        }
+        Ok(self)
+    }
+
+    pub fn gps(&mut self, channel: u8, lat: f64, lon: f64, alt: f64) -> Result<&mut Self> {
+        let lat = Self::fixed(Type::Gps, lat, 10000.0, 3, true)?;
+        let lon = Self::fixed(Type::Gps, lon, 10000.0, 3, true)?;
+        let alt = Self::fixed(Type::Gps, alt, 100.0, 3, true)?;
+        Ok(self
+            .header(channel, Type::Gps)
+            .int(lat, 3)
+            .int(lon, 3)
+            .int(alt, 3))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(m: &Measurement) -> f64 {
+        match m.value {
+            Value::Num(v) => v,
+            ref v => panic!("not a number: {v:?}"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut lpp = Encoder::new();
+        lpp.temperature(1, 21.5)
+            .unwrap()
+            .humidity(2, 55.5)
+            .unwrap()
+            .barometer(3, 1013.2)
+            .unwrap()
+            .presence(4, true)
+            .unwrap()
+            .gps(5, 44.4949, 11.3426, 54.12)
+            .unwrap();
+        let readings = decode(&lpp.finish()).unwrap();
+
+        let expected = [
+            ("1/temperature", "Cel", 21.5),
+            ("2/humidity", "%RH", 55.5),
+            ("3/barometer", "Pa", 101320.0),
+        ];
+        for (m, (name, unit, v)) in readings.iter().zip(expected) {
+            assert_eq!(m.name, name);
+            assert_eq!(m.unit.as_deref(), Some(unit));
+            assert!((num(m) - v).abs() < 1e-9, "{name}");
+        }
+        assert_eq!(readings[3].value, Value::Bool(true));
+        assert_eq!(readings[4].name, "5/gps/lat");
+        assert!((num(&readings[4]) - 44.4949).abs() < 1e-9);
+        assert!((num(&readings[6]) - 54.12).abs() < 1e-9);
+    }
+
+    #[test]
+    fn motion_units() {
+        let mut lpp = Encoder::new();
+        lpp.accelerometer(1, [0.0, -0.5, 1.0])
+            .unwrap()
+            .gyrometer(2, [180.0, 0.0, -90.0])
+            .unwrap();
+        let readings = decode(&lpp.finish()).unwrap();
+        assert_eq!(readings.len(), 6);
+        assert_eq!(readings[2].name, "1/accelerometer/z");
+        assert_eq!(readings[2].unit.as_deref(), Some("m/s2"));
+        assert!((num(&readings[1]) + G / 2.0).abs() < 1e-9);
+        assert!((num(&readings[2]) - G).abs() < 1e-9);
+        assert_eq!(readings[3].unit.as_deref(), Some("rad/s"));
+        assert!((num(&readings[3]) - std::f64::consts::PI).abs() < 1e-9);
+        assert!((num(&readings[5]) + std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut lpp = Encoder::new();
+        assert!(lpp.temperature(1, 3276.7).is_ok());
+        assert!(lpp.temperature(1, 3276.8).is_err());
+        assert!(lpp.temperature(1, f64::NAN).is_err());
+        assert!(lpp.humidity(1, -1.0).is_err());
+        assert!(lpp.humidity(1, 128.0).is_err());
+        assert!(lpp.accelerometer(1, [0.0, 33.0, 0.0]).is_err());
+        assert!(lpp.gps(1, 900.0, 0.0, 0.0).is_err());
+        // refused readings leave nothing behind
+        assert_eq!(lpp.len(), 4);
+    }
+
+    #[test]
+    fn bad_payloads() {
+        assert!(matches!(decode(&[1, 103, 0]), Err(Error::BadLpp(0))));
+        assert!(matches!(decode(&[1]), Err(Error::BadLpp(0))));
+        assert!(matches!(
+            decode(&[1, 200, 0]),
+            Err(Error::UnknownLppType(200))
+        ));
+    }
+}
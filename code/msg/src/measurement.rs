@@ -0,0 +1,44 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Typed sensor reading shared by the payload codecs (see lpp and senml)
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Value {
+    Num(f64),
+    Bool(bool),
+    Str(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub name: String,
+    pub unit: Option<String>, // SenML unit symbol, e.g. "Cel"
+    pub value: Value,
+    pub time: Option<f64>, // seconds since the UNIX epoch
+}
+
+impl Measurement {
+    pub fn new(name: &str, unit: Option<&str>, value: Value) -> Self {
+        Self {
+            name: name.to_string(),
+            unit: unit.map(str::to_string),
+            value,
+            time: None,
+        }
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Messages exchanged on the gateway broker, as opposed to the radio Packet
+
+use serde::{Deserialize, Serialize};
+
+use crate::measurement::Measurement;
+use crate::uplink::{Uplink, UplinkRef};
+use crate::{Error, Msg, MsgRef, Result};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Message {
+    // Data from an end device
+    Uplink(Uplink),
+    // Data from an end device that expects an Ack
+    ConfirmedUplink(Uplink),
+    // Acknowledgement of a confirmed uplink
+    Ack {
+        addr: u64,
+        fcnt: u32,
+    },
+    // Data for an end device
+    Downlink(Msg),
+    // Configuration command for an end device
+    DevCtrl {
+        addr: u64,
+        cmd: DevCmd,
+    },
+    // Configuration command for the gateway
+    GwCtrl(GwCmd),
+    // Readings of an end device, decoded by its virtual device
+    Readings {
+        addr: u64,
+        readings: Vec<Measurement>,
+    },
+    // Application event raised by the virtual device of an end device
+    Event {
+        addr: u64,
+        name: String,
+        data: Vec<u8>,
+    },
+}
+
+// Borrowed view of a serialized Message, payloads point into the input buffer.
+// Variants must mirror Message one to one (same order), as they share the
+// wire format.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum MessageRef<'a> {
+    #[serde(borrow)]
+    Uplink(UplinkRef<'a>),
+    #[serde(borrow)]
+    ConfirmedUplink(UplinkRef<'a>),
+    Ack {
+        addr: u64,
+        fcnt: u32,
+    },
+    #[serde(borrow)]
+    Downlink(MsgRef<'a>),
+    DevCtrl {
+        addr: u64,
+        cmd: DevCmd,
+    },
+    GwCtrl(GwCmd),
+    Readings {
+        addr: u64,
+        readings: Vec<Measurement>,
+    },
+    Event {
+        addr: u64,
+        name: &'a str,
+        data: &'a [u8],
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum DevCmd {
+    // Seconds between two uplinks
+    SetPeriod(u32),
+    // Spreading factor (7 - 12)
+    SetSf(u8),
+    // Transmission power in dBm
+    SetTxPower(u8),
+    Reboot,
+    // Device-specific command
+    Custom(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum GwCmd {
+    // Instantiate the virtual device of an end device
+    Load(u64),
+    // Stop the virtual device of an end device
+    Unload(u64),
+    // Restart the virtual device of an end device, e.g. with a new driver
+    Reload(u64),
+    // Stop the virtual device of an end device until resumed, its uplinks
+    // are dropped meanwhile
+    Pause(u64),
+    Resume(u64),
+}
+
+impl Message {
+    // End device the message is from or to, if any
+    pub fn addr(&self) -> Option<u64> {
+        match self {
+            Message::Uplink(up) | Message::ConfirmedUplink(up) => Some(up.msg.addr),
+            Message::Ack { addr, .. }
+            | Message::DevCtrl { addr, .. }
+            | Message::Readings { addr, .. }
+            | Message::Event { addr, .. } => Some(*addr),
+            Message::Downlink(msg) => Some(msg.addr),
+            Message::GwCtrl(_) => None,
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        bincode::serialize(&self).map_err(Error::Fmt)
+    }
+
+    pub fn serialize_into<W>(&self, writer: W) -> Result<()>
+    where
+        W: std::io::Write,
+    {
+        bincode::serialize_into(writer, &self).map_err(Error::Fmt)
+    }
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<Message> {
+    bincode::deserialize(bytes).map_err(Error::Fmt)
+}
+
+pub fn deserialize_from<R>(reader: R) -> Result<Message>
+where
+    R: std::io::Read,
+{
+    bincode::deserialize_from(reader).map_err(Error::Fmt)
+}
+
+pub fn deserialize_ref(bytes: &[u8]) -> Result<MessageRef<'_>> {
+    bincode::deserialize(bytes).map_err(Error::Fmt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::Value;
+    use crate::uplink::{self, RxMeta};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // Counts the allocations of each thread
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    // Result of f, and the allocations it made
+    fn allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let result = f();
+        (result, ALLOCATIONS.with(Cell::get) - before)
+    }
+
+    fn uplink() -> Uplink {
+        Uplink {
+            meta: RxMeta {
+                gateway: 0x1234,
+                time: 1_700_000_000_000,
+                rssi: -80,
+                snr: 7,
+                freq: 868_100_000,
+                sf: 7,
+            },
+            msg: Msg {
+                addr: 1,
+                fcnt: 42,
+                port: 2,
+                payload: vec![1, 2, 3],
+            },
+        }
+    }
+
+    // One message of each variant, in the order of Message
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Uplink(uplink()),
+            Message::ConfirmedUplink(uplink()),
+            Message::Ack { addr: 1, fcnt: 42 },
+            Message::Downlink(uplink().msg),
+            Message::DevCtrl {
+                addr: 1,
+                cmd: DevCmd::Custom(vec![4, 5]),
+            },
+            Message::GwCtrl(GwCmd::Reload(1)),
+            Message::Readings {
+                addr: 1,
+                readings: vec![Measurement {
+                    name: "temperature".to_string(),
+                    unit: Some("Cel".to_string()),
+                    value: Value::Num(21.5),
+                    time: None,
+                }],
+            },
+            Message::Event {
+                addr: 1,
+                name: "door".to_string(),
+                data: b"open".to_vec(),
+            },
+        ]
+    }
+
+    // Without wildcard, so that a variant added to one of the enums, and not
+    // to the other, does not build
+    const VARIANTS: usize = 8;
+
+    fn index(msg: &Message) -> usize {
+        match msg {
+            Message::Uplink(_) => 0,
+            Message::ConfirmedUplink(_) => 1,
+            Message::Ack { .. } => 2,
+            Message::Downlink(_) => 3,
+            Message::DevCtrl { .. } => 4,
+            Message::GwCtrl(_) => 5,
+            Message::Readings { .. } => 6,
+            Message::Event { .. } => 7,
+        }
+    }
+
+    fn ref_index(msg: &MessageRef) -> usize {
+        match msg {
+            MessageRef::Uplink(_) => 0,
+            MessageRef::ConfirmedUplink(_) => 1,
+            MessageRef::Ack { .. } => 2,
+            MessageRef::Downlink(_) => 3,
+            MessageRef::DevCtrl { .. } => 4,
+            MessageRef::GwCtrl(_) => 5,
+            MessageRef::Readings { .. } => 6,
+            MessageRef::Event { .. } => 7,
+        }
+    }
+
+    #[test]
+    fn refs_mirror_messages() {
+        let messages = messages();
+        let indexes: Vec<_> = messages.iter().map(index).collect();
+        assert_eq!(indexes, (0..VARIANTS).collect::<Vec<_>>());
+        for msg in &messages {
+            let bytes = msg.serialize().unwrap();
+            let msg_ref = deserialize_ref(&bytes).unwrap();
+            assert_eq!(ref_index(&msg_ref), index(msg), "{msg:?}");
+            // same fields, in the same wire format
+            assert_eq!(bincode::serialize(&msg_ref).unwrap(), bytes, "{msg:?}");
+        }
+    }
+
+    #[test]
+    fn borrowed_decoding() {
+        let borrowed =
+            |bytes: &[u8], payload: &[u8]| bytes.as_ptr_range().contains(&payload.as_ptr());
+
+        let bytes = Message::Uplink(uplink()).serialize().unwrap();
+        // the owned message copies its payload
+        assert!(allocations(|| deserialize(&bytes)).1 > 0);
+        let (decoded, allocated) = allocations(|| deserialize_ref(&bytes));
+        assert_eq!(allocated, 0);
+        let Ok(MessageRef::Uplink(up)) = decoded else {
+            panic!("{decoded:?}");
+        };
+        assert_eq!(up.meta, uplink().meta);
+        assert_eq!(
+            (up.msg.addr, up.msg.fcnt, up.msg.payload),
+            (1, 42, &[1, 2, 3][..])
+        );
+        assert!(borrowed(&bytes, up.msg.payload));
+
+        let event = Message::Event {
+            addr: 1,
+            name: "door".to_string(),
+            data: b"open".to_vec(),
+        };
+        let bytes = event.serialize().unwrap();
+        let (decoded, allocated) = allocations(|| deserialize_ref(&bytes));
+        assert_eq!(allocated, 0);
+        let Ok(MessageRef::Event { name, data, .. }) = decoded else {
+            panic!("{decoded:?}");
+        };
+        assert_eq!((name, data), ("door", &b"open"[..]));
+        assert!(borrowed(&bytes, data));
+
+        let bytes = uplink().serialize().unwrap();
+        let (decoded, allocated) = allocations(|| uplink::deserialize_ref(&bytes));
+        assert_eq!(allocated, 0);
+        assert!(borrowed(&bytes, decoded.unwrap().msg.payload));
+
+        let bytes = uplink().msg.serialize().unwrap();
+        let (decoded, allocated) = allocations(|| crate::deserialize_ref(&bytes));
+        assert_eq!(allocated, 0);
+        let decoded = decoded.unwrap();
+        assert!(borrowed(&bytes, decoded.payload));
+        assert_eq!(decoded.to_msg().serialize().unwrap(), bytes);
+    }
+}
@@ -0,0 +1,417 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Sensor Measurement Lists (SenML) codec, JSON and CBOR representations
+//
+// Sources:
+//  - [RFC 8428]
+//
+// Encoding puts the base name (if any) in the first record, decoding
+// resolves the base name, time, unit and value into each measurement (the
+// sum fields are ignored). Packs of a later version than 10, or with
+// must-understand fields (labels ending in '_'), are refused.
+//
+
+use ciborium::value::{Integer, Value as Cbor};
+use serde::{Deserialize, Serialize};
+
+use crate::measurement::{Measurement, Value};
+use crate::{Error, Result};
+
+// SenML version of RFC 8428
+const VERSION: u64 = 10;
+
+// CBOR labels, see [RFC 8428, Sec. 6]
+const BVER: i64 = -1;
+const BN: i64 = -2;
+const BT: i64 = -3;
+const BU: i64 = -4;
+const BV: i64 = -5;
+const N: i64 = 0;
+const U: i64 = 1;
+const V: i64 = 2;
+const VS: i64 = 3;
+const VB: i64 = 4;
+const T: i64 = 6;
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Record {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bver: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bu: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bv: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    u: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vs: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vb: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    t: Option<f64>,
+}
+
+fn to_records(measurements: &[Measurement], base_name: Option<&str>) -> Vec<Record> {
+    let mut records: Vec<Record> = measurements
+        .iter()
+        .map(|m| {
+            let mut r = Record {
+                n: Some(m.name.clone()),
+                u: m.unit.clone(),
+                t: m.time,
+                ..Record::default()
+            };
+            match m.value {
+                Value::Num(v) => r.v = Some(v),
+                Value::Bool(v) => r.vb = Some(v),
+                Value::Str(ref v) => r.vs = Some(v.clone()),
+            }
+            r
+        })
+        .collect();
+
+    if let (Some(bn), Some(first)) = (base_name, records.first_mut()) {
+        first.bn = Some(bn.to_string());
+    }
+    records
+}
+
+// Base fields apply to the following records too, until set again
+// [RFC 8428, Sec. 4.6]
+fn from_records(records: Vec<Record>) -> Result<Vec<Measurement>> {
+    let mut base_name = String::new();
+    let mut base_time = None;
+    let mut base_unit = None;
+    let mut base_value = None;
+
+    let mut measurements = Vec::with_capacity(records.len());
+    for r in records {
+        if r.bver.is_some_and(|bver| bver > VERSION) {
+            return Err(Error::BadSenml);
+        }
+        if let Some(bn) = r.bn {
+            base_name = bn;
+        }
+        if r.bt.is_some() {
+            base_time = r.bt;
+        }
+        if r.bu.is_some() {
+            base_unit = r.bu;
+        }
+        if r.bv.is_some() {
+            base_value = r.bv;
+        }
+        let value = match (r.v, r.vb, r.vs) {
+            (Some(v), None, None) => Value::Num(base_value.unwrap_or(0.0) + v),
+            (None, Some(v), None) => Value::Bool(v),
+            (None, None, Some(v)) => Value::Str(v),
+            // the base value alone
+            (None, None, None) => Value::Num(base_value.ok_or(Error::BadSenml)?),
+            _ => return Err(Error::BadSenml),
+        };
+        measurements.push(Measurement {
+            name: base_name.clone() + r.n.as_deref().unwrap_or(""),
+            unit: r.u.or_else(|| base_unit.clone()),
+            value,
+            time: match base_time {
+                Some(bt) => Some(bt + r.t.unwrap_or(0.0)),
+                None => r.t,
+            },
+        });
+    }
+    Ok(measurements)
+}
+
+pub fn to_json(measurements: &[Measurement], base_name: Option<&str>) -> Result<String> {
+    serde_json::to_string(&to_records(measurements, base_name)).map_err(Error::Json)
+}
+
+pub fn from_json(json: &str) -> Result<Vec<Measurement>> {
+    let pack: Vec<serde_json::Map<String, serde_json::Value>> =
+        serde_json::from_str(json).map_err(Error::Json)?;
+    let records = pack
+        .into_iter()
+        .map(|map| {
+            if map.keys().any(|label| label.ends_with('_')) {
+                return Err(Error::BadSenml);
+            }
+            serde_json::from_value(serde_json::Value::Object(map)).map_err(Error::Json)
+        })
+        .collect::<Result<_>>()?;
+    from_records(records)
+}
+
+pub fn to_cbor(measurements: &[Measurement], base_name: Option<&str>) -> Result<Vec<u8>> {
+    let label = |l: i64| Cbor::Integer(Integer::from(l));
+
+    let pack = to_records(measurements, base_name)
+        .into_iter()
+        .map(|r| {
+            let mut map = Vec::new();
+            if let Some(bn) = r.bn {
+                map.push((label(BN), Cbor::Text(bn)));
+            }
+            if let Some(n) = r.n {
+                map.push((label(N), Cbor::Text(n)));
+            }
+            if let Some(u) = r.u {
+                map.push((label(U), Cbor::Text(u)));
+            }
+            if let Some(v) = r.v {
+                map.push((label(V), Cbor::Float(v)));
+            }
+            if let Some(vs) = r.vs {
+                map.push((label(VS), Cbor::Text(vs)));
+            }
+            if let Some(vb) = r.vb {
+                map.push((label(VB), Cbor::Bool(vb)));
+            }
+            if let Some(t) = r.t {
+                map.push((label(T), Cbor::Float(t)));
+            }
+            Cbor::Map(map)
+        })
+        .collect();
+
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(&Cbor::Array(pack), &mut bytes)
+        .map_err(|e| Error::Cbor(e.to_string()))?;
+    Ok(bytes)
+}
+
+pub fn from_cbor(bytes: &[u8]) -> Result<Vec<Measurement>> {
+    let pack: Cbor = ciborium::de::from_reader(bytes).map_err(|e| Error::Cbor(e.to_string()))?;
+
+    let num = |v: &Cbor| match v {
+        Cbor::Float(f) => Ok(*f),
+        Cbor::Integer(i) => Ok(i128::from(*i) as f64),
+        _ => Err(Error::BadSenml),
+    };
+    let text = |v: &Cbor| v.as_text().map(str::to_string).ok_or(Error::BadSenml);
+
+    let mut records = Vec::new();
+    for map in pack.as_array().ok_or(Error::BadSenml)? {
+        let mut r = Record::default();
+        for (k, v) in map.as_map().ok_or(Error::BadSenml)? {
+            let k = match k {
+                Cbor::Integer(k) => i64::try_from(*k).map_err(|_| Error::BadSenml)?,
+                // extensions may use text labels
+                Cbor::Text(k) if k.ends_with('_') => return Err(Error::BadSenml),
+                Cbor::Text(_) => continue,
+                _ => return Err(Error::BadSenml),
+            };
+            match k {
+                BVER => {
+                    let bver = v.as_integer().ok_or(Error::BadSenml)?;
+                    r.bver = Some(u64::try_from(bver).map_err(|_| Error::BadSenml)?)
+                }
+                BN => r.bn = Some(text(v)?),
+                BT => r.bt = Some(num(v)?),
+                BU => r.bu = Some(text(v)?),
+                BV => r.bv = Some(num(v)?),
+                N => r.n = Some(text(v)?),
+                U => r.u = Some(text(v)?),
+                V => r.v = Some(num(v)?),
+                VS => r.vs = Some(text(v)?),
+                VB => r.vb = Some(v.as_bool().ok_or(Error::BadSenml)?),
+                T => r.t = Some(num(v)?),
+                _ => (), // fields this codec does not use (e.g. sum)
+            }
+        }
+        records.push(r);
+    }
+    from_records(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurements() -> Vec<Measurement> {
+        let mut time = Measurement::new("1/temperature", Some("Cel"), Value::Num(21.5));
+        time.time = Some(1_700_000_000.0);
+        vec![
+            time,
+            Measurement::new("2/presence", None, Value::Bool(true)),
+            Measurement::new("3/status", None, Value::Str("ok".to_string())),
+        ]
+    }
+
+    fn named(base_name: &str) -> Vec<Measurement> {
+        measurements()
+            .into_iter()
+            .map(|m| Measurement {
+                name: format!("{base_name}{}", m.name),
+                ..m
+            })
+            .collect()
+    }
+
+    #[test]
+    fn json() {
+        let bn = "urn:dev:clues:00000001:";
+        let json = to_json(&measurements(), Some(bn)).unwrap();
+        assert!(json.starts_with(
+            r#"[{"bn":"urn:dev:clues:00000001:","n":"1/temperature","u":"Cel","v":21.5"#
+        ));
+        assert_eq!(from_json(&json).unwrap(), named(bn));
+        assert_eq!(
+            from_json(&to_json(&measurements(), None).unwrap()).unwrap(),
+            measurements()
+        );
+    }
+
+    #[test]
+    fn cbor() {
+        let bn = "urn:dev:clues:00000001:";
+        let cbor = to_cbor(&measurements(), Some(bn)).unwrap();
+        assert_eq!(from_cbor(&cbor).unwrap(), named(bn));
+        assert!(from_cbor(&cbor[..cbor.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn lpp_to_senml() {
+        let mut lpp = crate::lpp::Encoder::new();
+        lpp.temperature(1, 21.5).unwrap();
+        let readings = crate::lpp::decode(&lpp.finish()).unwrap();
+        let json = to_json(&readings, None).unwrap();
+        assert_eq!(json, r#"[{"n":"1/temperature","u":"Cel","v":21.5}]"#);
+    }
+
+    // Example of [RFC 8428, Sec. 5.1.3], with a base unit and value
+    const RESOLVED: &str = r#"[
+        {"bn":"urn:dev:ow:10e2073a01080063:","bt":1320067464,"bu":"%RH","bv":40,"v":0.5,"n":"humidity"},
+        {"n":"temperature","u":"Cel","v":23.1,"t":1},
+        {"n":"humidity","t":2},
+        {"bver":10,"bu":"Cel","bv":20,"n":"temperature","v":2.5,"t":3}
+    ]"#;
+
+    #[test]
+    fn base_fields() {
+        let m = from_json(RESOLVED).unwrap();
+        let resolved = |m: &Measurement| (m.name.clone(), m.unit.clone().unwrap(), m.time.unwrap());
+        assert_eq!(
+            m.iter().map(resolved).collect::<Vec<_>>(),
+            [
+                ("urn:dev:ow:10e2073a01080063:humidity", "%RH", 1320067464.0),
+                (
+                    "urn:dev:ow:10e2073a01080063:temperature",
+                    "Cel",
+                    1320067465.0
+                ),
+                ("urn:dev:ow:10e2073a01080063:humidity", "%RH", 1320067466.0),
+                (
+                    "urn:dev:ow:10e2073a01080063:temperature",
+                    "Cel",
+                    1320067467.0
+                ),
+            ]
+            .map(|(n, u, t)| (n.to_string(), u.to_string(), t))
+        );
+        let values: Vec<_> = m.iter().map(|m| m.value.clone()).collect();
+        assert_eq!(values, [40.5, 63.1, 40.0, 22.5].map(Value::Num));
+
+        // the same pack in CBOR
+        let label = |l: i64| Cbor::Integer(Integer::from(l));
+        let text = |s: &str| Cbor::Text(s.to_string());
+        let float = |f: f64| Cbor::Float(f);
+        let pack = Cbor::Array(vec![
+            Cbor::Map(vec![
+                (label(BN), text("urn:dev:ow:10e2073a01080063:")),
+                (label(BT), float(1320067464.0)),
+                (label(BU), text("%RH")),
+                (label(BV), float(40.0)),
+                (label(V), float(0.5)),
+                (label(N), text("humidity")),
+            ]),
+            Cbor::Map(vec![
+                (label(N), text("temperature")),
+                (label(U), text("Cel")),
+                (label(V), float(23.1)),
+                (label(T), float(1.0)),
+            ]),
+            Cbor::Map(vec![(label(N), text("humidity")), (label(T), float(2.0))]),
+            Cbor::Map(vec![
+                (label(BVER), label(10)),
+                (label(BU), text("Cel")),
+                (label(BV), float(20.0)),
+                (label(N), text("temperature")),
+                (label(V), float(2.5)),
+                (label(T), float(3.0)),
+            ]),
+        ]);
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(&pack, &mut cbor).unwrap();
+        assert_eq!(from_cbor(&cbor).unwrap(), m);
+    }
+
+    fn record(map: Vec<(Cbor, Cbor)>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&Cbor::Array(vec![Cbor::Map(map)]), &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn refused() {
+        for json in [
+            // must-understand field
+            r#"[{"n":"a","v":1,"ut_":10}]"#,
+            // later version
+            r#"[{"bver":11,"n":"a","v":1}]"#,
+            // no value at all
+            r#"[{"n":"a"}]"#,
+            // two values
+            r#"[{"n":"a","v":1,"vb":true}]"#,
+        ] {
+            assert!(from_json(json).is_err(), "{json}");
+        }
+        // unknown fields are ignored
+        assert!(from_json(r#"[{"n":"a","v":1,"ut":10}]"#).is_ok());
+
+        let v = (Cbor::Integer(Integer::from(V)), Cbor::Float(1.0));
+        assert!(from_cbor(&record(vec![v.clone()])).is_ok());
+        assert!(from_cbor(&record(vec![
+            v.clone(),
+            (Cbor::Text("x".into()), Cbor::Null)
+        ]))
+        .is_ok());
+        for label in [
+            Cbor::Text("x_".into()),
+            // out of the range of i64, not a wrapped BVER
+            Cbor::Integer(Integer::from(u64::MAX)),
+            Cbor::Float(2.0),
+        ] {
+            let map = vec![
+                v.clone(),
+                (label.clone(), Cbor::Integer(Integer::from(100))),
+            ];
+            assert!(from_cbor(&record(map)).is_err(), "{label:?}");
+        }
+        let bver = (
+            Cbor::Integer(Integer::from(BVER)),
+            Cbor::Integer(Integer::from(11)),
+        );
+        assert!(from_cbor(&record(vec![v, bver])).is_err());
+    }
+}
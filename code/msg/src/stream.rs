@@ -0,0 +1,281 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Framing of messages over byte streams (pipes, sockets)
+//
+//   frame: SYNC (2) | len (4, LE) | crc32 (4, LE) | hcrc (2, LE) | body (len)
+//
+// crc32 covers the body, hcrc the 16 LSBs of the CRC-32 of the header before
+// it. The reader scans for the sync marker and checks the header before
+// waiting for the body, so a corrupted length never stalls it waiting for
+// bytes that are not coming; after a corrupted or truncated frame it
+// resynchronises on the next one instead of decoding garbage forever.
+//
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use crate::message::{self, Message};
+use crate::{Error, Result};
+
+pub const SYNC: [u8; 2] = [0xC1, 0x5E];
+
+pub const HEADER_LEN: usize = SYNC.len() + 4 + 4 + 2;
+
+// Larger frames are considered corrupted
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+pub fn encode_frame(body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.extend_from_slice(&SYNC);
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(body).to_le_bytes());
+    frame.extend_from_slice(&header_crc(&frame).to_le_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+// Checksum of the header fields preceding it
+fn header_crc(header: &[u8]) -> u16 {
+    crc32fast::hash(&header[..HEADER_LEN - 2]) as u16
+}
+
+// Write a whole frame with a single write, so that frames from different
+// writers sharing a stream never interleave
+pub fn write_frame<W>(mut writer: W, body: &[u8]) -> Result<()>
+where
+    W: Write,
+{
+    if body.len() > MAX_FRAME_LEN {
+        return Err(Error::FrameTooLarge(body.len()));
+    }
+    writer.write_all(&encode_frame(body)).map_err(Error::Io)
+}
+
+pub fn write_message<W>(writer: W, msg: &Message) -> Result<()>
+where
+    W: Write,
+{
+    write_frame(writer, &msg.serialize()?)
+}
+
+// Encode a message once, the frame can then be shared by any number of
+// subscribers without copies
+pub fn shared_frame(msg: &Message) -> Result<Arc<[u8]>> {
+    let body = msg.serialize()?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err(Error::FrameTooLarge(body.len()));
+    }
+    Ok(encode_frame(&body).into())
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    start: usize, // bytes of buf already returned or skipped
+    skipped: usize,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            start: 0,
+            skipped: 0,
+        }
+    }
+
+    // Bytes discarded so far while resynchronising
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    // Blocking read of the next valid frame body.
+    // Fails with Error::Closed once the stream reaches its end.
+    pub fn read_frame(&mut self) -> Result<Vec<u8>> {
+        self.next_frame().map(<[u8]>::to_vec)
+    }
+
+    // Same as read_frame, but the body is borrowed from the internal buffer
+    // until the next call (decode it with e.g. message::deserialize_ref)
+    pub fn next_frame(&mut self) -> Result<&[u8]> {
+        loop {
+            // Align the start to the next sync marker
+            self.fill(SYNC.len())?;
+            let data = &self.buf[self.start..];
+            match data.windows(SYNC.len()).position(|w| w == SYNC) {
+                Some(pos) => self.skip(pos),
+                None => {
+                    // the last byte may be the first half of the marker
+                    self.skip(data.len() - 1);
+                    continue;
+                }
+            }
+
+            self.fill(HEADER_LEN)?;
+            let header = &self.buf[self.start..self.start + HEADER_LEN];
+            let len = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+            let crc = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+            let hcrc = u16::from_le_bytes([header[10], header[11]]);
+            let len = len as usize;
+            if hcrc != header_crc(header) || len > MAX_FRAME_LEN {
+                self.skip(1);
+                continue;
+            }
+
+            self.fill(HEADER_LEN + len)?;
+            let body = self.start + HEADER_LEN..self.start + HEADER_LEN + len;
+            if crc32fast::hash(&self.buf[body.clone()]) != crc {
+                self.skip(1);
+                continue;
+            }
+
+            self.start = body.end;
+            return Ok(&self.buf[body]);
+        }
+    }
+
+    // Blocking read of the next message, skipping frames that do not decode
+    pub fn read_message(&mut self) -> Result<Message> {
+        loop {
+            match message::deserialize(self.next_frame()?) {
+                Ok(msg) => return Ok(msg),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    // Skipping only moves the start, the buffer is compacted once by fill
+    fn skip(&mut self, n: usize) {
+        self.start += n;
+        self.skipped += n;
+    }
+
+    // Make n bytes available from the start
+    fn fill(&mut self, n: usize) -> Result<()> {
+        if self.buf.len() - self.start >= n {
+            return Ok(());
+        }
+        self.buf.drain(..self.start);
+        self.start = 0;
+        let mut chunk = [0u8; 4096];
+        while self.buf.len() < n {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return Err(Error::Closed),
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(bytes: Vec<u8>) -> FrameReader<io::Cursor<Vec<u8>>> {
+        FrameReader::new(io::Cursor::new(bytes))
+    }
+
+    #[test]
+    fn frames() {
+        let mut bytes = encode_frame(b"one");
+        bytes.extend(encode_frame(b""));
+        bytes.extend(encode_frame(b"three"));
+        let mut reader = reader(bytes);
+        assert_eq!(reader.read_frame().unwrap(), b"one");
+        assert_eq!(reader.read_frame().unwrap(), b"");
+        assert_eq!(reader.read_frame().unwrap(), b"three");
+        assert!(matches!(reader.read_frame(), Err(Error::Closed)));
+        assert_eq!(reader.skipped(), 0);
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut bytes = vec![0x00, SYNC[0], 0xFF, SYNC[0], SYNC[1], 0x01];
+        bytes.extend(encode_frame(b"one"));
+        bytes.extend([SYNC[0]]);
+        bytes.extend(encode_frame(b"two"));
+        let mut reader = reader(bytes);
+        assert_eq!(reader.read_frame().unwrap(), b"one");
+        assert_eq!(reader.read_frame().unwrap(), b"two");
+        assert_eq!(reader.skipped(), 7);
+    }
+
+    #[test]
+    fn resync_over_false_markers() {
+        // many markers in a buffer holding a whole frame
+        let mut bytes = encode_frame(&[0; MAX_FRAME_LEN]);
+        bytes.truncate(HEADER_LEN);
+        bytes.extend(SYNC.repeat(MAX_FRAME_LEN / 2));
+        bytes.extend(encode_frame(b"two"));
+        let mut reader = reader(bytes);
+        assert_eq!(reader.read_frame().unwrap(), b"two");
+        assert_eq!(reader.skipped(), HEADER_LEN + MAX_FRAME_LEN);
+        assert!(matches!(reader.read_frame(), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn resync_after_corrupted_body() {
+        let mut bytes = encode_frame(b"corrupted");
+        *bytes.last_mut().unwrap() ^= 0x01;
+        bytes.extend(encode_frame(b"two"));
+        let mut reader = reader(bytes);
+        assert_eq!(reader.read_frame().unwrap(), b"two");
+    }
+
+    #[test]
+    fn resync_after_truncated_frame() {
+        let mut bytes = encode_frame(b"truncated");
+        bytes.truncate(HEADER_LEN + 2);
+        bytes.extend(encode_frame(b"two"));
+        let mut reader = reader(bytes);
+        assert_eq!(reader.read_frame().unwrap(), b"two");
+    }
+
+    #[test]
+    fn corrupted_length_does_not_stall() {
+        // a length within MAX_FRAME_LEN, the body would never come
+        let mut bytes = encode_frame(b"one");
+        bytes[3] ^= 0x10;
+        bytes.extend(encode_frame(b"two"));
+        let mut reader = reader(bytes);
+        assert_eq!(reader.read_frame().unwrap(), b"two");
+    }
+
+    #[test]
+    fn undecodable_messages_are_skipped() {
+        let ack = Message::Ack { addr: 1, fcnt: 2 };
+        let mut bytes = encode_frame(&[0xFF; 3]);
+        write_message(&mut bytes, &ack).unwrap();
+        let mut reader = reader(bytes);
+        assert!(matches!(
+            reader.read_message().unwrap(),
+            Message::Ack { addr: 1, fcnt: 2 }
+        ));
+    }
+
+    #[test]
+    fn too_large() {
+        let body = vec![0; MAX_FRAME_LEN + 1];
+        assert!(matches!(
+            write_frame(Vec::new(), &body),
+            Err(Error::FrameTooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Uplink envelope delivered to virtual devices: the message as sent by the
+// end device plus the metadata of its reception at the gateway
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Msg, MsgRef, Result};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RxMeta {
+    pub gateway: u64, // id of the gateway that heard the packet
+    pub time: u64,    // reception time in ms since the UNIX epoch
+    pub rssi: i32,    // in dBm
+    pub snr: i32,     // in dB
+    pub freq: u32,    // in Hz
+    pub sf: u8,       // spreading factor (6 - 12)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Uplink {
+    pub meta: RxMeta,
+    pub msg: Msg,
+}
+
+// Borrowed view of a serialized Uplink
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UplinkRef<'a> {
+    pub meta: RxMeta,
+    #[serde(borrow)]
+    pub msg: MsgRef<'a>,
+}
+
+impl Uplink {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        bincode::serialize(&self).map_err(Error::Fmt)
+    }
+
+    pub fn serialize_into<W>(&self, writer: W) -> Result<()>
+    where
+        W: std::io::Write,
+    {
+        bincode::serialize_into(writer, &self).map_err(Error::Fmt)
+    }
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<Uplink> {
+    bincode::deserialize(bytes).map_err(Error::Fmt)
+}
+
+pub fn deserialize_from<R>(reader: R) -> Result<Uplink>
+where
+    R: std::io::Read,
+{
+    bincode::deserialize_from(reader).map_err(Error::Fmt)
+}
+
+pub fn deserialize_ref(bytes: &[u8]) -> Result<UplinkRef<'_>> {
+    bincode::deserialize(bytes).map_err(Error::Fmt)
+}
@@ -16,7 +16,9 @@
 // LoRa end-device for test purposes
 
 use lora::{self, opcodes::*, *};
-use std::{thread, time};
+use msg::encoding::Encoding;
+use msg::{frag, Msg, Packet};
+use std::{env, process, thread, time};
 
 // Set spreading factor (SF7 - SF12)
 const SF: SpreadingFactor = SpreadingFactor::SF7;
@@ -24,13 +26,49 @@ const SF: SpreadingFactor = SpreadingFactor::SF7;
 // Set center frequency
 const FREQ: u32 = 868100000; // in Mhz! (868.1)
 
-// Default test payload
-const PAYLOAD: &str = "TEST MESSAGE";
+// Largest radio frame, larger packets are fragmented
+const MAX_PAYLOAD: usize = 128;
+
+// Smallest fragment payload, below it the encoding overhead dominates
+const MIN_CHUNK: usize = 8;
+
+// Data fragments covered by each parity fragment
+const FRAG_GROUP: u8 = 4;
+
+// Application port of the sensor readings
+const PORT: u8 = 1;
+
+// Longest time on air of a frame (SF12, 128 bytes)
+const TX_TIMEOUT: time::Duration = time::Duration::from_secs(5);
 
 // List of addresses to emulate device variety
 const ADDR_LST: [u64; 10] = [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9];
 
 pub fn main() -> Result<(), lora::Error> {
+    let mut encoding = Encoding::default();
+    let mut keys = msg::crypto::AppKeys::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--encoding" => {
+                let name = args.next().unwrap_or_else(|| help());
+                encoding = name.parse().unwrap_or_else(|_| help())
+            }
+            "--app-keys" => {
+                let path = args.next().unwrap_or_else(|| help());
+                keys = std::fs::read_to_string(&path)
+                    .map_err(msg::Error::Io)
+                    .and_then(|text| msg::crypto::AppKeys::parse(&text))
+                    .unwrap_or_else(|e| {
+                        eprintln!("{path}: {e}");
+                        help()
+                    })
+            }
+            _ => help(),
+        }
+    }
+
     let mut lora = Lora::new(Configs {
         sync_word: 0x12, // default sync word for non-LoRaWAN, private networks
         frf: Frf { freq: FREQ },
@@ -50,17 +88,14 @@ pub fn main() -> Result<(), lora::Error> {
             _ => 8,
         },
         modem_config3: ModemConfig3 {
-            low_data_rate_optimize: match SF {
-                SpreadingFactor::SF11 | SpreadingFactor::SF12 => true,
-                _ => false,
-            },
+            low_data_rate_optimize: matches!(SF, SpreadingFactor::SF11 | SpreadingFactor::SF12),
             agc_auto_on: true,
         },
         lna: Lna {
             lna_gain: LnaGain::G1,
             lna_boost_hf: true,
         },
-        max_payload: 128,
+        max_payload: MAX_PAYLOAD as u8,
     })?;
 
     lora.config_pa_ramp_time(PaRampTime::US50)?;
@@ -68,27 +103,101 @@ pub fn main() -> Result<(), lora::Error> {
     lora.config_power(23)?;
 
     println!(
-        "Send packets at {:#?} on {:.6} Mhz.",
+        "Send {} packets at {:#?} on {:.6} Mhz.",
+        encoding,
         SF,
         (FREQ as f64) / 1000000.0
     );
     println!("------------------");
 
+    // frame counter of each emulated device
+    let mut fcnts = [0u32; ADDR_LST.len()];
+
     loop {
-        let msg = {
-            use rand::seq::SliceRandom;
-            String::from("{\"addr\":\"")
-                + &ADDR_LST
-                    .choose(&mut rand::thread_rng())
-                    .unwrap()
-                    .to_string()
-                + "\",\"payload\":\""
-                + PAYLOAD
-                + "\"}"
+        let payload = match readings() {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("{e}");
+                thread::sleep(time::Duration::from_secs(5));
+                continue;
+            }
         };
+        let mut msg = {
+            use rand::Rng;
+            let dev = rand::thread_rng().gen_range(0..ADDR_LST.len());
+            fcnts[dev] = fcnts[dev].wrapping_add(1);
+            Msg {
+                addr: ADDR_LST[dev],
+                fcnt: fcnts[dev],
+                port: PORT,
+                payload,
+            }
+        };
+        println!("send: {:?}", &msg);
+        if let Some(key) = keys.get(msg.addr) {
+            msg.encrypt(key, msg::crypto::Dir::Up);
+        }
 
-        println!("send: {}", &msg);
-        lora.transmit(&msg.as_bytes())?;
+        let frames = match frames(&msg, encoding) {
+            Ok(frames) => frames,
+            Err(e) => {
+                eprintln!("{e}");
+                thread::sleep(time::Duration::from_secs(5));
+                continue;
+            }
+        };
+        for frame in frames {
+            lora.transmit(&frame)?;
+            // wait for the end of the transmission before the next fragment
+            if !lora.wait_tx_done(TX_TIMEOUT)? {
+                eprintln!("transmission timed out, message dropped");
+                break;
+            }
+        }
         thread::sleep(time::Duration::from_secs(5))
     }
 }
+
+// CayenneLPP readings, now and then with a location fix that makes the
+// payload large enough to be fragmented
+fn readings() -> Result<Vec<u8>, msg::Error> {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut lpp = msg::lpp::Encoder::new();
+    lpp.temperature(1, rng.gen_range(15.0..30.0))?
+        .humidity(2, rng.gen_range(30.0..70.0))?
+        .barometer(3, rng.gen_range(980.0..1040.0))?;
+    if rng.gen_bool(0.2) {
+        lpp.gps(4, 44.4949, 11.3426, rng.gen_range(50.0..60.0))?
+            .accelerometer(5, [0.0, 0.0, 1.0])?;
+    }
+    Ok(lpp.finish())
+}
+
+// Radio frames of a message: the whole packet if it fits, its fragments
+// otherwise (the fragment size shrinks until every frame fits, down to
+// MIN_CHUNK bytes)
+fn frames(msg: &Msg, encoding: Encoding) -> Result<Vec<Vec<u8>>, msg::Error> {
+    let packet = Packet::Msg(msg.clone()).serialize_as(encoding)?;
+    if packet.len() <= MAX_PAYLOAD {
+        return Ok(vec![packet]);
+    }
+
+    let mut chunk = MAX_PAYLOAD - frag::PACKET_OVERHEAD;
+    while chunk >= MIN_CHUNK {
+        let frames = frag::fragment(msg, chunk, FRAG_GROUP)?
+            .into_iter()
+            .map(|f| Packet::Fragment(f).serialize_as(encoding))
+            .collect::<Result<Vec<_>, _>>()?;
+        if frames.iter().all(|f| f.len() <= MAX_PAYLOAD) {
+            return Ok(frames);
+        }
+        chunk /= 2;
+    }
+    Err(msg::Error::PayloadTooLarge(msg.payload.len()))
+}
+
+fn help() -> ! {
+    println!("Usage: phy_dev [--encoding <bincode|json|cbor>] [--app-keys <file>]");
+    process::exit(1)
+}
@@ -0,0 +1,209 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Owner side of the ledger registry, on a file ledger
+//
+//   cargo run -p smart_gw --example ledger_tool -- keygen <key>
+//   cargo run -p smart_gw --example ledger_tool -- sign <key> <module.wasm>
+//   cargo run -p smart_gw --example ledger_tool -- \
+//       claim <operator key> <addr> <owner public key>
+//   cargo run -p smart_gw --example ledger_tool -- \
+//       publish <ledger> <key> <addr> <module.wasm> <version> <owner> [<claim>]
+//   cargo run -p smart_gw --example ledger_tool -- verify <ledger> [<operators>]
+//
+// keygen writes a new Ed25519 signing key (in hex, readable by its owner
+// only) and prints its public key, the line to add to the trusted publishers
+// (or operators) of gateways. sign writes the signature of a module next to
+// it (<module.wasm>.sig). claim prints the claim of an end device by an
+// owner key, signed by an operator, for the first binding of the device.
+// publish stores the module (and its signature) by hash in the "modules"
+// directory next to the ledger, and appends a binding of the end device to
+// it, signed with the key. verify checks the chain, and the claims with the
+// operators file (in the trusted publishers format), and lists the current
+// bindings.
+//
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use smart_gw::ledger::{self, Binding, Chain, Claims, FileLedger, Ledger};
+use smart_gw::trust::{self, Publishers};
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["keygen", key] => keygen(Path::new(key)),
+        ["sign", key, module] => sign(Path::new(key), Path::new(module)),
+        ["claim", operator, addr, owner_key] => claim(Path::new(operator), addr, owner_key),
+        ["publish", ledger, key, addr, module, version, owner, ref claim @ ..]
+            if claim.len() <= 1 =>
+        {
+            publish(
+                Path::new(ledger),
+                Path::new(key),
+                addr,
+                Path::new(module),
+                version,
+                owner,
+                claim.first().copied(),
+            )
+        }
+        ["verify", ledger] => verify(Path::new(ledger), None),
+        ["verify", ledger, operators] => verify(Path::new(ledger), Some(Path::new(operators))),
+        _ => {
+            eprintln!(
+                "Usage: ledger_tool keygen <key> | sign <key> <module.wasm> | \
+                claim <operator key> <addr> <owner public key> | \
+                publish <ledger> <key> <addr> <module.wasm> <version> <owner> [<claim>] | \
+                verify <ledger> [<operators>]"
+            );
+            process::exit(1)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        process::exit(1)
+    }
+}
+
+fn keygen(path: &Path) -> Result<(), String> {
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    // a secret key, never overwritten
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(hex::encode(key.to_bytes()).as_bytes()))
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    println!("{}", hex::encode(key.verifying_key().as_bytes()));
+    Ok(())
+}
+
+fn sign(key: &Path, module: &Path) -> Result<(), String> {
+    let key = load_key(key)?;
+    let bytes = fs::read(module).map_err(|e| format!("{}: {e}", module.display()))?;
+    let path = trust::signature_path(module);
+    fs::write(&path, trust::sign(&key, &bytes)).map_err(|e| e.to_string())?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+fn claim(operator: &Path, addr: &str, owner_key: &str) -> Result<(), String> {
+    let operator = load_key(operator)?;
+    let device = u64::from_str_radix(addr, 16).map_err(|e| format!("{addr}: {e}"))?;
+    let owner_key = hex::decode(owner_key.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .and_then(|b| VerifyingKey::from_bytes(&b).ok())
+        .ok_or(format!("{owner_key}: bad public key"))?;
+    println!("{}", ledger::claim(&operator, device, &owner_key));
+    Ok(())
+}
+
+fn load_key(path: &Path) -> Result<SigningKey, String> {
+    let hex = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let bytes: [u8; 32] = hex::decode(hex.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or(format!("{}: bad key", path.display()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+fn publish(
+    ledger_path: &Path,
+    key: &Path,
+    addr: &str,
+    module: &Path,
+    version: &str,
+    owner: &str,
+    claim: Option<&str>,
+) -> Result<(), String> {
+    let key = load_key(key)?;
+    let device = u64::from_str_radix(addr, 16).map_err(|e| format!("{addr}: {e}"))?;
+    let bytes = fs::read(module).map_err(|e| format!("{}: {e}", module.display()))?;
+    let module_hash = trust::module_hash(&bytes);
+
+    let module_dir = ledger_path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join("modules");
+    fs::create_dir_all(&module_dir).map_err(|e| e.to_string())?;
+    let stored = module_dir.join(format!("{module_hash}.wasm"));
+    fs::write(&stored, bytes).map_err(|e| e.to_string())?;
+    if let Ok(signature) = fs::read(trust::signature_path(module)) {
+        fs::write(trust::signature_path(&stored), signature).map_err(|e| e.to_string())?;
+    }
+
+    // claims are checked by the gateways, with their operator keys
+    let ledger = FileLedger::new(ledger_path.to_path_buf());
+    let records = ledger.records().map_err(|e| e.to_string())?;
+    let mut chain = Chain::verify(&records, Claims::FirstUse).map_err(|e| e.to_string())?;
+    let binding = Binding {
+        device,
+        module: module
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        version: version.to_string(),
+        module_hash,
+        owner: owner.to_string(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs()),
+    };
+    let record = chain
+        .seal(binding, &key, claim.map(str::to_string))
+        .map_err(|e| e.to_string())?;
+    // refused here rather than breaking the ledger for everyone
+    chain.push(&record).map_err(|e| e.to_string())?;
+    ledger.append(&record).map_err(|e| e.to_string())?;
+    println!("record {}: {}", record.seq, record.hash);
+    Ok(())
+}
+
+fn verify(path: &Path, operators: Option<&Path>) -> Result<(), String> {
+    let claims = match operators {
+        Some(path) => Claims::Operators(
+            Publishers::load(path).map_err(|e| format!("{}: {e}", path.display()))?,
+        ),
+        None => Claims::FirstUse,
+    };
+    let ledger = FileLedger::new(path.to_path_buf());
+    let records = ledger.records().map_err(|e| e.to_string())?;
+    let chain = Chain::verify(&records, claims).map_err(|e| e.to_string())?;
+    println!("{} records verified.", chain.len());
+    let mut bindings: Vec<_> = chain.bindings().collect();
+    bindings.sort_by_key(|(b, _)| b.device);
+    for (binding, key) in bindings {
+        println!(
+            "{:08x}: {} {} ({}) by {} [{}]",
+            binding.device,
+            binding.module,
+            binding.version,
+            binding.module_hash,
+            binding.owner,
+            key
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Stand-in remote registry, serving the drivers of a local manifest
+//
+//   cargo run -p smart_gw --example registry_server -- <manifest> [<ip:port>]
+//   cargo run -p smart_gw -- --registry http://127.0.0.1:8080
+//
+// Answers GET /devices/<addr> with the ServiceRecord of the end device, the
+// module being named after its file, GET /modules/<module>/<version> with
+// the module (chunked, as many servers send large bodies), and
+// GET /modules/<module>/<version>/signature with its signature, if any.
+//
+
+use smart_gw::discovery::{LocalRegistry, ServiceDiscovery, ServiceRecord};
+use smart_gw::trust;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process;
+
+const CHUNK: usize = 4096;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let Some(manifest) = args.next() else {
+        eprintln!("Usage: registry_server <manifest> [<ip:port>]");
+        process::exit(1)
+    };
+    let bind = args.next().unwrap_or("127.0.0.1:8080".to_string());
+    let registry = LocalRegistry::load(Path::new(&manifest)).unwrap();
+    let listener = TcpListener::bind(&bind).unwrap();
+    println!("[registry] serving {manifest} on {bind}");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = serve(&registry, stream) {
+                    eprintln!("[registry] {e}");
+                }
+            }
+            Err(e) => eprintln!("[registry] {e}"),
+        }
+    }
+}
+
+fn serve(registry: &LocalRegistry, mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = String::new();
+    let mut reader = BufReader::new(&stream);
+    reader.read_line(&mut request)?;
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    println!("[registry] GET {path}");
+
+    let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match parts[..] {
+        ["devices", addr] => {
+            let record = u64::from_str_radix(addr, 16)
+                .ok()
+                .and_then(|addr| registry.discover(addr).ok())
+                .map(|service| ServiceRecord {
+                    module: file_name(&service.module),
+                    version: service.version,
+                    owner: service.owner,
+                    sha256: service.sha256,
+                    policy: service.policy,
+                });
+            match record {
+                Some(record) => {
+                    let body = serde_json::to_vec(&record).unwrap();
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )?;
+                    stream.write_all(&body)
+                }
+                None => not_found(&mut stream),
+            }
+        }
+        ["modules", module, version, "signature"] => {
+            let signature = registry
+                .services()
+                .find(|s| file_name(&s.module) == module && s.version == version)
+                .and_then(|s| fs::read(trust::signature_path(&s.module)).ok());
+            match signature {
+                Some(signature) => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        signature.len()
+                    )?;
+                    stream.write_all(&signature)
+                }
+                None => not_found(&mut stream),
+            }
+        }
+        ["modules", module, version] => {
+            let service = registry
+                .services()
+                .find(|s| file_name(&s.module) == module && s.version == version);
+            let Some(service) = service else {
+                return not_found(&mut stream);
+            };
+            let bytes = fs::read(&service.module)?;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/wasm\r\n\
+                Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+            )?;
+            for chunk in bytes.chunks(CHUNK) {
+                write!(stream, "{:x}\r\n", chunk.len())?;
+                stream.write_all(chunk)?;
+                write!(stream, "\r\n")?;
+            }
+            write!(stream, "0\r\n\r\n")
+        }
+        _ => not_found(&mut stream),
+    }
+}
+
+fn not_found(stream: &mut TcpStream) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    )
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -13,40 +13,377 @@
 // limitations under the License.
 //
 
-// Pub/sub broker of wasmer_wasix::Pipe to wasm Stdin
+// Pub/sub broker of framed messages to wasm Stdin
+//
+// The broker is shared (cloning it gives another handle to the same
+// subscriptions). Subscriptions last as long as their Subscription handle,
+// and subscribers whose reader is gone are dropped on the next publish.
+// Each subscriber has a bounded queue (see queue), so a slow one only loses
+// its own frames. Failures are reported as events rather than aborting the
+// gateway.
+//
+
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+
+use msg::message::Message;
+use msg::stream;
+
+use crate::queue::{PushError, Queue, QueueConfig, QueueReader, QueueStats};
+use crate::topic::TopicTrie;
+use crate::Result;
+
+// Filter matching every topic
+pub const ALL: &str = "#";
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Subscribed {
+        id: u64,
+        filter: String,
+    },
+    Unsubscribed {
+        id: u64,
+        filter: String,
+    },
+    // the reader of the subscriber is gone, the subscription was removed
+    Dead {
+        id: u64,
+        filter: String,
+        error: io::ErrorKind,
+    },
+    // the queue of the subscriber overflowed under Overflow::Disconnect,
+    // the subscription was removed
+    Overflow {
+        id: u64,
+        filter: String,
+    },
+    // the message could not be delivered to anyone
+    PublishFailed {
+        topic: String,
+        error: String,
+    },
+}
+
+struct Sub {
+    id: u64,
+    filter: String,
+    queue: Arc<Queue>,
+}
 
-use std::io::Write;
-use wasmer_wasix::Pipe;
+struct Inner {
+    subs: TopicTrie<Sub>,
+    next_id: u64,
+    listeners: Vec<Sender<Event>>,
+}
 
-use msg::Msg;
+impl Inner {
+    fn emit(&mut self, event: Event) {
+        self.listeners.retain(|l| l.send(event.clone()).is_ok());
+    }
 
-pub const ALL: String = String::new();
+    // false if the subscription was already removed
+    fn unsubscribe(&mut self, id: u64, filter: &str) -> bool {
+        let removed = self.subs.remove_if(filter, |sub| {
+            let found = sub.id == id;
+            if found {
+                sub.queue.close();
+            }
+            found
+        });
+        removed > 0
+    }
+}
 
+#[derive(Clone)]
 pub struct Broker {
-    subs: Vec<(String, Pipe)>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+// Receiving end of a subscription, unsubscribes when dropped
+pub struct Subscription {
+    id: u64,
+    filter: String,
+    reader: Option<QueueReader>,
+    queue: Arc<Queue>,
+    broker: Weak<Mutex<Inner>>,
+}
+
+impl Subscription {
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    // Hand over the reading end of the subscription, e.g. as the stdin of a
+    // virtual device. It reaches EOF once the subscription is dropped.
+    pub fn take_reader(&mut self) -> Option<QueueReader> {
+        self.reader.take()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    // Handle to end the subscription from elsewhere, e.g. to stop the virtual
+    // device reading it
+    pub fn closer(&self) -> Closer {
+        Closer {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Closer {
+    queue: Arc<Queue>,
+}
+
+impl Closer {
+    // The reader gets EOF once it drained the queued frames
+    pub fn close(&self) {
+        self.queue.close()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(inner) = self.broker.upgrade() {
+            let mut inner = inner.lock().unwrap();
+            if inner.unsubscribe(self.id, &self.filter) {
+                inner.emit(Event::Unsubscribed {
+                    id: self.id,
+                    filter: self.filter.clone(),
+                });
+            }
+        }
+    }
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Broker {
     pub fn new() -> Self {
-        Self { subs: Vec::new() }
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                subs: TopicTrie::new(),
+                next_id: 0,
+                listeners: Vec::new(),
+            })),
+        }
     }
 
-    pub fn subscribe(&mut self, topic: String) -> Pipe {
-        println!("[broker] new subscription on topic {topic}.");
-        // create sender/receiver, store send Pipe and return receiver
-        let (sender, receiver) = Pipe::channel();
-        self.subs.push((topic, sender));
+    // New listener of the broker events
+    pub fn events(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.inner.lock().unwrap().listeners.push(sender);
         receiver
     }
 
-    pub fn publish(&mut self, topic: String, msg: Msg) {
-        // write on all pub Pipes that match topic
-        // (this may be optimizable with some algo theory)
-        for (t, s) in &mut self.subs {
-            if topic.starts_with(&t[..]) {
-                msg.serialize_into(s.by_ref())
-                    .expect(&format!("failed to write on pipe {s:?} (subbed to: {t})")[..]);
+    // Subscribe to the topics matching filter (see topic for wildcards)
+    pub fn subscribe(&self, filter: &str) -> Result<Subscription> {
+        self.subscribe_with(filter, QueueConfig::default())
+    }
+
+    pub fn subscribe_with(&self, filter: &str, config: QueueConfig) -> Result<Subscription> {
+        crate::topic::validate_filter(filter)?;
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        let (queue, reader) = Queue::new(config);
+        let sub = Sub {
+            id,
+            filter: filter.to_string(),
+            queue: queue.clone(),
+        };
+        inner.subs.insert(filter, sub)?;
+        inner.next_id += 1;
+        println!("[broker] new subscription on topic {filter}.");
+        inner.emit(Event::Subscribed {
+            id,
+            filter: filter.to_string(),
+        });
+        Ok(Subscription {
+            id,
+            filter: filter.to_string(),
+            reader: Some(reader),
+            queue,
+            broker: Arc::downgrade(&self.inner),
+        })
+    }
+
+    pub fn publish(&self, topic: String, msg: Message) {
+        let frame = match stream::shared_frame(&msg) {
+            Ok(frame) => frame,
+            Err(e) => {
+                let error = e.to_string();
+                let mut inner = self.inner.lock().unwrap();
+                return inner.emit(Event::PublishFailed { topic, error });
+            }
+        };
+
+        // queue on all the subscriptions that match topic, without holding
+        // the broker lock: a full queue under Overflow::Block only stalls
+        // this publisher, not the whole broker
+        let mut targets = Vec::new();
+        self.inner.lock().unwrap().subs.visit_mut(&topic, |sub| {
+            targets.push((sub.id, sub.filter.clone(), sub.queue.clone()))
+        });
+        let mut removed = Vec::new();
+        for (id, filter, queue) in targets {
+            match queue.push(frame.clone()) {
+                Ok(()) | Err(PushError::Dropped) => (),
+                Err(PushError::Overflow) => removed.push((id, filter, None)),
+                Err(PushError::Dead(e)) => removed.push((id, filter, Some(e))),
             }
         }
+        if removed.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        for (id, filter, error) in removed {
+            // unless unsubscribed meanwhile
+            if inner.unsubscribe(id, &filter) {
+                inner.emit(match error {
+                    Some(error) => Event::Dead { id, filter, error },
+                    None => Event::Overflow { id, filter },
+                });
+            }
+        }
+    }
+
+    // Queue statistics of every subscription
+    pub fn stats(&self) -> Vec<(u64, String, QueueStats)> {
+        let mut stats = Vec::new();
+        self.inner.lock().unwrap().subs.for_each(|sub| {
+            stats.push((sub.id, sub.filter.clone(), sub.queue.stats()));
+        });
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Overflow;
+    use msg::stream::FrameReader;
+    use std::thread;
+    use std::time::Duration;
+
+    fn ack(fcnt: u32) -> Message {
+        Message::Ack { addr: 1, fcnt }
+    }
+
+    // Events emitted so far, but the subscriptions
+    fn emitted(events: &Receiver<Event>) -> Vec<Event> {
+        events
+            .try_iter()
+            .filter(|e| !matches!(e, Event::Subscribed { .. }))
+            .collect()
+    }
+
+    #[test]
+    fn unsubscribe_on_drop() {
+        let broker = Broker::new();
+        let events = broker.events();
+        let mut sub = broker.subscribe("ack/+").unwrap();
+        let mut reader = FrameReader::new(sub.take_reader().unwrap());
+        broker.publish("ack/1".to_string(), ack(1));
+        assert_eq!(broker.stats().len(), 1);
+
+        drop(sub);
+        assert!(matches!(
+            &emitted(&events)[..],
+            [Event::Unsubscribed { id: 0, filter }] if filter == "ack/+"
+        ));
+        assert!(broker.stats().is_empty());
+        // queued frames are still read, then EOF
+        broker.publish("ack/1".to_string(), ack(2));
+        assert!(matches!(
+            reader.read_message(),
+            Ok(Message::Ack { fcnt: 1, .. })
+        ));
+        assert!(matches!(reader.read_message(), Err(msg::Error::Closed)));
+    }
+
+    #[test]
+    fn dead_reader() {
+        let broker = Broker::new();
+        let events = broker.events();
+        let mut sub = broker.subscribe("#").unwrap();
+        drop(sub.take_reader());
+        assert!(emitted(&events).is_empty());
+
+        broker.publish("ack/1".to_string(), ack(1));
+        assert!(matches!(
+            &emitted(&events)[..],
+            [Event::Dead {
+                id: 0,
+                error: io::ErrorKind::BrokenPipe,
+                ..
+            }]
+        ));
+        assert!(broker.stats().is_empty());
+        // already removed, not unsubscribed twice
+        drop(sub);
+        assert!(emitted(&events).is_empty());
+    }
+
+    #[test]
+    fn overflow() {
+        let broker = Broker::new();
+        let events = broker.events();
+        let config = QueueConfig {
+            capacity: 1,
+            overflow: Overflow::Disconnect,
+        };
+        let _sub = broker.subscribe_with("#", config).unwrap();
+        broker.publish("ack/1".to_string(), ack(1));
+        broker.publish("ack/1".to_string(), ack(2));
+        assert!(matches!(
+            &emitted(&events)[..],
+            [Event::Overflow { id: 0, .. }]
+        ));
+    }
+
+    // A publisher blocked on a full queue holds no lock: other publishers go
+    // on, and the blocked subscription can be dropped meanwhile
+    #[test]
+    fn publish_outside_the_lock() {
+        let broker = Broker::new();
+        let events = broker.events();
+        let config = QueueConfig {
+            capacity: 1,
+            overflow: Overflow::Block(Duration::from_secs(60)),
+        };
+        let blocked = broker.subscribe_with("ack/1", config).unwrap();
+        let mut other = broker.subscribe("ack/2").unwrap();
+        let mut other = FrameReader::new(other.take_reader().unwrap());
+        broker.publish("ack/1".to_string(), ack(1));
+
+        let publisher = {
+            let broker = broker.clone();
+            thread::spawn(move || broker.publish("ack/1".to_string(), ack(2)))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!publisher.is_finished());
+        broker.publish("ack/2".to_string(), ack(3));
+        assert!(matches!(
+            other.read_message(),
+            Ok(Message::Ack { fcnt: 3, .. })
+        ));
+
+        // dropping its reader wakes the blocked publisher
+        drop(blocked);
+        publisher.join().unwrap();
+        let emitted = emitted(&events);
+        assert!(
+            matches!(&emitted[..], [Event::Unsubscribed { id: 0, .. }]),
+            "{emitted:?}"
+        );
+        assert_eq!(broker.stats().len(), 1);
     }
 }
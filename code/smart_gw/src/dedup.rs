@@ -0,0 +1,181 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Cross-gateway deduplication of uplinks heard by overlapping gateways
+//
+// Each gateway sends the digest of every uplink it receives to its peers over
+// UDP, and holds the uplink for the election window while the listener thread
+// collects the digests of the same (addr, fcnt) from the others. All gateways
+// rank the same digests the same way, so exactly one delivers the uplink: the
+// home gateway of the end device if it heard it, the best RSSI (then SNR,
+// then lowest id) otherwise.
+//
+// Digests are not authenticated: any host that can send to the peer socket
+// can claim to be the home gateway of a device, or report a better RSSI, and
+// have its uplinks dropped by every gateway. Peer sockets must only be
+// reachable from the trusted neighbouring gateways (e.g. a VPN).
+//
+
+use msg::digest::{self, Digest};
+use msg::uplink::Uplink;
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+
+// Digests are kept this long, to recognize late receptions
+const EXPIRY: Duration = Duration::from_secs(60);
+
+type Digests = HashMap<(u64, u32), (Instant, Vec<Digest>)>;
+
+pub struct Dedup {
+    gw_id: u64,
+    socket: UdpSocket,
+    peers: Vec<SocketAddr>,
+    homes: HashSet<u64>,
+    window: Duration,
+    digests: Arc<Mutex<Digests>>,
+    // uplinks held until their election closes, and whether they expect an Ack
+    pending: VecDeque<(Instant, Uplink, bool)>,
+    _listener: JoinHandle<()>,
+}
+
+impl Dedup {
+    // `homes` lists the end devices this gateway is the home of
+    pub fn new(
+        gw_id: u64,
+        bind: SocketAddr,
+        peers: Vec<SocketAddr>,
+        homes: HashSet<u64>,
+        window: Duration,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(bind).map_err(Error::Io)?;
+        let digests = Arc::new(Mutex::new(Digests::new()));
+        println!("[dedup] listening on {bind}, peers: {peers:?}.");
+        Ok(Self {
+            gw_id,
+            _listener: Self::listen(
+                gw_id,
+                socket.try_clone().map_err(Error::Io)?,
+                digests.clone(),
+            ),
+            socket,
+            peers,
+            homes,
+            window,
+            digests,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn listen(gw_id: u64, socket: UdpSocket, digests: Arc<Mutex<Digests>>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            loop {
+                let len = match socket.recv_from(&mut buf) {
+                    Ok((len, _)) => len,
+                    Err(e) => {
+                        eprintln!("[dedup] {e}");
+                        continue;
+                    }
+                };
+                match digest::deserialize(&buf[..len]) {
+                    Ok(d) if d.meta.gateway != gw_id => insert(&mut digests.lock().unwrap(), d),
+                    Ok(_) => (),
+                    Err(e) => eprintln!("[dedup] bad digest: {e}"),
+                }
+            }
+        })
+    }
+
+    // Announce an uplink to the peers and hold it for the election window
+    pub fn submit(&mut self, up: Uplink, confirmed: bool) {
+        let own = Digest::new(&up, self.homes.contains(&up.msg.addr));
+        match own.serialize() {
+            Ok(bytes) => {
+                for peer in &self.peers {
+                    if let Err(e) = self.socket.send_to(&bytes, peer) {
+                        eprintln!("[dedup] failed to send digest to {peer}: {e}");
+                    }
+                }
+            }
+            Err(e) => eprintln!("[dedup] {e}"),
+        }
+        insert(&mut self.digests.lock().unwrap(), own);
+        self.pending
+            .push_back((Instant::now() + self.window, up, confirmed));
+    }
+
+    // When the election of the oldest held uplink closes
+    fn deadline(&self) -> Option<Instant> {
+        self.pending.front().map(|(deadline, _, _)| *deadline)
+    }
+
+    // Never blocks: the held uplinks whose election closed and this gateway
+    // won, to be delivered
+    pub fn poll(&mut self) -> Vec<(Uplink, bool)> {
+        let now = Instant::now();
+        let mut elected = Vec::new();
+        while self.deadline().is_some_and(|deadline| deadline <= now) {
+            let (_, up, confirmed) = self.pending.pop_front().unwrap();
+            if self.won(&up) {
+                elected.push((up, confirmed));
+            }
+        }
+        elected
+    }
+
+    fn won(&self, up: &Uplink) -> bool {
+        let key = (up.msg.addr, up.msg.fcnt);
+        let mut digests = self.digests.lock().unwrap();
+        digests.retain(|_, (first, _)| first.elapsed() < EXPIRY);
+        let winner = digests
+            .get(&key)
+            .and_then(|(_, ds)| ds.iter().max_by_key(|d| rank(d)))
+            .map(|d| d.meta.gateway);
+        let heard_by = digests.get(&key).map_or(1, |(_, ds)| ds.len());
+
+        let deliver = winner == Some(self.gw_id);
+        if !deliver {
+            println!(
+                "[dedup] {:08x}/{} heard by {heard_by} gateways, delivered by {:016x}.",
+                key.0,
+                key.1,
+                winner.unwrap_or_default()
+            );
+        }
+        deliver
+    }
+}
+
+fn insert(digests: &mut Digests, d: Digest) {
+    let (_, ds) = digests
+        .entry((d.addr, d.fcnt))
+        .or_insert_with(|| (Instant::now(), Vec::new()));
+    // a gateway may hear the same uplink twice (e.g. retransmissions)
+    if !ds.iter().any(|other| other.meta.gateway == d.meta.gateway) {
+        ds.push(d);
+    }
+}
+
+// Greatest wins
+fn rank(d: &Digest) -> (bool, i32, i32, Reverse<u64>) {
+    (d.home, d.meta.rssi, d.meta.snr, Reverse(d.meta.gateway))
+}
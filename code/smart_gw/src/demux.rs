@@ -13,21 +13,110 @@
 // limitations under the License.
 //
 
+use crate::fcnt::{Counters, FcntTracker, Policy, Verdict};
 use crate::{broker::Broker, vdctrl::VirtDevCtrl};
 
+use msg::message::Message;
+
+// Messages are routed by type on topics "<type>/<addr>[/<port>]"
+pub fn topic(msg: &Message) -> String {
+    match msg {
+        Message::Uplink(up) | Message::ConfirmedUplink(up) => {
+            format!("uplink/{:08x}/{}", up.msg.addr, up.msg.port)
+        }
+        Message::Downlink(msg) => format!("downlink/{:08x}/{}", msg.addr, msg.port),
+        Message::Ack { addr, .. } => format!("ack/{addr:08x}"),
+        Message::DevCtrl { addr, .. } => format!("devctrl/{addr:08x}"),
+        Message::GwCtrl(_) => "gwctrl".to_string(),
+        Message::Readings { addr, .. } => format!("readings/{addr:08x}"),
+        Message::Event { addr, name, .. } => format!("event/{addr:08x}/{name}"),
+    }
+}
+
+// Messages written by the virtual device of an end device are routed on
+// "out/<addr>/<topic>", e.g. "out/00000001/readings/00000001"
+pub fn output_topic(addr: u64, msg: &Message) -> String {
+    format!("out/{addr:08x}/{}", topic(msg))
+}
+
+// Topic filter of all the output of a virtual device
+pub fn output_filter(addr: u64) -> String {
+    format!("out/{addr:08x}/#")
+}
+
+// Topic filter of all the uplinks of an end device
+pub fn uplink_topic(addr: u64) -> String {
+    format!("uplink/{addr:08x}/+")
+}
+
 pub struct Demux {
     vdctrl: VirtDevCtrl,
     broker: Broker,
+    fcnts: FcntTracker,
 }
 
 impl Demux {
-    pub fn new(broker: Broker, vdctrl: VirtDevCtrl) -> Self {
-        Self { broker, vdctrl }
+    pub fn new(broker: Broker, vdctrl: VirtDevCtrl, policy: Policy) -> Self {
+        Self {
+            broker,
+            vdctrl,
+            fcnts: FcntTracker::new(policy),
+        }
+    }
+
+    // Frame counter statistics of an end device
+    pub fn counters(&self, addr: u64) -> Option<&Counters> {
+        self.fcnts.counters(addr)
     }
 
-    pub fn dispatch(&mut self, msg: msg::Msg) {
+    // Uplinks are only published if their fcnt is accepted, other messages
+    // always are
+    pub fn dispatch(&mut self, msg: Message) -> Verdict {
+        let mut verdict = Verdict::Accept;
+        if let Message::Uplink(ref up) | Message::ConfirmedUplink(ref up) = msg {
+            let addr = up.msg.addr;
+            verdict = self.fcnts.check(&up.msg);
+            match verdict {
+                Verdict::Accept => (),
+                Verdict::Reset => println!("[demux] fcnt reset of {addr:08x}."),
+                verdict => {
+                    let counters = self.fcnts.counters(addr);
+                    println!(
+                        "[demux] drop {verdict:?} {addr:08x}/{} ({counters:?}).",
+                        up.msg.fcnt
+                    );
+                    // a retransmission means the Ack got lost
+                    if let (Verdict::Duplicate, Message::ConfirmedUplink(_)) = (verdict, &msg) {
+                        self.ack(addr, up.msg.fcnt);
+                    }
+                    return verdict;
+                }
+            }
+        }
+
         println!("[demux] send: {msg:?}");
-        self.vdctrl.instantiate_if_new(&mut self.broker, msg.addr);
-        self.broker.publish(format!("{:08x}", msg.addr), msg)
+        let ack = match msg {
+            Message::Uplink(ref up) => {
+                self.vdctrl.instantiate_if_new(up.msg.addr);
+                None
+            }
+            Message::ConfirmedUplink(ref up) => {
+                self.vdctrl.instantiate_if_new(up.msg.addr);
+                Some((up.msg.addr, up.msg.fcnt))
+            }
+            _ => None,
+        };
+        self.broker.publish(topic(&msg), msg);
+        // acknowledged once delivered
+        if let Some((addr, fcnt)) = ack {
+            self.ack(addr, fcnt);
+        }
+        verdict
+    }
+
+    // The gateway acknowledges reception on behalf of the network
+    fn ack(&self, addr: u64, fcnt: u32) {
+        let ack = Message::Ack { addr, fcnt };
+        self.broker.publish(topic(&ack), ack);
     }
 }
@@ -0,0 +1,588 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Discovery of the virtual device driver (wasm module) of an end device
+//
+// Registries map end device addresses to the driver published by their owner:
+//  - LocalRegistry: a JSON manifest next to the driver modules,
+//  - HttpRegistry: a remote registry, whose modules are downloaded once and
+//    cached on disk,
+//  - LedgerRegistry: signed bindings on a hash-chained ledger (see ledger).
+//
+// The manifest lists drivers for single addresses or address ranges, the
+// most specific entry wins:
+//
+//   { "drivers": [
+//       { "devices": "00000000-ffffffff", "module": "virt_dev.wasm",
+//         "version": "0.1.0", "owner": "clues" },
+//       { "devices": "00000001", "module": "thermo.wasm",
+//         "version": "1.2.0", "owner": "alice" } ] }
+//
+// Entries may announce the SHA-256 hash of their module ("sha256"), checked
+// before it runs, as its signature (see trust), and the WASI capabilities of
+// the driver ("policy", see policy).
+//
+// A remote registry answers GET /devices/<addr> with the ServiceRecord of the
+// device in JSON (404 if none), GET /modules/<module>/<version> with the
+// module itself, and GET /modules/<module>/<version>/signature with its
+// signature (404 if unsigned). Records are not authenticated: their policies
+// are capped by the grants of the gateway (see policy).
+//
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::policy::{Grants, Policy};
+use crate::trust;
+use crate::{Error, Result};
+
+// Driver of every end device when there is no registry
+pub const DEFAULT_MODULE: &str = "./target/wasm32-wasi/release/virt_dev.wasm";
+
+// Where modules downloaded from a remote registry are kept
+pub const CACHE_DIR: &str = "./registry-cache";
+
+// Driver of an end device, ready to be run
+#[derive(Clone, Debug)]
+pub struct Service {
+    pub module: PathBuf,
+    pub version: String,
+    pub owner: String,
+    // expected SHA-256 of the module, in hex
+    pub sha256: Option<String>,
+    pub policy: Policy,
+}
+
+pub trait ServiceDiscovery: Send + Sync {
+    fn discover(&self, deveui: u64) -> Result<Service>;
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    drivers: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize)]
+struct ManifestEntry {
+    devices: String, // "<addr>" or "<first addr>-<last addr>", in hex
+    module: PathBuf, // relative to the manifest
+    version: String,
+    owner: String,
+    #[serde(default)]
+    sha256: Option<String>,
+    #[serde(default)]
+    policy: Policy,
+}
+
+struct Entry {
+    first: u64,
+    last: u64,
+    service: Service,
+}
+
+pub struct LocalRegistry {
+    entries: Vec<Entry>,
+}
+
+impl LocalRegistry {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bad =
+            |e: &dyn std::fmt::Display| Error::BadManifest(format!("{}: {e}", path.display()));
+        let text = fs::read_to_string(path).map_err(Error::Io)?;
+        let manifest: Manifest = serde_json::from_str(&text).map_err(|e| bad(&e))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut entries = Vec::new();
+        for entry in manifest.drivers {
+            let (first, last) = parse_devices(&entry.devices).ok_or_else(|| bad(&entry.devices))?;
+            entry.policy.check().map_err(|e| bad(&e))?;
+            entries.push(Entry {
+                first,
+                last,
+                service: Service {
+                    module: dir.join(entry.module),
+                    version: entry.version,
+                    owner: entry.owner,
+                    sha256: entry.sha256,
+                    policy: entry.policy,
+                },
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    // Every end device gets the same driver
+    pub fn single(service: Service) -> Self {
+        Self {
+            entries: vec![Entry {
+                first: 0,
+                last: u64::MAX,
+                service,
+            }],
+        }
+    }
+
+    pub fn services(&self) -> impl Iterator<Item = &Service> {
+        self.entries.iter().map(|e| &e.service)
+    }
+}
+
+impl Default for LocalRegistry {
+    fn default() -> Self {
+        Self::single(Service {
+            module: DEFAULT_MODULE.into(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            owner: "local".to_string(),
+            sha256: None,
+            policy: Policy::default(),
+        })
+    }
+}
+
+impl ServiceDiscovery for LocalRegistry {
+    fn discover(&self, deveui: u64) -> Result<Service> {
+        self.entries
+            .iter()
+            .filter(|e| (e.first..=e.last).contains(&deveui))
+            .min_by_key(|e| e.last - e.first)
+            .map(|e| e.service.clone())
+            .ok_or(Error::NoDriver(deveui))
+    }
+}
+
+fn parse_devices(devices: &str) -> Option<(u64, u64)> {
+    let (first, last) = devices.split_once('-').unwrap_or((devices, devices));
+    let first = u64::from_str_radix(first.trim(), 16).ok()?;
+    let last = u64::from_str_radix(last.trim(), 16).ok()?;
+    (first <= last).then_some((first, last))
+}
+
+// Answer of a remote registry about an end device
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServiceRecord {
+    pub module: String,
+    pub version: String,
+    pub owner: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub policy: Policy,
+}
+
+// Lookups are cached this long, misses included
+pub const LOOKUP_TTL: Duration = Duration::from_secs(600);
+
+pub struct HttpRegistry {
+    base: String, // e.g. "http://192.168.1.2:8080"
+    cache_dir: PathBuf,
+    grants: Grants,
+    lookups: Mutex<HashMap<u64, (Instant, Option<ServiceRecord>)>>,
+}
+
+impl HttpRegistry {
+    pub fn new(base: &str, cache_dir: PathBuf, grants: Grants) -> Result<Self> {
+        fs::create_dir_all(&cache_dir).map_err(Error::Io)?;
+        Ok(Self {
+            base: base.trim_end_matches('/').to_string(),
+            cache_dir,
+            grants,
+            lookups: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn lookup(&self, deveui: u64) -> Result<Option<ServiceRecord>> {
+        if let Some((at, record)) = self.lookups.lock().unwrap().get(&deveui) {
+            if at.elapsed() < LOOKUP_TTL {
+                return Ok(record.clone());
+            }
+        }
+        let record = match http_get(&format!("{}/devices/{deveui:08x}", self.base))? {
+            Some(body) => Some(
+                serde_json::from_slice::<ServiceRecord>(&body)
+                    .map_err(|e| Error::Http(format!("bad record of {deveui:08x}: {e}")))?,
+            ),
+            None => None,
+        };
+        let mut lookups = self.lookups.lock().unwrap();
+        lookups.insert(deveui, (Instant::now(), record.clone()));
+        Ok(record)
+    }
+}
+
+impl ServiceDiscovery for HttpRegistry {
+    fn discover(&self, deveui: u64) -> Result<Service> {
+        let record = self.lookup(deveui)?.ok_or(Error::NoDriver(deveui))?;
+        // names become paths of the cache
+        for name in [&record.module, &record.version] {
+            if !is_safe_name(name) {
+                return Err(Error::Http(format!("bad module name {name}")));
+            }
+        }
+        record
+            .policy
+            .check()
+            .map_err(|e| Error::Http(format!("bad policy of {deveui:08x}: {e}")))?;
+
+        let module = self
+            .cache_dir
+            .join(&record.module)
+            .join(format!("{}.wasm", record.version));
+        // the module cached may be stale (e.g. republished under the same
+        // version) or damaged: it is checked against the hash of the record
+        let cached = match (fs::read(&module), &record.sha256) {
+            (Ok(bytes), Some(sha256)) => match trust::verify_hash(&bytes, sha256) {
+                Ok(()) => true,
+                Err(e) => {
+                    println!("[discovery] {}: {e}, downloaded again.", module.display());
+                    false
+                }
+            },
+            (Ok(_), None) => true,
+            (Err(_), _) => false,
+        };
+        if !cached {
+            let url = format!("{}/modules/{}/{}", self.base, record.module, record.version);
+            let bytes = http_get(&url)?.ok_or_else(|| Error::Http(format!("{url} not found")))?;
+            println!("[discovery] downloaded {url} ({} bytes).", bytes.len());
+            if let Some(ref sha256) = record.sha256 {
+                trust::verify_hash(&bytes, sha256)?;
+            }
+            let signature = http_get(&format!("{url}/signature"))?;
+            fs::create_dir_all(self.cache_dir.join(&record.module)).map_err(Error::Io)?;
+            let signature_path = trust::signature_path(&module);
+            match signature {
+                Some(signature) => write_complete(&signature_path, &signature)?,
+                // not the signature of the previous module
+                None => {
+                    let _ = fs::remove_file(&signature_path);
+                }
+            }
+            write_complete(&module, &bytes)?;
+        }
+        Ok(Service {
+            module,
+            version: record.version,
+            owner: record.owner,
+            sha256: record.sha256,
+            policy: record.policy.capped(self.grants),
+        })
+    }
+}
+
+// Complete files only, even if the gateway stops meanwhile. Devices sharing
+// a module may download it concurrently: each writes its own partial file,
+// the last rename wins.
+fn write_complete(path: &Path, bytes: &[u8]) -> Result<()> {
+    static PARTIALS: AtomicU64 = AtomicU64::new(0);
+    let n = PARTIALS.fetch_add(1, Ordering::Relaxed);
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{}-{n}.part", std::process::id()));
+    let partial = PathBuf::from(partial);
+    fs::write(&partial, bytes)
+        .and_then(|_| fs::rename(&partial, path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&partial);
+            Error::Io(e)
+        })
+}
+
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// Largest response accepted, headers included
+pub const MAX_RESPONSE: u64 = 32 << 20;
+
+// Minimal HTTP/1.1 GET ("http://host[:port]/path"), None on 404
+pub fn http_get(url: &str) -> Result<Option<Vec<u8>>> {
+    let bad = |what: &str| Error::Http(format!("GET {url}: {what}"));
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| bad("only http:// is supported"))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let addr = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{host}:80"),
+    };
+    let addr = addr
+        .to_socket_addrs()
+        .map_err(Error::Io)?
+        .next()
+        .ok_or_else(|| bad("unknown host"))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT).map_err(Error::Io)?;
+    stream
+        .set_read_timeout(Some(HTTP_TIMEOUT))
+        .map_err(Error::Io)?;
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n"
+    )
+    .map_err(Error::Io)?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE + 1)
+        .read_to_end(&mut response)
+        .map_err(Error::Io)?;
+    if response.len() as u64 > MAX_RESPONSE {
+        return Err(bad("response too large"));
+    }
+
+    let head_len = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| bad("truncated response"))?;
+    let head = std::str::from_utf8(&response[..head_len]).map_err(|_| bad("bad header"))?;
+    let mut lines = head.split("\r\n");
+    let status: u16 = lines
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| bad("bad status line"))?;
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse::<usize>().ok();
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    let body = &response[head_len + 4..];
+    let body = if chunked {
+        dechunk(body).ok_or_else(|| bad("bad chunked body"))?
+    } else {
+        match content_length {
+            Some(len) => body.get(..len).ok_or_else(|| bad("truncated body"))?,
+            None => body,
+        }
+        .to_vec()
+    };
+    match status {
+        200 => Ok(Some(body)),
+        404 => Ok(None),
+        status => Err(bad(&format!("status {status}"))),
+    }
+}
+
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let line_len = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_len]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_len + 2..];
+        if size == 0 {
+            return Some(data);
+        }
+        data.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serves the given raw responses, one per connection, on a local port
+    fn server(responses: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (response, stream) in responses.into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                // the request ends with an empty line
+                let mut request = BufReader::new(&stream);
+                let mut line = String::new();
+                while request.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                stream.write_all(&response).unwrap();
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    fn get(response: &[u8]) -> Result<Option<Vec<u8>>> {
+        http_get(&format!("{}/x", server(vec![response.to_vec()])))
+    }
+
+    // 200 with a Content-Length
+    fn ok(body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+        response.push_str(std::str::from_utf8(body).unwrap());
+        response.into_bytes()
+    }
+
+    const NOT_FOUND: &[u8] = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+
+    #[test]
+    fn responses() {
+        assert_eq!(get(&ok(b"module")).unwrap().unwrap(), b"module");
+        // only the announced length
+        let extra = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nabcd";
+        assert_eq!(get(extra).unwrap().unwrap(), b"ab");
+        let until_closed = b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nabcd";
+        assert_eq!(get(until_closed).unwrap().unwrap(), b"abcd");
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nwasm\r\na;ext=1\r\n module 01\r\n0\r\n\r\n";
+        assert_eq!(get(chunked).unwrap().unwrap(), b"wasm module 01");
+        assert!(get(NOT_FOUND).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_responses() {
+        for response in [
+            &b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"[..],
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabcd",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n",
+            b"HTTP/1.1 OK\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nwasm",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nwasm\r\n0\r\n\r\n",
+        ] {
+            let e = get(response).unwrap_err();
+            assert!(matches!(e, Error::Http(_)), "{e}");
+        }
+        assert!(http_get("https://localhost/").is_err());
+    }
+
+    #[test]
+    fn chunks() {
+        assert_eq!(dechunk(b"0\r\n\r\n").unwrap(), b"");
+        assert_eq!(
+            dechunk(b"3\r\nabc\r\n1\r\nd\r\n0\r\n\r\n").unwrap(),
+            b"abcd"
+        );
+        assert_eq!(dechunk(b"A\r\n0123456789\r\n0\r\n").unwrap(), b"0123456789");
+        // missing last chunk, short chunk, bad size
+        assert!(dechunk(b"3\r\nabc\r\n").is_none());
+        assert!(dechunk(b"4\r\nabc\r\n0\r\n\r\n").is_none());
+        assert!(dechunk(b"-1\r\nabc\r\n0\r\n\r\n").is_none());
+        assert!(dechunk(b"ffffffffffffffffff\r\nabc").is_none());
+    }
+
+    #[test]
+    fn devices() {
+        assert_eq!(parse_devices("0000abcd"), Some((0xabcd, 0xabcd)));
+        assert_eq!(parse_devices("00000000-ffffffff"), Some((0, 0xffffffff)));
+        assert_eq!(parse_devices(" 10 - 1f "), Some((0x10, 0x1f)));
+        for bad in ["", "xyz", "1f-10", "1-2-3", "-1"] {
+            assert_eq!(parse_devices(bad), None, "{bad}");
+        }
+    }
+
+    fn service(module: &str) -> Service {
+        Service {
+            module: module.into(),
+            version: "1".to_string(),
+            owner: "test".to_string(),
+            sha256: None,
+            policy: Policy::default(),
+        }
+    }
+
+    #[test]
+    fn most_specific() {
+        let entry = |first, last, module| Entry {
+            first,
+            last,
+            service: service(module),
+        };
+        let registry = LocalRegistry {
+            entries: vec![
+                entry(0, u64::MAX, "any"),
+                entry(0x10, 0x1f, "range"),
+                entry(0x12, 0x12, "single"),
+                entry(0x14, 0x17, "prefix"),
+            ],
+        };
+        let module = |deveui| registry.discover(deveui).unwrap().module;
+        assert_eq!(module(0x12), Path::new("single"));
+        assert_eq!(module(0x15), Path::new("prefix"));
+        assert_eq!(module(0x10), Path::new("range"));
+        assert_eq!(module(0x1f), Path::new("range"));
+        assert_eq!(module(0x20), Path::new("any"));
+
+        let none = LocalRegistry {
+            entries: vec![entry(0x10, 0x1f, "range")],
+        };
+        assert!(matches!(none.discover(0x20), Err(Error::NoDriver(0x20))));
+    }
+
+    #[test]
+    fn stale_cache() {
+        let module = b"fresh module";
+        let record = ServiceRecord {
+            module: "thermo".to_string(),
+            version: "1.0".to_string(),
+            owner: "alice".to_string(),
+            sha256: Some(trust::module_hash(module)),
+            policy: Policy::default(),
+        };
+        let record = serde_json::to_vec(&record).unwrap();
+        // record, module and no signature, then record and forged module
+        let base = server(vec![
+            ok(&record),
+            ok(module),
+            NOT_FOUND.to_vec(),
+            ok(&record),
+            ok(b"forged module"),
+        ]);
+
+        let dir = std::env::temp_dir().join(format!("discovery-{}", std::process::id()));
+        let cached = dir.join("thermo").join("1.0.wasm");
+        fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        fs::write(&cached, b"stale module").unwrap();
+        fs::write(trust::signature_path(&cached), b"stale signature").unwrap();
+
+        let registry = HttpRegistry::new(&base, dir.clone(), Grants::default()).unwrap();
+        assert_eq!(registry.discover(1).unwrap().module, cached);
+        assert_eq!(fs::read(&cached).unwrap(), module);
+        assert!(!trust::signature_path(&cached).exists());
+
+        // cached: no request
+        assert_eq!(registry.discover(1).unwrap().module, cached);
+
+        fs::write(&cached, b"damaged module").unwrap();
+        let registry = HttpRegistry::new(&base, dir.clone(), Grants::default()).unwrap();
+        let e = registry.discover(1).unwrap_err();
+        assert!(matches!(e, Error::ModuleRefused(_)), "{e}");
+        assert_eq!(fs::read(&cached).unwrap(), b"damaged module");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Downlinks to end devices, with LoRaWAN Class A timing
+//
+// Sources:
+//  - [LoRaWAN 1.0.3 Specification, Sec. 3.3]
+//  - [LoRaWAN Regional Parameters v1.0.3revA, Sec. 2.2.7 (EU863-870)]
+//
+// Downlinks and commands written by virtual devices are queued per end
+// device, the Acks of the gateway are kept by uplink frame counter. Class A
+// end devices only listen right after each uplink: the main loop schedules
+// the receive windows of the delivered uplinks and polls them, sending the
+// Ack of the uplink, or else the next queued packet, in RX1 on the uplink
+// channel or in RX2 on the regional default channel. The radio then returns
+// to continuous reception.
+//
+
+use lora::opcodes::SpreadingFactor;
+use msg::message::Message;
+use msg::stream::FrameReader;
+use msg::Packet;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::broker::{Broker, Subscription};
+use crate::{Error, Result, FREQ, SF};
+
+// Receive windows open this long after the end of the uplink
+pub const RECEIVE_DELAY1: Duration = Duration::from_secs(1);
+pub const RECEIVE_DELAY2: Duration = Duration::from_secs(2);
+
+// Latest start of a transmission in an open window: end devices only listen
+// for a few preamble symbols
+pub const RX_SLACK: Duration = Duration::from_millis(20);
+
+// Default RX2 channel of EU868 end devices (869.525 MHz, SF12/BW125)
+pub const RX2_FREQ: u32 = 869525000;
+pub const RX2_SF: SpreadingFactor = SpreadingFactor::SF12;
+
+// Queued packets per end device, the oldest are dropped beyond
+pub const MAX_QUEUED: usize = 8;
+
+// Largest frame sent by the gateway (the max_payload of the receivers)
+pub const MAX_FRAME: usize = 128;
+
+// Topics of the packets to send: virtual device downlinks and commands,
+// gateway Acks (see demux::topic and demux::output_topic)
+const FILTERS: [&str; 3] = ["out/+/downlink/#", "out/+/devctrl/#", "ack/+"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RxWindow {
+    Rx1,
+    Rx2,
+}
+
+impl RxWindow {
+    // Frequency and spreading factor of the window (RX1DROffset 0)
+    pub fn channel(self) -> (u32, SpreadingFactor) {
+        match self {
+            RxWindow::Rx1 => (FREQ, SF),
+            RxWindow::Rx2 => (RX2_FREQ, RX2_SF),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    packets: HashMap<u64, VecDeque<Packet>>,
+    // Acks by end device and uplink fcnt, with their arrival time
+    acks: HashMap<(u64, u32), SystemTime>,
+}
+
+// Receive windows following an uplink
+struct Windows {
+    addr: u64,
+    fcnt: u32,
+    rx_time: SystemTime,
+}
+
+enum Slot {
+    Ahead,
+    Open(RxWindow),
+    Missed,
+}
+
+impl Windows {
+    fn slot(&self, now: SystemTime) -> Slot {
+        for (delay, window) in [
+            (RECEIVE_DELAY1, RxWindow::Rx1),
+            (RECEIVE_DELAY2, RxWindow::Rx2),
+        ] {
+            let open = self.rx_time + delay;
+            if now < open {
+                return Slot::Ahead;
+            }
+            if now < open + RX_SLACK {
+                return Slot::Open(window);
+            }
+        }
+        Slot::Missed
+    }
+}
+
+pub struct Downlinks {
+    queues: Arc<Mutex<Queues>>,
+    windows: Vec<Windows>,
+    _listeners: Vec<JoinHandle<()>>,
+}
+
+impl Downlinks {
+    pub fn new(broker: &Broker) -> Result<Self> {
+        let queues = Arc::new(Mutex::new(Queues::default()));
+        let mut listeners = Vec::new();
+        for filter in FILTERS {
+            let sub = broker.subscribe(filter)?;
+            listeners.push(Self::listen(sub, queues.clone()));
+        }
+        Ok(Self {
+            queues,
+            windows: Vec::new(),
+            _listeners: listeners,
+        })
+    }
+
+    fn listen(mut sub: Subscription, queues: Arc<Mutex<Queues>>) -> JoinHandle<()> {
+        let mut receiver = FrameReader::new(sub.take_reader().expect("reader already taken"));
+        thread::spawn(move || {
+            // unsubscribes when the thread ends
+            let _sub = sub;
+            loop {
+                let packet = match receiver.read_message() {
+                    Ok(Message::Downlink(msg)) => Packet::Downlink(msg),
+                    Ok(Message::Ack { addr, fcnt }) => {
+                        let mut queues = queues.lock().unwrap();
+                        queues.acks.insert((addr, fcnt), SystemTime::now());
+                        continue;
+                    }
+                    Ok(Message::DevCtrl { addr, cmd }) => Packet::DevCtrl { addr, cmd },
+                    Ok(_) => continue,
+                    Err(msg::Error::Closed) => break,
+                    Err(e) => {
+                        eprintln!("[downlink] {e}");
+                        break;
+                    }
+                };
+                let mut queues = queues.lock().unwrap();
+                let queue = queues.packets.entry(packet.addr()).or_default();
+                if queue.len() == MAX_QUEUED {
+                    let dropped = queue.pop_front();
+                    println!("[downlink] queue full, drop {dropped:?}.");
+                }
+                queue.push_back(packet);
+            }
+        })
+    }
+
+    // Packets waiting for an uplink of the end device, Acks aside
+    pub fn pending(&self, addr: u64) -> usize {
+        self.queues
+            .lock()
+            .unwrap()
+            .packets
+            .get(&addr)
+            .map_or(0, VecDeque::len)
+    }
+
+    // Open the receive windows of the uplink fcnt of addr, received at
+    // rx_time (ms since the UNIX epoch)
+    pub fn schedule(&mut self, addr: u64, fcnt: u32, rx_time: u64) {
+        self.windows.push(Windows {
+            addr,
+            fcnt,
+            rx_time: UNIX_EPOCH + Duration::from_millis(rx_time),
+        });
+    }
+
+    // Send the packets of the receive windows open at now, without waiting
+    // for the others: the Ack of the uplink if any, or else the next packet
+    // queued for the end device. Windows with nothing to send wait for RX2,
+    // Acks whose windows have passed are dropped.
+    pub fn poll<E, T>(&mut self, now: SystemTime, mut encode: E, mut transmit: T)
+    where
+        E: FnMut(&Packet) -> Result<Vec<u8>>,
+        T: FnMut(&[u8], RxWindow) -> Result<()>,
+    {
+        let mut queues = self.queues.lock().unwrap();
+        let mut open = Vec::new();
+        self.windows.retain(|w| match w.slot(now) {
+            Slot::Ahead => true,
+            Slot::Open(window) => {
+                let key = (w.addr, w.fcnt);
+                let packet = match queues.acks.remove(&key) {
+                    Some(_) => Some(Packet::Ack {
+                        addr: w.addr,
+                        fcnt: w.fcnt,
+                    }),
+                    None => queues
+                        .packets
+                        .get_mut(&w.addr)
+                        .and_then(VecDeque::pop_front),
+                };
+                match packet {
+                    Some(packet) => {
+                        open.push((packet, window));
+                        false
+                    }
+                    None => window == RxWindow::Rx1,
+                }
+            }
+            Slot::Missed => {
+                if queues.acks.remove(&(w.addr, w.fcnt)).is_some()
+                    || queues.packets.get(&w.addr).is_some_and(|q| !q.is_empty())
+                {
+                    eprintln!("{}", Error::MissedRxWindow(w.addr));
+                }
+                false
+            }
+        });
+        let expiry = RECEIVE_DELAY2 + RX_SLACK;
+        queues.acks.retain(|(addr, fcnt), arrival| {
+            let live = now
+                .duration_since(*arrival)
+                .map_or(true, |age| age < expiry);
+            if !live {
+                println!("[downlink] drop stale Ack {fcnt} of {addr:08x}.");
+            }
+            live
+        });
+        drop(queues);
+
+        for (packet, window) in open {
+            let frame = match encode(&packet) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    println!("[downlink] drop {packet:?}: {e}");
+                    continue;
+                }
+            };
+            if frame.len() > MAX_FRAME {
+                println!("[downlink] drop {packet:?}: {} bytes.", frame.len());
+                continue;
+            }
+            println!("[downlink] send in {window:?}: {packet:?}");
+            if let Err(e) = transmit(&frame, window) {
+                eprintln!("{e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demux::{output_topic, topic};
+    use msg::Msg;
+
+    const ADDR: u64 = 0x1;
+    const RX_TIME: u64 = 1_000_000;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(RX_TIME + ms)
+    }
+
+    fn downlink(fcnt: u32) -> Message {
+        Message::Downlink(Msg {
+            addr: ADDR,
+            fcnt,
+            port: 1,
+            payload: vec![1, 2, 3],
+        })
+    }
+
+    // Publish on the topic the gateway would, and wait for the listeners
+    fn publish(broker: &Broker, downlinks: &Downlinks, msg: Message) {
+        let topic = match msg {
+            Message::Ack { .. } => topic(&msg),
+            _ => output_topic(ADDR, &msg),
+        };
+        let queued =
+            |q: &Queues| q.acks.len() + q.packets.values().map(VecDeque::len).sum::<usize>();
+        let before = queued(&downlinks.queues.lock().unwrap());
+        broker.publish(topic, msg);
+        while queued(&downlinks.queues.lock().unwrap()) == before {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Packets sent when polling at now
+    fn poll(downlinks: &mut Downlinks, now: SystemTime) -> Vec<(Vec<u8>, RxWindow)> {
+        let mut sent = Vec::new();
+        downlinks.poll(
+            now,
+            |packet| packet.serialize().map_err(Error::Msg),
+            |frame, window| {
+                sent.push((frame.to_vec(), window));
+                Ok(())
+            },
+        );
+        sent
+    }
+
+    fn frame(packet: Packet) -> Vec<u8> {
+        packet.serialize().unwrap()
+    }
+
+    #[test]
+    fn ack_in_rx1() {
+        let broker = Broker::new();
+        let mut downlinks = Downlinks::new(&broker).unwrap();
+        publish(&broker, &downlinks, downlink(1));
+        publish(
+            &broker,
+            &downlinks,
+            Message::Ack {
+                addr: ADDR,
+                fcnt: 7,
+            },
+        );
+        downlinks.schedule(ADDR, 7, RX_TIME);
+
+        assert!(poll(&mut downlinks, at(999)).is_empty());
+        let ack = frame(Packet::Ack {
+            addr: ADDR,
+            fcnt: 7,
+        });
+        assert_eq!(poll(&mut downlinks, at(1005)), [(ack, RxWindow::Rx1)]);
+        // one packet per uplink, the downlink waits for the next one
+        assert!(poll(&mut downlinks, at(2005)).is_empty());
+        assert_eq!(downlinks.pending(ADDR), 1);
+    }
+
+    #[test]
+    fn downlink_in_rx2() {
+        let broker = Broker::new();
+        let mut downlinks = Downlinks::new(&broker).unwrap();
+        downlinks.schedule(ADDR, 7, RX_TIME);
+        // nothing to send in RX1
+        assert!(poll(&mut downlinks, at(1000)).is_empty());
+        publish(&broker, &downlinks, downlink(1));
+        assert!(poll(&mut downlinks, at(1500)).is_empty());
+        let sent = poll(&mut downlinks, at(2000));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, RxWindow::Rx2);
+        let (freq, sf) = RxWindow::Rx2.channel();
+        assert_eq!((freq, sf as u8), (869525000, 12));
+        assert_eq!(downlinks.pending(ADDR), 0);
+    }
+
+    #[test]
+    fn stale_acks() {
+        let broker = Broker::new();
+        let mut downlinks = Downlinks::new(&broker).unwrap();
+        // the window of the uplink was missed
+        publish(
+            &broker,
+            &downlinks,
+            Message::Ack {
+                addr: ADDR,
+                fcnt: 7,
+            },
+        );
+        downlinks.schedule(ADDR, 7, RX_TIME);
+        assert!(poll(&mut downlinks, at(2500)).is_empty());
+        // an Ack without window expires
+        publish(
+            &broker,
+            &downlinks,
+            Message::Ack {
+                addr: ADDR,
+                fcnt: 8,
+            },
+        );
+        let later = SystemTime::now() + RECEIVE_DELAY2 + RX_SLACK;
+        assert!(poll(&mut downlinks, later).is_empty());
+        assert!(downlinks.queues.lock().unwrap().acks.is_empty());
+
+        // the Ack of another uplink is never sent in its place
+        publish(
+            &broker,
+            &downlinks,
+            Message::Ack {
+                addr: ADDR,
+                fcnt: 9,
+            },
+        );
+        downlinks.schedule(ADDR, 10, RX_TIME);
+        assert!(poll(&mut downlinks, at(1000)).is_empty());
+        assert!(poll(&mut downlinks, at(2000)).is_empty());
+    }
+}
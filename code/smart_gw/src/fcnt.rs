@@ -0,0 +1,359 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Frame counter tracking of end devices, to drop replayed and duplicated
+// uplinks before they reach the virtual devices
+//
+// A frame is a duplicate when a recent frame had the same fcnt and payload
+// (e.g. a retransmission), a replay when its fcnt is not acceptable under the
+// policy. At most MAX_DEVICES end devices are tracked, the least recently
+// heard one is forgotten beyond: its next frame is accepted as a first one.
+//
+
+use msg::Msg;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use crate::Error;
+
+// Largest out of order window
+pub const MAX_WINDOW: u32 = 64;
+
+// Frames remembered per device to tell duplicates from replays
+const RECENT: usize = 16;
+
+// End devices tracked at once
+pub const MAX_DEVICES: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    // fcnt must strictly increase
+    Strict,
+    // fcnt may also be up to N frames behind the last one, if not seen yet
+    // (out of order delivery), N <= MAX_WINDOW
+    Window(u32),
+    // fcnt must strictly increase, but a fcnt up to N restarts the counter
+    // (the end device rebooted). Opt-in only: the first N frames of a device
+    // can be replayed at any time.
+    Reset(u32),
+}
+
+impl Policy {
+    // Out of order window of N frames, clamped to 1..=MAX_WINDOW
+    pub fn window(n: u32) -> Self {
+        Policy::Window(n.clamp(1, MAX_WINDOW))
+    }
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    // "strict", "window[:N]" or "reset[:N]"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || Error::BadFcntPolicy(s.to_string());
+        let (name, n) = match s.split_once(':') {
+            Some((name, n)) => (name, Some(n.parse::<u32>().map_err(|_| bad())?)),
+            None => (s, None),
+        };
+        match (name, n) {
+            ("strict", None) => Ok(Policy::Strict),
+            ("window", n) => match n.unwrap_or(16) {
+                n @ 1..=MAX_WINDOW => Ok(Policy::Window(n)),
+                _ => Err(bad()),
+            },
+            ("reset", n) => Ok(Policy::Reset(n.unwrap_or(1))),
+            _ => Err(bad()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    // accepted as the first frame after a reboot of the end device
+    Reset,
+    Duplicate,
+    Replay,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    pub accepted: u64,
+    pub duplicates: u64,
+    pub replays: u64,
+    pub resets: u64,
+}
+
+struct DevState {
+    last: u32,
+    seen: u64, // bit i: frame last - i received
+    recent: VecDeque<(u32, u64)>,
+    counters: Counters,
+    heard: u64, // tick of the last frame
+}
+
+impl DevState {
+    fn new(fcnt: u32, heard: u64) -> Self {
+        Self {
+            last: fcnt,
+            seen: 1,
+            recent: VecDeque::with_capacity(RECENT),
+            counters: Counters::default(),
+            heard,
+        }
+    }
+
+    fn remember(&mut self, fcnt: u32, digest: u64) {
+        if self.recent.len() == RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((fcnt, digest));
+    }
+}
+
+pub struct FcntTracker {
+    policy: Policy,
+    devs: HashMap<u64, DevState>,
+    ticks: u64,
+}
+
+impl FcntTracker {
+    pub fn new(policy: Policy) -> Self {
+        // a wider window would not fit the seen bitmap
+        let policy = match policy {
+            Policy::Window(n) => Policy::window(n),
+            policy => policy,
+        };
+        Self {
+            policy,
+            devs: HashMap::new(),
+            ticks: 0,
+        }
+    }
+
+    pub fn counters(&self, addr: u64) -> Option<&Counters> {
+        self.devs.get(&addr).map(|dev| &dev.counters)
+    }
+
+    // Classify an uplink and update the state of its end device
+    pub fn check(&mut self, msg: &Msg) -> Verdict {
+        let digest = {
+            let mut hasher = DefaultHasher::new();
+            msg.port.hash(&mut hasher);
+            msg.payload.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        self.ticks += 1;
+        let Some(dev) = self.devs.get_mut(&msg.addr) else {
+            // first frame since the gateway started, or since it was forgotten
+            if self.devs.len() >= MAX_DEVICES {
+                self.forget_idlest();
+            }
+            let mut dev = DevState::new(msg.fcnt, self.ticks);
+            dev.remember(msg.fcnt, digest);
+            dev.counters.accepted += 1;
+            self.devs.insert(msg.addr, dev);
+            return Verdict::Accept;
+        };
+        dev.heard = self.ticks;
+
+        let verdict = if msg.fcnt > dev.last {
+            let shift = msg.fcnt - dev.last;
+            dev.seen = dev.seen.checked_shl(shift).unwrap_or(0) | 1;
+            dev.last = msg.fcnt;
+            Verdict::Accept
+        } else if dev.recent.contains(&(msg.fcnt, digest)) {
+            Verdict::Duplicate
+        } else {
+            let age = dev.last - msg.fcnt;
+            match self.policy {
+                Policy::Window(window) if age < window && dev.seen & (1 << age) == 0 => {
+                    dev.seen |= 1 << age;
+                    Verdict::Accept
+                }
+                Policy::Reset(max) if msg.fcnt <= max => {
+                    let counters = std::mem::take(&mut dev.counters);
+                    *dev = DevState::new(msg.fcnt, self.ticks);
+                    dev.counters = counters;
+                    Verdict::Reset
+                }
+                _ => Verdict::Replay,
+            }
+        };
+
+        match verdict {
+            Verdict::Accept => dev.counters.accepted += 1,
+            Verdict::Reset => {
+                dev.counters.accepted += 1;
+                dev.counters.resets += 1
+            }
+            Verdict::Duplicate => dev.counters.duplicates += 1,
+            Verdict::Replay => dev.counters.replays += 1,
+        }
+        if matches!(verdict, Verdict::Accept | Verdict::Reset) {
+            dev.remember(msg.fcnt, digest);
+        }
+        verdict
+    }
+
+    fn forget_idlest(&mut self) {
+        let idlest = self
+            .devs
+            .iter()
+            .min_by_key(|(_, dev)| dev.heard)
+            .map(|(addr, _)| *addr);
+        if let Some(addr) = idlest {
+            println!("[fcnt] too many end devices, forget {addr:08x}.");
+            self.devs.remove(&addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Verdict::*;
+
+    fn msg(addr: u64, fcnt: u32, payload: &[u8]) -> Msg {
+        Msg {
+            addr,
+            fcnt,
+            port: 1,
+            payload: payload.to_vec(),
+        }
+    }
+
+    // Verdicts of the frames of device 1, with distinct payloads
+    fn verdicts(tracker: &mut FcntTracker, fcnts: &[u32]) -> Vec<Verdict> {
+        fcnts
+            .iter()
+            .map(|&fcnt| tracker.check(&msg(1, fcnt, &fcnt.to_le_bytes())))
+            .collect()
+    }
+
+    #[test]
+    fn in_order() {
+        for policy in [Policy::Strict, Policy::window(16), Policy::Reset(1)] {
+            let mut tracker = FcntTracker::new(policy);
+            assert_eq!(verdicts(&mut tracker, &[5, 6, 8, 100]), [Accept; 4]);
+            assert_eq!(tracker.counters(1).unwrap().accepted, 4);
+        }
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut tracker = FcntTracker::new(Policy::Strict);
+        assert_eq!(tracker.check(&msg(1, 5, b"a")), Accept);
+        assert_eq!(tracker.check(&msg(1, 6, b"b")), Accept);
+        // retransmissions, recent or not the last
+        assert_eq!(tracker.check(&msg(1, 6, b"b")), Duplicate);
+        assert_eq!(tracker.check(&msg(1, 5, b"a")), Duplicate);
+        // same counter, other payload or port
+        assert_eq!(tracker.check(&msg(1, 6, b"c")), Replay);
+        assert_eq!(
+            tracker.check(&Msg {
+                port: 2,
+                ..msg(1, 6, b"b")
+            }),
+            Replay
+        );
+        // other devices are tracked apart
+        assert_eq!(tracker.check(&msg(2, 6, b"b")), Accept);
+
+        let counters = tracker.counters(1).unwrap();
+        assert_eq!((counters.duplicates, counters.replays), (2, 2));
+    }
+
+    #[test]
+    fn window() {
+        let mut tracker = FcntTracker::new(Policy::window(4));
+        assert_eq!(
+            verdicts(&mut tracker, &[10, 8, 8, 7, 6, 10, 11]),
+            [Accept, Accept, Duplicate, Accept, Replay, Duplicate, Accept]
+        );
+        // out of the window once the counter moved on
+        assert_eq!(verdicts(&mut tracker, &[9]), [Accept]);
+        assert_eq!(tracker.check(&msg(1, 7, b"x")), Replay);
+
+        let mut tracker = FcntTracker::new(Policy::Strict);
+        assert_eq!(verdicts(&mut tracker, &[10, 9]), [Accept, Replay]);
+    }
+
+    #[test]
+    fn wide_window() {
+        assert_eq!(Policy::window(100), Policy::Window(MAX_WINDOW));
+        assert_eq!(Policy::window(0), Policy::Window(1));
+        assert!("window:65".parse::<Policy>().is_err());
+
+        // built directly, the window is clamped too
+        let mut tracker = FcntTracker::new(Policy::Window(100));
+        assert_eq!(
+            verdicts(&mut tracker, &[100, 37, 36, 35]),
+            [Accept, Accept, Replay, Replay]
+        );
+    }
+
+    #[test]
+    fn rollover() {
+        // the 16 bit counter of the frame overflows into the 32 bit one
+        let mut tracker = FcntTracker::new(Policy::window(16));
+        assert_eq!(
+            verdicts(&mut tracker, &[0xFFFE, 0x1_0001, 0xFFFF, 0x1_0000, 0xFFFF]),
+            [Accept, Accept, Accept, Accept, Duplicate]
+        );
+        // a device back at the 16 bit value is a replay
+        assert_eq!(verdicts(&mut tracker, &[0x0002]), [Replay]);
+    }
+
+    #[test]
+    fn resets() {
+        let mut tracker = FcntTracker::new(Policy::Reset(2));
+        assert_eq!(verdicts(&mut tracker, &[1, 2, 50]), [Accept; 3]);
+        // a retransmission of the first frame is told apart by its digest
+        assert_eq!(verdicts(&mut tracker, &[1]), [Duplicate]);
+        // a new first frame restarts the counter
+        assert_eq!(tracker.check(&msg(1, 1, b"rebooted")), Reset);
+        assert_eq!(
+            verdicts(&mut tracker, &[2, 3, 2]),
+            [Accept, Accept, Duplicate]
+        );
+        // beyond N, low counters are replays
+        assert_eq!(verdicts(&mut tracker, &[60]), [Accept]);
+        assert_eq!(tracker.check(&msg(1, 3, b"x")), Replay);
+
+        let counters = tracker.counters(1).unwrap();
+        assert_eq!((counters.resets, counters.accepted), (1, 7));
+    }
+
+    #[test]
+    fn idlest_forgotten() {
+        let mut tracker = FcntTracker::new(Policy::Strict);
+        for addr in 0..MAX_DEVICES as u64 {
+            assert_eq!(tracker.check(&msg(addr, 10, b"a")), Accept);
+        }
+        // device 0 is heard again, 1 is the idlest
+        assert_eq!(tracker.check(&msg(0, 11, b"a")), Accept);
+        assert_eq!(tracker.check(&msg(u64::MAX, 10, b"a")), Accept);
+        assert_eq!(tracker.devs.len(), MAX_DEVICES);
+        assert!(tracker.counters(1).is_none());
+        assert_eq!(tracker.check(&msg(0, 9, b"b")), Replay);
+        // forgotten, its counter starts over
+        assert_eq!(tracker.check(&msg(1, 9, b"b")), Accept);
+    }
+}
@@ -0,0 +1,626 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Driver registry on an append-only, hash-chained ledger
+//
+// Owners of end devices publish records binding a device address to a driver
+// module, identified by its SHA-256 hash. Each record is signed (Ed25519) by
+// its owner, together with its position (sequence number and hash of the
+// previous record), and chained to the previous one by hash, as the blocks of
+// a Hyperledger ledger, so records cannot be altered, removed or replayed
+// elsewhere without breaking the chain. The first binding of an address makes
+// its signer the owner of the device: later bindings must be signed by the
+// same key. Who may claim an address is up to the gateway (see Claims): the
+// first binding carries the signature of an operator key over the address and
+// the owner key, or, if the gateway opts into trust on first use, anyone may
+// claim any address not bound yet.
+//
+// The whole chain is verified before any module is run, then only the
+// records appended since: a ledger that no longer extends the verified head
+// is refused. Modules are stored by hash, <module_dir>/<hash>.wasm,
+// and hashed again before they run (see trust), with the default policy
+// (see policy).
+//
+// FileLedger, a JSON record per line, stands in for a distributed ledger.
+//
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::discovery::{Service, ServiceDiscovery};
+use crate::policy::Policy;
+use crate::trust::{self, decode_array, Publishers};
+use crate::{Error, Result};
+
+// Content signed by the owner of the end device
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Binding {
+    #[serde(with = "hex_addr")]
+    pub device: u64,
+    pub module: String,
+    pub version: String,
+    pub module_hash: String, // SHA-256, in hex
+    pub owner: String,
+    pub timestamp: u64, // seconds since the UNIX epoch
+}
+
+impl Binding {
+    // Signed at its position in the chain, one field per line
+    fn signed_bytes(&self, seq: u64, prev: &str) -> Vec<u8> {
+        format!(
+            "{seq}\n{prev}\n{:016x}\n{}\n{}\n{}\n{}\n{}",
+            self.device, self.module, self.version, self.module_hash, self.owner, self.timestamp
+        )
+        .into_bytes()
+    }
+
+    fn check(&self) -> std::result::Result<(), &'static str> {
+        if [&self.module, &self.version, &self.owner]
+            .iter()
+            .any(|field| field.contains('\n'))
+        {
+            return Err("newline in a field");
+        }
+        // also the name of the stored module
+        let hex = |c: char| c.is_ascii_digit() || ('a'..='f').contains(&c);
+        if self.module_hash.len() != 64 || !self.module_hash.chars().all(hex) {
+            return Err("bad module hash");
+        }
+        Ok(())
+    }
+}
+
+// Block of the ledger
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    pub seq: u64,
+    pub prev: String, // hash of the previous record, GENESIS for the first
+    pub binding: Binding,
+    pub owner_key: String, // Ed25519 public key, in hex
+    pub signature: String,
+    // signature of an operator over the device and the owner key, on the
+    // first binding of the device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claim: Option<String>,
+    pub hash: String,
+}
+
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+impl Record {
+    fn compute_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.binding.signed_bytes(self.seq, &self.prev));
+        hasher.update(format!("\n{}\n{}", self.owner_key, self.signature));
+        if let Some(ref claim) = self.claim {
+            hasher.update(format!("\n{claim}"));
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    fn verify_signature(&self) -> std::result::Result<(), &'static str> {
+        let key = decode_array(&self.owner_key).ok_or("bad owner key")?;
+        let key = VerifyingKey::from_bytes(&key).map_err(|_| "bad owner key")?;
+        let signature = decode_array(&self.signature).ok_or("bad signature")?;
+        key.verify(
+            &self.binding.signed_bytes(self.seq, &self.prev),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| "bad signature")
+    }
+}
+
+// Signed by an operator to let a key claim a device
+fn claim_bytes(device: u64, owner_key: &str) -> Vec<u8> {
+    format!("claim\n{device:016x}\n{owner_key}").into_bytes()
+}
+
+// Claim of a device by the owner key, to attach to its first binding
+pub fn claim(operator: &SigningKey, device: u64, owner_key: &VerifyingKey) -> String {
+    trust::sign(
+        operator,
+        &claim_bytes(device, &hex::encode(owner_key.as_bytes())),
+    )
+}
+
+// Who may publish the first binding of a device, and so own it
+#[derive(Clone, Debug)]
+pub enum Claims {
+    // the keys the operators claim devices for, in the publishers format
+    // (see trust)
+    Operators(Publishers),
+    // any key, for the devices not bound yet: whoever appends first owns
+    // them, only for ledgers open to trusted owners
+    FirstUse,
+}
+
+// Verified state of a ledger: its head and the current bindings
+pub struct Chain {
+    claims: Claims,
+    len: u64,
+    head: Option<String>,
+    owners: HashMap<u64, String>,
+    bindings: HashMap<u64, (Binding, String)>, // with the owner key
+}
+
+impl Chain {
+    pub fn new(claims: Claims) -> Self {
+        Self {
+            claims,
+            len: 0,
+            head: None,
+            owners: HashMap::new(),
+            bindings: HashMap::new(),
+        }
+    }
+
+    pub fn verify(records: &[Record], claims: Claims) -> Result<Self> {
+        let mut chain = Self::new(claims);
+        for record in records {
+            chain.push(record)?;
+        }
+        Ok(chain)
+    }
+
+    // Verify and apply the next record
+    pub fn push(&mut self, record: &Record) -> Result<()> {
+        let bad = |what: &str| Error::BadLedger(format!("record {}: {what}", record.seq));
+        if record.seq != self.len {
+            return Err(bad("out of sequence"));
+        }
+        if record.prev != self.head.as_deref().unwrap_or(GENESIS) {
+            return Err(bad("broken chain"));
+        }
+        if record.hash != record.compute_hash() {
+            return Err(bad("bad hash"));
+        }
+        record.binding.check().map_err(bad)?;
+        record.verify_signature().map_err(bad)?;
+        let device = record.binding.device;
+        match self.owners.get(&device) {
+            Some(owner) if *owner != record.owner_key => {
+                return Err(bad(&format!("{device:08x} is owned by another key")));
+            }
+            Some(_) if record.claim.is_some() => {
+                return Err(bad(&format!("{device:08x} is already claimed")));
+            }
+            Some(_) => (),
+            None => {
+                if let Claims::Operators(ref operators) = self.claims {
+                    operators
+                        .verify(
+                            &claim_bytes(device, &record.owner_key),
+                            record.claim.as_deref(),
+                        )
+                        .map_err(|_| bad(&format!("{device:08x} not claimed by an operator")))?;
+                }
+                self.owners.insert(device, record.owner_key.clone());
+            }
+        }
+        self.bindings
+            .insert(device, (record.binding.clone(), record.owner_key.clone()));
+        self.head = Some(record.hash.clone());
+        self.len += 1;
+        Ok(())
+    }
+
+    // Next record, binding a device to a module on behalf of key, with the
+    // claim of the device on its first binding
+    pub fn seal(
+        &self,
+        binding: Binding,
+        key: &SigningKey,
+        claim: Option<String>,
+    ) -> Result<Record> {
+        binding
+            .check()
+            .map_err(|e| Error::BadLedger(format!("record {}: {e}", self.len)))?;
+        let seq = self.len;
+        let prev = self.head.clone().unwrap_or(GENESIS.to_string());
+        let signature = key.sign(&binding.signed_bytes(seq, &prev));
+        let mut record = Record {
+            seq,
+            prev,
+            binding,
+            owner_key: hex::encode(key.verifying_key().as_bytes()),
+            signature: hex::encode(signature.to_bytes()),
+            claim,
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        Ok(record)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Current binding of a device, and the key of its owner
+    pub fn binding(&self, device: u64) -> Option<&(Binding, String)> {
+        self.bindings.get(&device)
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = &(Binding, String)> {
+        self.bindings.values()
+    }
+}
+
+pub trait Ledger: Send + Sync {
+    fn records(&self) -> Result<Vec<Record>>;
+    fn append(&self, record: &Record) -> Result<()>;
+}
+
+pub struct FileLedger {
+    path: PathBuf,
+}
+
+impl FileLedger {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Ledger for FileLedger {
+    fn records(&self) -> Result<Vec<Record>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let mut records = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(Error::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(&line)
+                .map_err(|e| Error::BadLedger(format!("line {}: {e}", i + 1)))?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    fn append(&self, record: &Record) -> Result<()> {
+        let mut line =
+            serde_json::to_string(record).map_err(|e| Error::BadLedger(e.to_string()))?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(Error::Io)?;
+        file.write_all(line.as_bytes()).map_err(Error::Io)
+    }
+}
+
+pub struct LedgerRegistry {
+    ledger: Box<dyn Ledger>,
+    module_dir: PathBuf,
+    chain: Mutex<Chain>,
+}
+
+impl LedgerRegistry {
+    pub fn new(ledger: Box<dyn Ledger>, module_dir: PathBuf, claims: Claims) -> Result<Self> {
+        let chain = Chain::verify(&ledger.records()?, claims)?;
+        println!("[ledger] {} records verified.", chain.len());
+        Ok(Self {
+            ledger,
+            module_dir,
+            chain: Mutex::new(chain),
+        })
+    }
+
+    // A file ledger, with the modules in the "modules" directory next to it
+    pub fn open(path: &Path, claims: Claims) -> Result<Self> {
+        let module_dir = path.parent().unwrap_or(Path::new(".")).join("modules");
+        Self::new(
+            Box::new(FileLedger::new(path.to_path_buf())),
+            module_dir,
+            claims,
+        )
+    }
+
+    // Verify the records appended since the last call. A ledger rewritten
+    // meanwhile is refused, the verified chain is kept.
+    fn refresh(&self) -> Result<()> {
+        let records = self.ledger.records()?;
+        let mut chain = self.chain.lock().unwrap();
+        let len = chain.len() as usize;
+        let extends_head = match len {
+            0 => true,
+            len => records.get(len - 1).map(|r| &r.hash) == chain.head.as_ref(),
+        };
+        if !extends_head {
+            println!("[ledger] ledger rewritten, refused.");
+            return Err(Error::BadLedger(format!(
+                "ledger rewritten before record {len}"
+            )));
+        }
+        for record in &records[len..] {
+            chain.push(record)?;
+        }
+        Ok(())
+    }
+}
+
+impl ServiceDiscovery for LedgerRegistry {
+    fn discover(&self, deveui: u64) -> Result<Service> {
+        self.refresh()?;
+        let chain = self.chain.lock().unwrap();
+        let (binding, _) = chain.binding(deveui).ok_or(Error::NoDriver(deveui))?;
+        let module = self
+            .module_dir
+            .join(format!("{}.wasm", binding.module_hash));
+        Ok(Service {
+            module,
+            version: binding.version.clone(),
+            owner: binding.owner.clone(),
+            sha256: Some(binding.module_hash.clone()),
+            policy: Policy::default(),
+        })
+    }
+}
+
+// Device addresses in hex, as everywhere else
+mod hex_addr {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(addr: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{addr:08x}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        u64::from_str_radix(&s, 16).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn binding(device: u64, version: &str, timestamp: u64) -> Binding {
+        Binding {
+            device,
+            module: "thermo.wasm".to_string(),
+            version: version.to_string(),
+            module_hash: "ab".repeat(32),
+            owner: "alice".to_string(),
+            timestamp,
+        }
+    }
+
+    // Three records: devices 1 and 2 bound, then device 1 bound again
+    fn records() -> Vec<Record> {
+        let mut chain = Chain::new(Claims::FirstUse);
+        let mut records = Vec::new();
+        for b in [
+            binding(1, "1.0.0", 10),
+            binding(2, "1.0.0", 11),
+            binding(1, "1.1.0", 12),
+        ] {
+            let record = chain.seal(b, &key(1), None).unwrap();
+            chain.push(&record).unwrap();
+            records.push(record);
+        }
+        records
+    }
+
+    fn rejected(records: &[Record], why: &str) {
+        match Chain::verify(records, Claims::FirstUse) {
+            Err(Error::BadLedger(e)) => assert!(e.contains(why), "{e}"),
+            Err(e) => panic!("{e}"),
+            Ok(_) => panic!("accepted, expected {why}"),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let chain = Chain::verify(&records(), Claims::FirstUse).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain.binding(1).unwrap().0.version, "1.1.0");
+        assert_eq!(chain.binding(2).unwrap().0.version, "1.0.0");
+        assert!(chain.binding(3).is_none());
+    }
+
+    #[test]
+    fn tampered() {
+        let mut records = records();
+        records[1].binding.module_hash = "cd".repeat(32);
+        rejected(&records, "bad hash");
+
+        // hashed again, the owner signature no longer matches
+        records[1].hash = records[1].compute_hash();
+        rejected(&records, "bad signature");
+
+        let mut records = self::records();
+        records[0].owner_key = hex::encode(key(2).verifying_key().as_bytes());
+        records[0].hash = records[0].compute_hash();
+        rejected(&records, "bad signature");
+    }
+
+    #[test]
+    fn reordered() {
+        let mut records = records();
+        records.swap(1, 2);
+        rejected(&records, "out of sequence");
+
+        let mut records = self::records();
+        records.remove(1);
+        rejected(&records, "out of sequence");
+
+        // renumbered, the chain is still broken
+        let mut records = self::records();
+        records.remove(1);
+        records[1].seq = 1;
+        rejected(&records, "broken chain");
+    }
+
+    #[test]
+    fn foreign_key() {
+        let records = records();
+        let mut chain = Chain::verify(&records, Claims::FirstUse).unwrap();
+        let stolen = chain.seal(binding(1, "6.6.6", 13), &key(2), None).unwrap();
+        match chain.push(&stolen) {
+            Err(Error::BadLedger(e)) => assert!(e.contains("owned by another key"), "{e}"),
+            other => panic!("{other:?}"),
+        }
+        // the first binding of a device makes the signer its owner
+        let other = chain.seal(binding(3, "1.0.0", 13), &key(2), None).unwrap();
+        chain.push(&other).unwrap();
+        assert_eq!(chain.binding(3).unwrap().1, other.owner_key);
+    }
+
+    #[test]
+    fn replayed() {
+        // an old binding appended again, to roll device 1 back to 1.0.0
+        let mut records = records();
+        let mut replay = records[0].clone();
+        replay.seq = 3;
+        replay.prev = records[2].hash.clone();
+        replay.hash = replay.compute_hash();
+        records.push(replay);
+        rejected(&records, "bad signature");
+    }
+
+    #[test]
+    fn bad_bindings() {
+        let chain = Chain::new(Claims::FirstUse);
+        let mut b = binding(1, "1.0.0\n", 10);
+        assert!(chain.seal(b.clone(), &key(1), None).is_err());
+        b.version = "1.0.0".to_string();
+        for hash in ["ab".repeat(31), "AB".repeat(32), "../".repeat(21) + "x"] {
+            b.module_hash = hash;
+            assert!(chain.seal(b.clone(), &key(1), None).is_err());
+        }
+
+        let mut records = records();
+        records[0].binding.owner = "alice\nbob".to_string();
+        records[0].hash = records[0].compute_hash();
+        rejected(&records, "newline in a field");
+    }
+
+    #[derive(Clone)]
+    struct MemLedger(Arc<Mutex<Vec<Record>>>);
+
+    impl Ledger for MemLedger {
+        fn records(&self) -> Result<Vec<Record>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn append(&self, record: &Record) -> Result<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rewritten() {
+        let records = records();
+        let ledger = MemLedger(Arc::new(Mutex::new(records[..2].to_vec())));
+        let registry =
+            LedgerRegistry::new(Box::new(ledger.clone()), PathBuf::new(), Claims::FirstUse)
+                .unwrap();
+        ledger.append(&records[2]).unwrap();
+        registry.refresh().unwrap();
+        assert_eq!(registry.chain.lock().unwrap().len(), 3);
+
+        // a valid chain, but not the one verified
+        let mut chain = Chain::new(Claims::FirstUse);
+        let other: Vec<_> = [binding(1, "0.9.0", 10), binding(2, "0.9.0", 11)]
+            .into_iter()
+            .map(|b| {
+                let record = chain.seal(b, &key(1), None).unwrap();
+                chain.push(&record).unwrap();
+                record
+            })
+            .collect();
+        *ledger.0.lock().unwrap() = other;
+        assert!(matches!(registry.refresh(), Err(Error::BadLedger(_))));
+        let chain = registry.chain.lock().unwrap();
+        assert_eq!(chain.binding(1).unwrap().0.version, "1.1.0");
+    }
+
+    #[test]
+    fn claims() {
+        let operator = key(9);
+        let operators = Publishers::parse(&format!(
+            "operator {}",
+            hex::encode(operator.verifying_key().as_bytes())
+        ))
+        .unwrap();
+        let claims = Claims::Operators(operators);
+        let (alice, mallory) = (key(1), key(2));
+        let mut chain = Chain::new(claims.clone());
+
+        // unclaimed, or claimed for another key or device
+        let unclaimed = chain.seal(binding(1, "1.0.0", 10), &mallory, None).unwrap();
+        let alices = claim(&operator, 1, &alice.verifying_key());
+        let stolen = chain
+            .seal(binding(1, "1.0.0", 10), &mallory, Some(alices.clone()))
+            .unwrap();
+        let other = claim(&operator, 2, &mallory.verifying_key());
+        let elsewhere = chain
+            .seal(binding(1, "1.0.0", 10), &mallory, Some(other))
+            .unwrap();
+        let self_claimed = claim(&mallory, 1, &mallory.verifying_key());
+        let self_claimed = chain
+            .seal(binding(1, "1.0.0", 10), &mallory, Some(self_claimed))
+            .unwrap();
+        for record in [unclaimed, stolen, elsewhere, self_claimed] {
+            match chain.push(&record) {
+                Err(Error::BadLedger(e)) => {
+                    assert!(e.contains("not claimed by an operator"), "{e}")
+                }
+                other => panic!("{other:?}"),
+            }
+        }
+
+        let first = chain
+            .seal(binding(1, "1.0.0", 10), &alice, Some(alices.clone()))
+            .unwrap();
+        chain.push(&first).unwrap();
+        // later bindings need no claim, and cannot claim it again
+        let second = chain.seal(binding(1, "1.1.0", 11), &alice, None).unwrap();
+        chain.push(&second).unwrap();
+        let again = chain
+            .seal(binding(1, "1.2.0", 12), &alice, Some(alices))
+            .unwrap();
+        assert!(matches!(chain.push(&again), Err(Error::BadLedger(_))));
+        let records = [first, second];
+        assert_eq!(Chain::verify(&records, claims).unwrap().len(), 2);
+
+        // the claim is chained with its record
+        let mut records = records;
+        records[0].claim = None;
+        rejected(&records, "bad hash");
+    }
+}
@@ -14,11 +14,27 @@
 //
 
 pub mod broker;
+pub mod dedup;
 pub mod demux;
+pub mod discovery;
+pub mod downlink;
+pub mod fcnt;
+pub mod ledger;
+pub mod limits;
+pub mod lorawan;
+pub mod modcache;
+pub mod policy;
+pub mod queue;
+pub mod reasm;
+pub mod supervisor;
+pub mod topic;
+pub mod trust;
 pub mod vdctrl;
 
 use lora::{self, opcodes::*, *};
+use msg::uplink::RxMeta;
 use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{thread, time};
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -27,6 +43,22 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     Lora(lora::Error),
     Msg(msg::Error),
+    Io(std::io::Error),
+    UnknownDevAddr(u32),
+    RepeatedFcnt(u32, u32),
+    BadFcntPolicy(String),
+    BadTopicFilter(String),
+    BadQueueConfig(String),
+    BadCommand(String),
+    MissedRxWindow(u64),
+    StaleFcntDown(u32, u32),
+    UnsupportedDownlink(u64),
+    TxTimeout,
+    NoDriver(u64),
+    BadManifest(String),
+    Http(String),
+    BadLedger(String),
+    ModuleRefused(String),
 }
 
 impl fmt::Display for Error {
@@ -34,19 +66,56 @@ impl fmt::Display for Error {
         match *self {
             Error::Lora(ref err) => write!(f, "Lora error: {err}"),
             Error::Msg(ref err) => write!(f, "Msg error: {err}"),
+            Error::Io(ref err) => write!(f, "I/O error: {err}"),
+            Error::UnknownDevAddr(addr) => write!(f, "Unknown DevAddr: {addr:08x}"),
+            Error::RepeatedFcnt(addr, fcnt) => {
+                write!(
+                    f,
+                    "Repeated fcnt {fcnt} of unconfirmed uplink from {addr:08x}"
+                )
+            }
+            Error::BadFcntPolicy(ref policy) => write!(f, "Bad fcnt policy: {policy}"),
+            Error::BadTopicFilter(ref filter) => write!(f, "Bad topic filter: {filter}"),
+            Error::BadQueueConfig(ref config) => write!(f, "Bad queue config: {config}"),
+            Error::BadCommand(ref cmd) => write!(f, "Bad command: {cmd}"),
+            Error::MissedRxWindow(addr) => write!(f, "Missed the receive windows of {addr:08x}"),
+            Error::StaleFcntDown(addr, fcnt) => {
+                write!(
+                    f,
+                    "Downlink fcnt {fcnt} of {addr:08x} not ahead of the last sent"
+                )
+            }
+            Error::UnsupportedDownlink(addr) => {
+                write!(
+                    f,
+                    "Downlink not supported by the LoRaWAN end device {addr:08x}"
+                )
+            }
+            Error::TxTimeout => write!(f, "Transmission timed out"),
+            Error::NoDriver(addr) => write!(f, "No driver for {addr:08x}"),
+            Error::BadManifest(ref err) => write!(f, "Bad driver manifest: {err}"),
+            Error::Http(ref err) => write!(f, "HTTP error: {err}"),
+            Error::BadLedger(ref err) => write!(f, "Bad ledger: {err}"),
+            Error::ModuleRefused(ref why) => write!(f, "Module refused: {why}"),
         }
     }
 }
 
-pub fn init_lora() -> Result<Lora> {
-    // Set spreading factor (SF7 - SF12)
-    const SF: SpreadingFactor = SpreadingFactor::SF7;
+// Default sync word for non-LoRaWAN, private networks
+pub const SYNC_WORD_PRIVATE: u8 = 0x12;
 
-    // Set center frequency
-    const FREQ: u32 = 868100000; // in Mhz! (868.1)
+// Sync word of public LoRaWAN networks
+pub const SYNC_WORD_LORAWAN: u8 = 0x34;
 
+// Set spreading factor (SF7 - SF12)
+pub const SF: SpreadingFactor = SpreadingFactor::SF7;
+
+// Set center frequency
+pub const FREQ: u32 = 868100000; // in Mhz! (868.1)
+
+pub fn init_lora(sync_word: u8) -> Result<Lora> {
     let mut lora = Lora::new(Configs {
-        sync_word: 0x12, // default sync word for non-LoRaWAN, private networks
+        sync_word,
         frf: Frf { freq: FREQ },
         modem_config1: ModemConfig1 {
             bw: Bandwidth::KHz125,
@@ -64,10 +133,7 @@ pub fn init_lora() -> Result<Lora> {
             _ => 8,
         },
         modem_config3: ModemConfig3 {
-            low_data_rate_optimize: match SF {
-                SpreadingFactor::SF11 | SpreadingFactor::SF12 => true,
-                _ => false,
-            },
+            low_data_rate_optimize: matches!(SF, SpreadingFactor::SF11 | SpreadingFactor::SF12),
             agc_auto_on: true,
         },
         lna: Lna {
@@ -91,18 +157,73 @@ pub fn init_lora() -> Result<Lora> {
 }
 
 // Blocking reception method
-pub fn recv(lora: &mut Lora) -> Result<Vec<u8>> {
+pub fn recv(lora: &mut Lora) -> Result<Reception> {
     loop {
-        if let Some(r) = lora.try_receive().map_err(Error::Lora)? {
-            println!(
-                "receive: {:#?} ({} bytes), RSS: {} dBm, SNR: {}",
-                &r.data,
-                r.data.len(),
-                r.rss,
-                r.snr
-            );
-            return Ok(r.data);
+        if let Some(r) = try_recv(lora)? {
+            return Ok(r);
         }
         thread::sleep(time::Duration::from_millis(1))
     }
 }
+
+// Non-blocking reception method
+pub fn try_recv(lora: &mut Lora) -> Result<Option<Reception>> {
+    let r = lora.try_receive().map_err(Error::Lora)?;
+    if let Some(ref r) = r {
+        println!(
+            "receive: {:#?} ({} bytes), RSS: {} dBm, SNR: {}",
+            &r.data,
+            r.data.len(),
+            r.rss,
+            r.snr
+        );
+    }
+    Ok(r)
+}
+
+// Longest time on air of a frame (SF12, 128 bytes)
+pub const TX_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// Blocking transmission, the radio is back in continuous reception after
+pub fn transmit(lora: &mut Lora, frame: &[u8]) -> Result<()> {
+    lora.transmit(frame).map_err(Error::Lora)?;
+    let done = lora.wait_tx_done(TX_TIMEOUT).map_err(Error::Lora)?;
+    lora.receive_continuous().map_err(Error::Lora)?;
+    if done {
+        Ok(())
+    } else {
+        Err(Error::TxTimeout)
+    }
+}
+
+// Class A downlink in a receive window of the end device, with the IQ
+// polarity of LoRaWAN downlinks if invert_iq, then back to reception on the
+// uplink channel
+pub fn transmit_in(
+    lora: &mut Lora,
+    frame: &[u8],
+    window: downlink::RxWindow,
+    invert_iq: bool,
+) -> Result<()> {
+    let (freq, sf) = window.channel();
+    lora.set_channel(freq, sf, invert_iq).map_err(Error::Lora)?;
+    let sent = transmit(lora, frame);
+    lora.set_channel(FREQ, SF, false)
+        .and_then(|()| lora.receive_continuous())
+        .map_err(Error::Lora)?;
+    sent
+}
+
+// Metadata of a packet heard by this gateway now
+pub fn rx_meta(gateway: u64, rx: &Reception) -> RxMeta {
+    RxMeta {
+        gateway,
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_millis() as u64),
+        rssi: rx.rss,
+        snr: rx.snr,
+        freq: FREQ,
+        sf: SF as u8,
+    }
+}
@@ -0,0 +1,609 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Resource limits of the virtual devices
+//
+// A driver stuck in a loop or leaking memory must not take the gateway, and
+// the other virtual devices, with it:
+// - CPU: modules are compiled with a metering middleware, which charges each
+//   operator to a fuel counter (a global of the module) at the end of each
+//   basic block, and traps when it runs out. The fuel is set again each time
+//   a read of stdin returns data, so it bounds the work done per message.
+//   Each engine compiles a single module, as the middleware keeps the
+//   globals of the module it compiles;
+// - memory: linear memories cannot grow beyond max_memory, a driver failing
+//   to allocate usually aborts. The middleware also keeps the result of the
+//   last memory.grow, to tell why;
+// - time: the processing of a message, from the read returning it to the
+//   next read of stdin returning data, may not exceed max_processing_time,
+//   not counting the time asleep in poll_oneoff. It is checked at each host
+//   call of the driver (CPU-bound loops are bounded by the fuel).
+// Violations terminate the instance, and are reported to its supervisor as
+// the error of its crash.
+//
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use wasmer::vm::{MemoryError, MemoryStyle, TableStyle, VMMemory, VMMemoryDefinition};
+use wasmer::vm::{VMTable, VMTableDefinition};
+use wasmer::wasmparser::{BlockType, Operator};
+use wasmer::{
+    AsStoreRef, BaseTunables, CompilerConfig, Cranelift, Engine, EngineBuilder, ExportIndex,
+    Extern, Function, FunctionEnv, FunctionEnvMut, FunctionMiddleware, Global, GlobalInit,
+    GlobalType, Imports, Instance, LocalFunctionIndex, Memory, MemoryType, MiddlewareError,
+    MiddlewareReaderState, Module, ModuleMiddleware, Mutability, NativeEngineExt, Pages,
+    RuntimeError, Store, TableType, Target, Tunables, Type, Value, WASM_PAGE_SIZE,
+};
+use wasmer_types::entity::EntityRef;
+use wasmer_types::{GlobalIndex, ModuleInfo};
+use wasmer_wasix::wasmer_wasix_types::wasi::{Errno, ExitCode};
+use wasmer_wasix::{WasiError, WasiFunctionEnv};
+
+use crate::policy::Policy;
+
+// Exports of the globals added to the modules
+const FUEL: &str = "__smart_gw_fuel";
+const GROWN: &str = "__smart_gw_grown"; // last memory.grow result
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    // operators a virtual device may run per message
+    pub fuel: u64,
+    // largest linear memory of a virtual device
+    pub max_memory: usize, // in bytes
+    // longest a virtual device may take to process a message
+    pub max_processing_time: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: 100_000_000,
+            max_memory: 64 << 20,
+            max_processing_time: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Limits {
+    fn max_pages(&self) -> Pages {
+        Pages((self.max_memory / WASM_PAGE_SIZE) as u32)
+    }
+}
+
+// Engine compiling a metered module, with limited memories. The stores
+// running the module must use the engine that compiled it.
+pub fn engine(limits: &Limits) -> Engine {
+    let mut compiler = Cranelift::default();
+    compiler.push_middleware(Arc::new(Metering::default()));
+    let mut engine: Engine = EngineBuilder::new(compiler).into();
+    engine.set_tunables(LimitingTunables {
+        base: BaseTunables::for_target(&Target::default()),
+        max_pages: limits.max_pages(),
+    });
+    engine
+}
+
+// Adds the fuel counter to a module, and charges its functions. The indexes
+// of the globals are those of the single module compiled by the engine: the
+// functions of a second module would be charged to the globals of the first,
+// they are refused instead.
+#[derive(Debug, Default)]
+struct Metering {
+    globals: OnceLock<(GlobalIndex, GlobalIndex)>, // fuel, grown
+    reused: AtomicBool,
+}
+
+impl ModuleMiddleware for Metering {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        let globals = self.globals.get().expect("module info not transformed");
+        if self.reused.load(Ordering::Relaxed) {
+            return Box::new(Reused);
+        }
+        let (fuel, grown) = globals;
+        Box::new(FunctionMetering {
+            fuel: fuel.index() as u32,
+            grown: grown.index() as u32,
+            cost: 0,
+        })
+    }
+
+    fn transform_module_info(&self, info: &mut ModuleInfo) {
+        let fuel = info
+            .globals
+            .push(GlobalType::new(Type::I64, Mutability::Var));
+        info.global_initializers.push(GlobalInit::I64Const(0));
+        info.exports
+            .insert(FUEL.to_string(), ExportIndex::Global(fuel));
+        let grown = info
+            .globals
+            .push(GlobalType::new(Type::I32, Mutability::Var));
+        info.global_initializers.push(GlobalInit::I32Const(0));
+        info.exports
+            .insert(GROWN.to_string(), ExportIndex::Global(grown));
+        if self.globals.set((fuel, grown)).is_err() {
+            self.reused.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// Fails the compilation of a second module by the same engine
+#[derive(Debug)]
+struct Reused;
+
+impl FunctionMiddleware for Reused {
+    fn feed<'a>(
+        &mut self,
+        _: Operator<'a>,
+        _: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        Err(MiddlewareError::new(
+            "metering",
+            "an engine compiles a single module",
+        ))
+    }
+}
+
+#[derive(Debug)]
+struct FunctionMetering {
+    fuel: u32,
+    grown: u32,
+    cost: u64, // of the current basic block
+}
+
+impl FunctionMiddleware for FunctionMetering {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        self.cost += 1;
+        let ends_block = matches!(
+            operator,
+            Operator::Loop { .. }
+                | Operator::End
+                | Operator::Else
+                | Operator::Br { .. }
+                | Operator::BrIf { .. }
+                | Operator::BrTable { .. }
+                | Operator::Call { .. }
+                | Operator::CallIndirect { .. }
+                | Operator::Return
+        );
+        if ends_block {
+            // if fuel < cost { fuel = 0; unreachable } fuel -= cost
+            let (fuel, cost) = (self.fuel, self.cost as i64);
+            state.extend(&[
+                Operator::GlobalGet { global_index: fuel },
+                Operator::I64Const { value: cost },
+                Operator::I64LtU,
+                Operator::If {
+                    blockty: BlockType::Empty,
+                },
+                Operator::I64Const { value: 0 },
+                Operator::GlobalSet { global_index: fuel },
+                Operator::Unreachable,
+                Operator::End,
+                Operator::GlobalGet { global_index: fuel },
+                Operator::I64Const { value: cost },
+                Operator::I64Sub,
+                Operator::GlobalSet { global_index: fuel },
+            ]);
+            self.cost = 0;
+        }
+        let grows = matches!(operator, Operator::MemoryGrow { .. });
+        state.push_operator(operator);
+        if grows {
+            let grown = self.grown;
+            state.extend(&[
+                Operator::GlobalSet {
+                    global_index: grown,
+                },
+                Operator::GlobalGet {
+                    global_index: grown,
+                },
+            ]);
+        }
+        Ok(())
+    }
+}
+
+// Caps the maximum of the memories, so that wasm code cannot grow them beyond
+#[derive(Clone)]
+struct LimitingTunables {
+    base: BaseTunables,
+    max_pages: Pages,
+}
+
+impl LimitingTunables {
+    fn limit(&self, ty: &MemoryType) -> Result<MemoryType, MemoryError> {
+        if ty.minimum > self.max_pages {
+            return Err(MemoryError::MinimumMemoryTooLarge {
+                min_requested: ty.minimum,
+                max_allowed: self.max_pages,
+            });
+        }
+        let mut ty = *ty;
+        ty.maximum = Some(
+            ty.maximum
+                .map_or(self.max_pages, |max| max.min(self.max_pages)),
+        );
+        Ok(ty)
+    }
+}
+
+impl Tunables for LimitingTunables {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        let ty = self.limit(memory).unwrap_or(*memory);
+        self.base.memory_style(&ty)
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+    ) -> Result<VMMemory, MemoryError> {
+        self.base.create_host_memory(&self.limit(ty)?, style)
+    }
+
+    // Safety: as BaseTunables::create_vm_memory, the type only changes its
+    // maximum
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: std::ptr::NonNull<VMMemoryDefinition>,
+    ) -> Result<VMMemory, MemoryError> {
+        self.base
+            .create_vm_memory(&self.limit(ty)?, style, vm_definition_location)
+    }
+
+    fn create_host_table(&self, ty: &TableType, style: &TableStyle) -> Result<VMTable, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    // Safety: as BaseTunables::create_vm_table
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: std::ptr::NonNull<VMTableDefinition>,
+    ) -> Result<VMTable, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
+
+// Host side of the limits of a running virtual device
+struct Guard {
+    limits: Limits,
+    fuel: Option<Global>,
+    // where fd_read writes the number of bytes read
+    memory: Option<Memory>,
+    // when the message being processed was read, None while reading stdin
+    busy_since: Option<Instant>,
+    violation: Option<&'static str>,
+}
+
+impl Guard {
+    fn check_time(&mut self) -> Result<(), RuntimeError> {
+        match self.busy_since {
+            Some(since) if since.elapsed() > self.limits.max_processing_time => {
+                Err(self.violated("processing time limit exceeded"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn violated(&mut self, violation: &'static str) -> RuntimeError {
+        self.violation = Some(violation);
+        RuntimeError::new(violation)
+    }
+}
+
+// Run a metered module, with the WASI imports of wasi allowed by policy,
+// until it exits
+pub fn run(
+    store: &mut Store,
+    module: &Module,
+    mut wasi: WasiFunctionEnv,
+    limits: &Limits,
+    policy: &Policy,
+) -> Result<(), String> {
+    let guard = FunctionEnv::new(
+        store,
+        Guard {
+            limits: *limits,
+            fuel: None,
+            memory: None,
+            busy_since: Some(Instant::now()),
+            violation: None,
+        },
+    );
+    let wasi_imports = wasi
+        .import_object_for_all_wasi_versions(store, module)
+        .map_err(|e| e.to_string())?;
+    let mut imports = Imports::new();
+    for ((namespace, name), import) in wasi_imports.into_iter() {
+        let import = match import {
+            Extern::Function(function) if !policy.allows(&name) => {
+                Extern::Function(denied(store, &function))
+            }
+            Extern::Function(function) => Extern::Function(guarded(store, &guard, &name, function)),
+            import => import,
+        };
+        imports.define(&namespace, &name, import);
+    }
+    // modules importing their memory, as wasix does
+    let mut memory = None;
+    if let Some(import) = module.imports().memories().next() {
+        let imported = Memory::new(store, *import.ty()).map_err(|e| e.to_string())?;
+        imports.define(import.module(), import.name(), imported.clone());
+        memory = Some(imported);
+    }
+
+    let instance = match Instance::new(store, module, &imports) {
+        Ok(instance) => instance,
+        Err(e) => {
+            wasi.cleanup(store, Some(Errno::Noexec.into()));
+            return Err(e.to_string());
+        }
+    };
+    let fuel = instance
+        .exports
+        .get_global(FUEL)
+        .map_err(|e| e.to_string())?
+        .clone();
+    fuel.set(store, Value::I64(limits.fuel as i64))
+        .map_err(|e| e.to_string())?;
+    guard.as_mut(store).fuel = Some(fuel.clone());
+    let grown = instance
+        .exports
+        .get_global(GROWN)
+        .map_err(|e| e.to_string())?
+        .clone();
+    guard.as_mut(store).memory = match memory {
+        Some(ref memory) => Some(memory.clone()),
+        None => instance.exports.get_memory("memory").ok().cloned(),
+    };
+    if let Err(e) = wasi.initialize_with_memory(store, instance.clone(), memory, true) {
+        wasi.cleanup(store, Some(Errno::Noexec.into()));
+        return Err(e.to_string());
+    }
+    wasi.data(store).thread.set_status_running();
+
+    let mut result = Ok(());
+    for export in ["_initialize", "_start"] {
+        if let Ok(function) = instance.exports.get_function(export) {
+            result = function.call(store, &[]).map(|_| ());
+            if result.is_err() {
+                break;
+            }
+        }
+    }
+    let (result, exit_code) = match result {
+        Ok(()) => (Ok(()), ExitCode::from(Errno::Success)),
+        Err(e) => match e.downcast_ref::<WasiError>() {
+            Some(WasiError::Exit(code)) if code.is_success() => (Ok(()), *code),
+            Some(WasiError::Exit(code)) => (Err(format!("exit code {}", code.raw())), *code),
+            _ => {
+                let violation = guard.as_ref(store).violation.or_else(|| {
+                    if fuel.get(store) == Value::I64(0) {
+                        Some("CPU limit exceeded")
+                    } else if grown.get(store) == Value::I32(-1) {
+                        Some("memory limit exceeded")
+                    } else {
+                        None
+                    }
+                });
+                let error = match violation {
+                    Some(violation) => violation.to_string(),
+                    None => e.to_string(),
+                };
+                (Err(error), Errno::Noexec.into())
+            }
+        },
+    };
+    wasi.cleanup(store, Some(exit_code));
+    result
+}
+
+// Host function calling function, checking the processing time before (and
+// after). Reads of stdin returning data end the processing of a message, and
+// refuel the virtual device for the next one: reads returning nothing (an
+// empty buffer, EOF or an error) do not, as spinning on them would otherwise
+// run with unlimited fuel and time. The time spent in poll_oneoff (e.g. a
+// sleep of the driver) is not processing time.
+fn guarded(
+    store: &mut Store,
+    guard: &FunctionEnv<Guard>,
+    name: &str,
+    function: Function,
+) -> Function {
+    let ty = function.ty(store);
+    let fd_read = name == "fd_read";
+    let poll = name == "poll_oneoff";
+    Function::new_with_env(
+        store,
+        guard,
+        ty,
+        move |mut env: FunctionEnvMut<Guard>, args: &[Value]| {
+            env.data_mut().check_time()?;
+            let reads_stdin = fd_read && matches!(args.first(), Some(Value::I32(0)));
+            // not processing while waiting for stdin
+            let busy_since = match reads_stdin {
+                true => env.data_mut().busy_since.take(),
+                false => None,
+            };
+            let called = Instant::now();
+            let results = function.call(&mut env, args)?;
+            if poll {
+                let guard = env.data_mut();
+                guard.busy_since = guard.busy_since.map(|since| since + called.elapsed());
+            }
+            if reads_stdin {
+                let (guard, mut store) = env.data_and_store_mut();
+                if read_data(guard, &store, args, &results) {
+                    guard.busy_since = Some(Instant::now());
+                    if let Some(ref fuel) = guard.fuel {
+                        fuel.set(&mut store, Value::I64(guard.limits.fuel as i64))?;
+                    }
+                    return Ok(results.into_vec());
+                }
+                guard.busy_since = busy_since;
+            }
+            env.data_mut().check_time()?;
+            Ok(results.into_vec())
+        },
+    )
+}
+
+// Whether fd_read(fd, iovs, iovs_len, nread) succeeded with data, read from
+// the nread it wrote to the memory of the guest
+fn read_data(guard: &Guard, store: &impl AsStoreRef, args: &[Value], results: &[Value]) -> bool {
+    let (Some(Value::I32(iovs_len)), Some(Value::I32(nread))) = (args.get(2), args.get(3)) else {
+        return false;
+    };
+    if *iovs_len <= 0 || results.first() != Some(&Value::I32(Errno::Success as i32)) {
+        return false;
+    }
+    let Some(ref memory) = guard.memory else {
+        return false;
+    };
+    let mut bytes = [0; 4];
+    memory
+        .view(store)
+        .read(*nread as u32 as u64, &mut bytes)
+        .is_ok()
+        && u32::from_le_bytes(bytes) > 0
+}
+
+// Host function failing with ENOTCAPABLE, instead of function
+fn denied(store: &mut Store, function: &Function) -> Function {
+    let ty = function.ty(store);
+    let errno = ty.results() == [Type::I32];
+    Function::new(store, ty, move |_| {
+        if errno {
+            Ok(vec![Value::I32(Errno::Notcapable as i32)])
+        } else {
+            Err(RuntimeError::new("capability denied by the driver policy"))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use wasmer_wasix::{Pipe, WasiEnv};
+
+    // Reads stdin into the 16 bytes at 32 (or nothing without the iovec)
+    // until a read returns no data
+    fn reader(iovs_len: u32) -> String {
+        format!(
+            r#"(module
+              (import "wasi_snapshot_preview1" "fd_read"
+                (func $fd_read (param i32 i32 i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (data (i32.const 0) "\20\00\00\00\10\00\00\00")
+              (func (export "_start")
+                (loop $next
+                  (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const {iovs_len}) (i32.const 8)))
+                  (br_if $next (i32.load (i32.const 8))))))"#
+        )
+    }
+
+    // Spins on reads of stdin returning nothing
+    fn spinner(iovs_len: u32) -> String {
+        reader(iovs_len).replace("(br_if $next (i32.load (i32.const 8)))", "(br $next)")
+    }
+
+    // Sleeps 3 times 100 ms in poll_oneoff
+    const SLEEPER: &str = r#"(module
+      (import "wasi_snapshot_preview1" "poll_oneoff"
+        (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 16) "\01\00\00\00\00\00\00\00\00\e1\f5\05\00\00\00\00")
+      (func (export "_start") (local $n i32)
+        (loop $sleep
+          (if (call $poll_oneoff (i32.const 0) (i32.const 64) (i32.const 1) (i32.const 128))
+            (then unreachable))
+          (local.set $n (i32.add (local.get $n) (i32.const 1)))
+          (br_if $sleep (i32.lt_u (local.get $n) (i32.const 3))))))"#;
+
+    fn run_wat(wat: &str, input: &[u8]) -> Result<(), String> {
+        run_wat_within(wat, input, Duration::from_secs(2))
+    }
+
+    fn run_wat_within(
+        wat: &str,
+        input: &[u8],
+        max_processing_time: Duration,
+    ) -> Result<(), String> {
+        let limits = Limits {
+            fuel: 10_000,
+            max_memory: 1 << 20,
+            max_processing_time,
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+        let mut store = Store::new(engine(&limits));
+        let module = Module::new(&store, wat).unwrap();
+        let (mut writer, stdin) = Pipe::channel();
+        writer.write_all(input).unwrap();
+        drop(writer);
+        let wasi = WasiEnv::builder("test")
+            .stdin(Box::new(stdin))
+            .finalize(&mut store)
+            .unwrap();
+        run(&mut store, &module, wasi, &limits, &Policy::default())
+    }
+
+    #[test]
+    fn reads_with_data() {
+        assert_eq!(run_wat(&reader(1), &[1; 100]), Ok(()));
+    }
+
+    #[test]
+    fn empty_reads_do_not_refuel() {
+        // without an iovec, then at EOF
+        assert_eq!(
+            run_wat(&spinner(0), &[1; 100]),
+            Err("CPU limit exceeded".to_string())
+        );
+        assert_eq!(
+            run_wat(&spinner(1), &[]),
+            Err("CPU limit exceeded".to_string())
+        );
+    }
+
+    #[test]
+    fn sleeping_is_not_processing() {
+        let started = Instant::now();
+        assert_eq!(
+            run_wat_within(SLEEPER, &[], Duration::from_millis(200)),
+            Ok(())
+        );
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// LoRaWAN uplink reception and downlinks for off-the-shelf end devices (ABP).
+// The gateway only holds the NwkSKey of each device to check and sign frames,
+// the FRMPayload is forwarded still encrypted with the AppSKey, both ways.
+
+use crate::{Error, Result};
+
+use msg::crypto::{parse_key_table, AppKey};
+use msg::lorawan::{FCtrl, Fhdr, MType, MacPayload, Mhdr, PhyPayload};
+use msg::{Msg, Packet};
+use std::collections::HashMap;
+
+struct Session {
+    nwk_skey: AppKey,
+    fcnt: Option<u32>,      // last full 32 bit frame counter accepted
+    fcnt_down: Option<u32>, // last downlink frame counter sent
+}
+
+pub struct NwkKeys {
+    sessions: HashMap<u32, Session>,
+    // whether end devices may restart their counter (the reset fcnt policy)
+    resets: bool,
+}
+
+impl NwkKeys {
+    // Load a NwkSKey table, one "<DevAddr> <NwkSKey>" pair of hex strings
+    // per line (see msg::crypto::parse_key_table)
+    pub fn load(path: &str, resets: bool) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(Error::Io)?;
+        Self::parse(&text, resets)
+    }
+
+    pub fn parse(text: &str, resets: bool) -> Result<Self> {
+        let sessions = parse_key_table(text, |addr, key| {
+            let session = Session {
+                nwk_skey: AppKey::from_hex(key).ok()?,
+                fcnt: None,
+                fcnt_down: None,
+            };
+            Some((u32::from_str_radix(addr, 16).ok()?, session))
+        })
+        .map_err(Error::Msg)?;
+        Ok(Self { sessions, resets })
+    }
+
+    // Parse and authenticate a LoRaWAN uplink, mapping it to a Msg keyed by
+    // DevAddr. Its counter is only committed once accepted (see commit).
+    pub fn decode(&self, bytes: &[u8]) -> Result<Packet> {
+        let phy = PhyPayload::parse(bytes).map_err(Error::Msg)?;
+        let session = self
+            .sessions
+            .get(&phy.dev_addr())
+            .ok_or(Error::UnknownDevAddr(phy.dev_addr()))?;
+
+        // Frames carry the 16 LSBs of the counter, rebuild the MSBs: the frame
+        // is from the current epoch, the next one, or, if end devices may
+        // reset, from the first one after a reset (the fcnt policy tells
+        // those from replays)
+        let lsb = phy.mac_payload.fhdr.fcnt as u32;
+        let last = session.fcnt.unwrap_or(0);
+        let same = (last & 0xFFFF_0000) | lsb;
+        let reset = self.resets.then_some(lsb);
+        let fcnt = [same, same.wrapping_add(0x1_0000)]
+            .into_iter()
+            .chain(reset)
+            .find(|&fcnt| phy.verify_mic(&session.nwk_skey, fcnt).is_ok())
+            .ok_or(Error::Msg(msg::Error::BadMic))?;
+
+        let confirmed = phy.mhdr.mtype == MType::ConfirmedDataUp;
+        // only confirmed uplinks are retransmitted with the same counter
+        if session.fcnt == Some(fcnt) && !confirmed {
+            return Err(Error::RepeatedFcnt(phy.dev_addr(), fcnt));
+        }
+
+        let msg = Msg {
+            fcnt,
+            ..phy.into_msg()
+        };
+        if confirmed {
+            Ok(Packet::Confirmed(msg))
+        } else {
+            Ok(Packet::Msg(msg))
+        }
+    }
+
+    // Record the counter of an uplink accepted by the fcnt policy: replayed
+    // frames never move it, nor does anything move it backwards unless end
+    // devices may reset
+    pub fn commit(&mut self, dev_addr: u32, fcnt: u32) {
+        if let Some(session) = self.sessions.get_mut(&dev_addr) {
+            if self.resets || session.fcnt.map_or(true, |last| fcnt > last) {
+                session.fcnt = Some(fcnt);
+            }
+        }
+    }
+
+    // Sign a downlink to a LoRaWAN end device: an Ack is an empty frame
+    // with the ACK bit, and a virtual device downlink carries an FRMPayload
+    // it encrypted with the AppSKey and its fcnt, which must be ahead of the
+    // last sent. The gateway commands of the private network are refused.
+    pub fn encode(&mut self, packet: &Packet) -> Result<Vec<u8>> {
+        let addr = packet.addr();
+        let dev_addr = u32::try_from(addr).map_err(|_| Error::UnsupportedDownlink(addr))?;
+        let session = self
+            .sessions
+            .get_mut(&dev_addr)
+            .ok_or(Error::UnknownDevAddr(dev_addr))?;
+        let next = session.fcnt_down.map_or(0, |last| last.wrapping_add(1));
+        let (fcnt, ack, fport, frm_payload) = match packet {
+            Packet::Ack { .. } => (next, true, None, Vec::new()),
+            Packet::Downlink(msg) => {
+                if session.fcnt_down.is_some_and(|last| msg.fcnt <= last) {
+                    return Err(Error::StaleFcntDown(dev_addr, msg.fcnt));
+                }
+                (msg.fcnt, false, Some(msg.port), msg.payload.clone())
+            }
+            _ => return Err(Error::UnsupportedDownlink(addr)),
+        };
+        let mut phy = PhyPayload {
+            mhdr: Mhdr {
+                mtype: MType::UnconfirmedDataDown,
+                major: 0,
+            },
+            mac_payload: MacPayload {
+                fhdr: Fhdr {
+                    dev_addr,
+                    fctrl: FCtrl {
+                        ack,
+                        ..FCtrl::default()
+                    },
+                    fcnt: fcnt as u16,
+                    fopts: Vec::new(),
+                },
+                fport,
+                frm_payload,
+            },
+            mic: [0; 4],
+        };
+        phy.mic = phy
+            .compute_mic(&session.nwk_skey, fcnt)
+            .map_err(Error::Msg)?;
+        let frame = phy.to_bytes().map_err(Error::Msg)?;
+        // never reused, even if the transmission fails
+        session.fcnt_down = Some(fcnt);
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msg::lorawan::SessionKeys;
+
+    const DEV_ADDR: u32 = 0x49be7df1;
+
+    fn keys(resets: bool) -> NwkKeys {
+        NwkKeys::parse("49be7df1 44024241ed4ce9a68c6a8bc055233fd3", resets).unwrap()
+    }
+
+    fn frame(fcnt: u32) -> Vec<u8> {
+        let keys = SessionKeys {
+            nwk_skey: AppKey::from_hex("44024241ed4ce9a68c6a8bc055233fd3").unwrap(),
+            app_skey: AppKey::from_hex("ec925802ae430ca77fd3dd73cb2cc588").unwrap(),
+        };
+        PhyPayload::new_data(MType::UnconfirmedDataUp, DEV_ADDR, fcnt, 1, b"hi", &keys)
+            .unwrap()
+            .to_bytes()
+            .unwrap()
+    }
+
+    fn fcnt(packet: Result<Packet>) -> u32 {
+        match packet {
+            Ok(Packet::Msg(msg)) => msg.fcnt,
+            other => panic!("{other:?}"),
+        }
+    }
+
+    // The end device two epochs ahead of its first frame, all accepted
+    fn ahead(keys: &mut NwkKeys) -> Vec<u8> {
+        let first = frame(1);
+        for n in [1, 0x1_0002, 0x2_0003] {
+            assert_eq!(fcnt(keys.decode(&frame(n))), n);
+            keys.commit(DEV_ADDR, n);
+        }
+        first
+    }
+
+    #[test]
+    fn epochs() {
+        let mut keys = keys(false);
+        ahead(&mut keys);
+        assert_eq!(fcnt(keys.decode(&frame(0x2_0004))), 0x2_0004);
+        // not committed, the same frame is not a repetition yet
+        assert_eq!(fcnt(keys.decode(&frame(0x2_0004))), 0x2_0004);
+        keys.commit(DEV_ADDR, 0x2_0004);
+        assert!(matches!(
+            keys.decode(&frame(0x2_0004)),
+            Err(Error::RepeatedFcnt(DEV_ADDR, 0x2_0004))
+        ));
+        assert!(matches!(
+            keys.decode(&frame(0x4_0005)),
+            Err(Error::Msg(msg::Error::BadMic))
+        ));
+    }
+
+    #[test]
+    fn replayed_frame() {
+        let mut keys = keys(false);
+        let replay = ahead(&mut keys);
+        assert!(matches!(
+            keys.decode(&replay),
+            Err(Error::Msg(msg::Error::BadMic))
+        ));
+        // even if committed by mistake, the counter does not move back
+        keys.commit(DEV_ADDR, 1);
+        assert_eq!(fcnt(keys.decode(&frame(0x2_0004))), 0x2_0004);
+    }
+
+    #[test]
+    fn replayed_frame_with_resets() {
+        let mut keys = keys(true);
+        let replay = ahead(&mut keys);
+        // a reset candidate, left to the fcnt policy: refused, not committed
+        assert_eq!(fcnt(keys.decode(&replay)), 1);
+        assert_eq!(fcnt(keys.decode(&frame(0x2_0004))), 0x2_0004);
+        // accepted as a reset, the device restarts from there
+        keys.commit(DEV_ADDR, 1);
+        assert_eq!(fcnt(keys.decode(&frame(2))), 2);
+    }
+
+    #[test]
+    fn downlinks() {
+        let mut keys = keys(false);
+        let nwk_skey = AppKey::from_hex("44024241ed4ce9a68c6a8bc055233fd3").unwrap();
+        let addr = DEV_ADDR as u64;
+
+        let ack = keys.encode(&Packet::Ack { addr, fcnt: 5 }).unwrap();
+        let phy = PhyPayload::parse(&ack).unwrap();
+        assert_eq!(phy.mhdr.mtype, MType::UnconfirmedDataDown);
+        assert!(phy.mac_payload.fhdr.fctrl.ack);
+        assert_eq!(phy.mac_payload.fport, None);
+        assert!(phy.verify_mic(&nwk_skey, 0).is_ok());
+
+        // the FRMPayload is sent as encrypted by the virtual device
+        let downlink = Msg {
+            addr,
+            fcnt: 0x1_0000,
+            port: 2,
+            payload: vec![0xAA; 3],
+        };
+        let frame = keys.encode(&Packet::Downlink(downlink.clone())).unwrap();
+        let phy = PhyPayload::parse(&frame).unwrap();
+        assert!(!phy.mac_payload.fhdr.fctrl.ack);
+        assert_eq!(phy.mac_payload.fport, Some(2));
+        assert_eq!(phy.mac_payload.frm_payload, [0xAA; 3]);
+        assert!(phy.verify_mic(&nwk_skey, 0x1_0000).is_ok());
+        // counters are never reused
+        assert!(matches!(
+            keys.encode(&Packet::Downlink(downlink)),
+            Err(Error::StaleFcntDown(DEV_ADDR, 0x1_0000))
+        ));
+        let ack = keys.encode(&Packet::Ack { addr, fcnt: 6 }).unwrap();
+        let phy = PhyPayload::parse(&ack).unwrap();
+        assert!(phy.verify_mic(&nwk_skey, 0x1_0001).is_ok());
+
+        assert!(matches!(
+            keys.encode(&Packet::Ack { addr: 0x2, fcnt: 1 }),
+            Err(Error::UnknownDevAddr(0x2))
+        ));
+        let cmd = Packet::DevCtrl {
+            addr,
+            cmd: msg::message::DevCmd::Reboot,
+        };
+        assert!(matches!(
+            keys.encode(&cmd),
+            Err(Error::UnsupportedDownlink(_))
+        ));
+    }
+}
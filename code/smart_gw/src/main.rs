@@ -13,85 +13,436 @@
 // limitations under the License.
 //
 
-use broker::{Broker, ALL};
+use broker::Broker;
+use dedup::Dedup;
 use demux::Demux;
+use discovery::{HttpRegistry, LocalRegistry, ServiceDiscovery};
+use downlink::Downlinks;
+use fcnt::{Policy, Verdict};
+use ledger::{Claims, LedgerRegistry};
+use lora::Reception;
+use lorawan::NwkKeys;
+use msg::crypto::AppKeys;
+use msg::message::Message;
+use msg::uplink::{RxMeta, Uplink};
+use msg::Packet;
+use policy::Grants;
+use reasm::Reassembler;
 use smart_gw::*;
+use trust::Publishers;
 use vdctrl::VirtDevCtrl;
 
-use std::{env, process, thread, time::Duration};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use std::{env, io, process, thread};
 
 pub fn main() -> ! {
-    let args: Vec<String> = env::args().collect();
+    let mut lora = false;
+    let mut lorawan = None;
+    let mut emu_keys = None;
+    let mut gw_id = rand::random::<u64>();
+    let mut policy = Policy::window(16);
+    let mut peer_bind = None;
+    let mut peers = Vec::new();
+    let mut homes = HashSet::new();
+    let mut vd_config = vdctrl::Config::default();
+    let mut registry = None;
+    let mut registry_cache = discovery::CACHE_DIR.to_string();
+    let mut grants = Grants::default();
+    let mut claims = None;
 
-    if args.len() > 2 || (args.len() == 2 && args[1] != "--lora") {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--lora" => lora = true,
+            "--lorawan" => lorawan = Some(args.next().unwrap_or_else(|| help())),
+            "--emu-keys" => emu_keys = Some(args.next().unwrap_or_else(|| help())),
+            "--id" => {
+                let id = args.next().unwrap_or_else(|| help());
+                gw_id = u64::from_str_radix(&id, 16).unwrap_or_else(|_| help())
+            }
+            "--fcnt" => {
+                let p = args.next().unwrap_or_else(|| help());
+                policy = p.parse().unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    help()
+                })
+            }
+            "--peer-bind" => {
+                let addr = args.next().unwrap_or_else(|| help());
+                peer_bind = Some(addr.parse().unwrap_or_else(|_| help()))
+            }
+            "--peer" => {
+                let addr = args.next().unwrap_or_else(|| help());
+                peers.push(addr.parse().unwrap_or_else(|_| help()))
+            }
+            "--home" => {
+                let addr = args.next().unwrap_or_else(|| help());
+                homes.insert(u64::from_str_radix(&addr, 16).unwrap_or_else(|_| help()));
+            }
+            "--vd-idle" => {
+                let secs = args.next().unwrap_or_else(|| help());
+                vd_config.idle_timeout =
+                    Duration::from_secs(secs.parse().unwrap_or_else(|_| help()))
+            }
+            "--vd-max" => {
+                let max = args.next().unwrap_or_else(|| help());
+                vd_config.max_instances = max.parse().unwrap_or_else(|_| help())
+            }
+            "--vd-state" => {
+                vd_config.state_dir = Some(args.next().unwrap_or_else(|| help()).into())
+            }
+            "--vd-cache" => {
+                vd_config.module_cache = Some(args.next().unwrap_or_else(|| help()).into())
+            }
+            "--vd-queue" => {
+                let q = args.next().unwrap_or_else(|| help());
+                vd_config.queue = q.parse().unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    help()
+                })
+            }
+            "--vd-fuel" => {
+                let fuel = args.next().unwrap_or_else(|| help());
+                vd_config.limits.fuel = fuel.parse().unwrap_or_else(|_| help())
+            }
+            "--vd-memory" => {
+                let mib = args.next().unwrap_or_else(|| help());
+                vd_config.limits.max_memory = mib.parse::<usize>().unwrap_or_else(|_| help()) << 20
+            }
+            "--vd-time" => {
+                let secs = args.next().unwrap_or_else(|| help());
+                vd_config.limits.max_processing_time =
+                    Duration::from_secs(secs.parse().unwrap_or_else(|_| help()))
+            }
+            "--trusted" => {
+                let path = args.next().unwrap_or_else(|| help());
+                let publishers = Publishers::load(Path::new(&path)).unwrap_or_else(|e| {
+                    eprintln!("{path}: {e}");
+                    help()
+                });
+                vd_config.publishers = Some(Arc::new(publishers))
+            }
+            "--registry" => registry = Some(args.next().unwrap_or_else(|| help())),
+            "--registry-cache" => registry_cache = args.next().unwrap_or_else(|| help()),
+            "--registry-grants" => {
+                let g = args.next().unwrap_or_else(|| help());
+                grants = g.parse().unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    help()
+                })
+            }
+            "--ledger-operators" => {
+                let path = args.next().unwrap_or_else(|| help());
+                let operators = Publishers::load(Path::new(&path)).unwrap_or_else(|e| {
+                    eprintln!("{path}: {e}");
+                    help()
+                });
+                claims = Some(Claims::Operators(operators))
+            }
+            "--ledger-tofu" => claims = Some(Claims::FirstUse),
+            _ => help(),
+        }
+    }
+    if (lorawan.is_some() && !lora)
+        || (emu_keys.is_some() && lora)
+        || (peer_bind.is_none() && !peers.is_empty())
+    {
         help()
     }
 
+    println!("[gw] id: {gw_id:016x}");
+    if vd_config.publishers.is_none() {
+        println!("[gw] no trusted publishers, driver signatures are not checked.");
+    }
+
     // create pub/sub broker
-    let mut broker = Broker::new();
+    let broker = Broker::new();
+    let events = broker.events();
+    thread::spawn(move || {
+        for event in events {
+            println!("[gw] broker event: {event:?}");
+        }
+    });
+
+    // virtual devices and their control plane
+    let discovery: Arc<dyn ServiceDiscovery> = match registry {
+        Some(url) if url.starts_with("http://") => {
+            Arc::new(HttpRegistry::new(&url, registry_cache.into(), grants).unwrap())
+        }
+        Some(url) if url.starts_with("ledger:") => {
+            // who may claim end devices is a choice of the operator
+            let claims = claims.unwrap_or_else(|| {
+                eprintln!("ledger registries need --ledger-operators <file> or --ledger-tofu");
+                help()
+            });
+            Arc::new(LedgerRegistry::open(Path::new(&url["ledger:".len()..]), claims).unwrap())
+        }
+        Some(manifest) => Arc::new(LocalRegistry::load(Path::new(&manifest)).unwrap()),
+        None => Arc::new(LocalRegistry::default()),
+    };
+    let vdctrl = VirtDevCtrl::new(&broker, vd_config, discovery).unwrap();
+
+    // commands of the operator on stdin, e.g. "reload 0000abcd"
+    let commands = broker.clone();
+    thread::spawn(move || vdctrl::read_commands(&commands, io::stdin().lock()));
 
-    // subscribe the virt dev ctrl to all topics
-    let global_sub = broker.subscribe(ALL);
-    let vdctrl = VirtDevCtrl::new(global_sub);
+    // per device queues of the packets to send after the next uplink
+    let mut downlinks = Downlinks::new(&broker).unwrap();
 
     // create demultiplexer
-    let mut demux = Demux::new(broker, vdctrl);
+    let mut demux = Demux::new(broker, vdctrl, policy);
+
+    // buffers of fragmented messages
+    let mut reasm = Reassembler::new(REASM_TIMEOUT);
+
+    // election of the gateway delivering uplinks heard by neighbours too
+    let mut dedup =
+        peer_bind.map(|bind| Dedup::new(gw_id, bind, peers, homes, DEDUP_WINDOW).unwrap());
+
+    if lora {
+        // LoRaWAN end devices are authenticated with their NwkSKey
+        let resets = matches!(policy, Policy::Reset(_));
+        let mut nwk_keys = lorawan.map(|path| NwkKeys::load(&path, resets).unwrap());
 
-    if args.len() == 2 {
         // init lora interface
-        let mut lora = init_lora().unwrap();
+        let mut lora = match nwk_keys {
+            Some(_) => init_lora(SYNC_WORD_LORAWAN),
+            None => init_lora(SYNC_WORD_PRIVATE),
+        }
+        .unwrap();
 
         // Main loop
         loop {
-            let msg = {
-                let bytes = recv(&mut lora).unwrap();
-                match msg::deserialize(bytes.as_slice()) {
-                    Ok(m) => m,
-                    Err(e) => {
-                        eprintln!("{e}");
-                        continue;
+            let mut delivered = elected(&mut demux, &mut dedup);
+            match try_recv(&mut lora).unwrap() {
+                Some(rx) => {
+                    let packet = match nwk_keys {
+                        Some(ref keys) => keys.decode(rx.data.as_slice()),
+                        None => {
+                            msg::deserialize_packet_auto(rx.data.as_slice()).map_err(Error::Msg)
+                        }
+                    };
+                    match packet {
+                        Ok(packet) => {
+                            let meta = rx_meta(gw_id, &rx);
+                            delivered
+                                .extend(dispatch(&mut demux, &mut reasm, &mut dedup, packet, meta))
+                        }
+                        Err(e) => eprintln!("{e}"),
                     }
                 }
-            };
-            demux.dispatch(msg);
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+            if let Some(ref mut keys) = nwk_keys {
+                for d in delivered.iter().filter(|d| d.accepted) {
+                    keys.commit(d.addr as u32, d.fcnt);
+                }
+            }
+            for d in delivered {
+                downlinks.schedule(d.addr, d.fcnt, d.rx_time);
+            }
+            // LoRaWAN end devices get LoRaWAN downlinks, with inverted IQ
+            let invert_iq = nwk_keys.is_some();
+            downlinks.poll(
+                SystemTime::now(),
+                |packet| match nwk_keys {
+                    Some(ref mut keys) => keys.encode(packet),
+                    None => packet.serialize().map_err(Error::Msg),
+                },
+                |frame, window| transmit_in(&mut lora, frame, window, invert_iq),
+            );
         }
     } else {
+        // frame counters and application keys of the emulated devices
+        let mut fcnts = [0u32; ADDR_LST.len()];
+        let keys = match emu_keys {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(Error::Io)
+                .and_then(|text| AppKeys::parse(&text).map_err(Error::Msg))
+                .unwrap(),
+            None => AppKeys::default(),
+        };
+
         // Main loop
+        let mut next_rx = Instant::now();
         loop {
-            let msg = {
-                let bytes = emu_recv().unwrap();
-                match msg::deserialize(bytes.as_slice()) {
-                    Ok(m) => m,
-                    Err(e) => {
-                        eprintln!("{e}");
-                        continue;
+            let mut delivered = elected(&mut demux, &mut dedup);
+            if Instant::now() >= next_rx {
+                next_rx += EMU_PERIOD;
+                let rx = emu_recv(&mut fcnts, &keys).unwrap();
+                match msg::deserialize_packet_auto(rx.data.as_slice()) {
+                    Ok(packet) => {
+                        let meta = rx_meta(gw_id, &rx);
+                        delivered.extend(dispatch(&mut demux, &mut reasm, &mut dedup, packet, meta))
                     }
+                    Err(e) => eprintln!("{e}"),
                 }
-            };
-            demux.dispatch(msg);
+            } else {
+                thread::sleep(Duration::from_millis(1))
+            }
+            for d in delivered {
+                downlinks.schedule(d.addr, d.fcnt, d.rx_time);
+            }
+            downlinks.poll(
+                SystemTime::now(),
+                |packet| packet.serialize().map_err(Error::Msg),
+                |frame, window| {
+                    println!("[gw] emu transmit in {window:?}: {} bytes.", frame.len());
+                    Ok(())
+                },
+            );
         }
     }
 }
 
-// Default test payload
-const PAYLOAD: &str = "TEST MESSAGE";
+// Time to receive all the fragments of a message
+const REASM_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Time to collect the digests of neighbouring gateways
+const DEDUP_WINDOW: Duration = Duration::from_millis(250);
+
+// Time between the receptions of emulated uplinks
+const EMU_PERIOD: Duration = Duration::from_secs(1);
+
+// Uplink of a reassembled message, confirmed if any of its fragments was
+fn reassembled((msg, confirmed): (msg::Msg, bool), meta: RxMeta) -> (Uplink, bool) {
+    (Uplink { meta, msg }, confirmed)
+}
+
+// Uplink delivered to the virtual devices, followed by the Class A receive
+// windows of its end device
+struct Delivered {
+    addr: u64,
+    fcnt: u32,
+    // by the fcnt policy, false for the retransmission of a confirmed uplink
+    accepted: bool,
+    rx_time: u64,
+}
+
+// Reassembled messages carry the metadata of their last fragment. Uplinks
+// also heard by neighbouring gateways are held until their election closes
+// (see elected).
+fn dispatch(
+    demux: &mut Demux,
+    reasm: &mut Reassembler,
+    dedup: &mut Option<Dedup>,
+    packet: Packet,
+    meta: RxMeta,
+) -> Option<Delivered> {
+    let (up, confirmed) = match packet {
+        Packet::Msg(msg) => (Uplink { meta, msg }, false),
+        Packet::Confirmed(msg) => (Uplink { meta, msg }, true),
+        Packet::Fragment(frag) => reassembled(reasm.push(frag, false)?, meta),
+        Packet::ConfirmedFragment(frag) => reassembled(reasm.push(frag, true)?, meta),
+        // sent by a neighbouring gateway
+        Packet::Downlink(_) | Packet::Ack { .. } | Packet::DevCtrl { .. } => return None,
+    };
+    match dedup {
+        Some(dedup) => {
+            dedup.submit(up, confirmed);
+            None
+        }
+        None => deliver(demux, up, confirmed),
+    }
+}
+
+// Deliver the held uplinks whose election this gateway won
+fn elected(demux: &mut Demux, dedup: &mut Option<Dedup>) -> Vec<Delivered> {
+    let Some(dedup) = dedup else {
+        return Vec::new();
+    };
+    dedup
+        .poll()
+        .into_iter()
+        .filter_map(|(up, confirmed)| deliver(demux, up, confirmed))
+        .collect()
+}
+
+// None if the demultiplexer dropped the uplink: replays get no receive window
+fn deliver(demux: &mut Demux, up: Uplink, confirmed: bool) -> Option<Delivered> {
+    let mut delivered = Delivered {
+        addr: up.msg.addr,
+        fcnt: up.msg.fcnt,
+        accepted: true,
+        rx_time: up.meta.time,
+    };
+    let verdict = if confirmed {
+        demux.dispatch(Message::ConfirmedUplink(up))
+    } else {
+        demux.dispatch(Message::Uplink(up))
+    };
+    match verdict {
+        Verdict::Accept | Verdict::Reset => Some(delivered),
+        // the Ack of the first transmission got lost, it is sent again
+        Verdict::Duplicate if confirmed => {
+            delivered.accepted = false;
+            Some(delivered)
+        }
+        Verdict::Duplicate | Verdict::Replay => None,
+    }
+}
 
 // List of addresses to emulate device variety
 const ADDR_LST: [u64; 10] = [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9];
 
-fn emu_recv() -> Result<Vec<u8>> {
-    use rand::seq::SliceRandom;
-    thread::sleep(Duration::from_secs(1));
-    msg::Msg {
-        addr: *ADDR_LST.choose(&mut rand::thread_rng()).unwrap(),
-        fcnt: 0,
-        payload: PAYLOAD.as_bytes().to_vec(),
+fn emu_recv(fcnts: &mut [u32; ADDR_LST.len()], keys: &AppKeys) -> Result<Reception> {
+    use rand::Rng;
+    // emulated readings are CayenneLPP encoded, as off-the-shelf sensors do
+    let payload = {
+        let mut rng = rand::thread_rng();
+        let mut lpp = msg::lpp::Encoder::new();
+        lpp.temperature(1, rng.gen_range(15.0..30.0))
+            .and_then(|lpp| lpp.humidity(2, rng.gen_range(30.0..70.0)))
+            .map_err(Error::Msg)?;
+        lpp.finish()
+    };
+    let dev = rand::thread_rng().gen_range(0..ADDR_LST.len());
+    fcnts[dev] += 1;
+    let mut msg = msg::Msg {
+        addr: ADDR_LST[dev],
+        fcnt: fcnts[dev],
+        port: 1,
+        payload,
+    };
+    if let Some(key) = keys.get(msg.addr) {
+        msg.encrypt(key, msg::crypto::Dir::Up);
     }
-    .serialize()
-    .map_err(Error::Msg)
+    // some emulated devices ask for acknowledgements
+    let packet = if rand::thread_rng().gen_bool(0.25) {
+        Packet::Confirmed(msg)
+    } else {
+        Packet::Msg(msg)
+    };
+    Ok(Reception {
+        data: packet.serialize().map_err(Error::Msg)?,
+        rss: rand::thread_rng().gen_range(-120..-40),
+        snr: rand::thread_rng().gen_range(-10..10),
+    })
 }
 
 fn help() -> ! {
-    println!("Usage: smart_gw [--lora]");
+    println!(
+        "Usage: smart_gw [--id <gw_id>] [--fcnt <strict|window[:N]|reset[:N]>] \
+        [--peer-bind <ip:port> [--peer <ip:port>]... [--home <addr>]...] \
+        [--vd-idle <secs>] [--vd-max <n>] [--vd-state <dir>] [--vd-cache <dir>] \
+        [--vd-queue <n>[:<drop-oldest|drop-newest|block:<ms>|disconnect>]] \
+        [--vd-fuel <n>] [--vd-memory <MiB>] [--vd-time <secs>] \
+        [--registry <manifest|ledger:<file>|http://host:port> [--registry-cache <dir>] \
+        [--registry-grants <network,env,args>] [--ledger-operators <keys> | --ledger-tofu]] \
+        [--trusted <publishers>] \
+        [--lora [--lorawan <nwk_keys>] | --emu-keys <app_keys>]"
+    );
+    println!(
+        "  --fcnt defaults to window:16. reset accepts low counters as reboots, \
+        so anyone can replay the first uplinks of a device: only enable it for \
+        end devices that lose their counter on reboot."
+    );
+    println!(
+        "  Virtual devices are managed with commands on stdin, one per line: \
+        <load|unload|reload|pause|resume> <addr>."
+    );
     process::exit(1)
 }
@@ -0,0 +1,288 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Cache of the compiled driver modules, shared by the virtual devices
+//
+// Compiling a wasm module takes seconds on a Raspberry Pi, while most end
+// devices share a few drivers: modules are compiled once per content hash,
+// and shared by every instance. If a directory is set, compiled modules are
+// also serialized there, so gateway restarts load them instead of compiling
+// again. Artifacts are named after the hash, the engine and the wasmer
+// version, as they only load in the same setup. Modules are compiled metered
+// (see limits), each by its own engine, as the metering middleware keeps the
+// globals of the module it compiles: the stores running a module use its
+// engine.
+//
+// Deserializing an artifact runs its native code unchecked: artifacts start
+// with their HMAC-SHA256 under a key of the gateway, kept next to the
+// directory (<dir>.key, readable by the gateway only), and only artifacts
+// with a valid MAC are deserialized. Anyone able to write the directory can
+// at worst force compilations.
+//
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use wasmer::{Engine, Module};
+
+use crate::limits::{self, Limits};
+use crate::trust::{self, decode_array};
+
+const MAC_LEN: usize = 32;
+
+type Key = [u8; 32];
+
+// Module, and the engine that compiled it
+pub type Compiled = (Engine, Module);
+
+pub struct ModuleCache {
+    limits: Limits,
+    // of every engine of the limits, in the names of the artifacts
+    engine_id: String,
+    dir: Option<(PathBuf, Key)>,
+    modules: Mutex<HashMap<String, Compiled>>,
+}
+
+impl ModuleCache {
+    // Without its key, the directory is not used
+    pub fn new(limits: Limits, dir: Option<PathBuf>) -> Self {
+        let dir = dir.and_then(|dir| {
+            let mut path = dir.clone().into_os_string();
+            path.push(".key");
+            let path = PathBuf::from(path);
+            match load_key(&path) {
+                Ok(key) => Some((dir, key)),
+                Err(e) => {
+                    println!("[modcache] {}: {e}, not caching on disk.", path.display());
+                    None
+                }
+            }
+        });
+        let engine_id = limits::engine(&limits)
+            .deterministic_id()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        Self {
+            limits,
+            engine_id,
+            dir,
+            modules: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Compiled module of the given (verified) wasm bytes, with its engine
+    pub fn get(&self, bytes: &[u8]) -> Result<Compiled, String> {
+        let hash = trust::module_hash(bytes);
+        if let Some(compiled) = self.modules.lock().unwrap().get(&hash) {
+            return Ok(compiled.clone());
+        }
+
+        // compiled out of the lock, at worst twice
+        let artifact = self.artifact(&hash);
+        let compiled = match artifact
+            .as_ref()
+            .and_then(|(path, key)| self.load(path, key))
+        {
+            Some(compiled) => compiled,
+            None => {
+                let engine = limits::engine(&self.limits);
+                let module = Module::new(&engine, bytes).map_err(|e| e.to_string())?;
+                if let Some((ref path, key)) = artifact {
+                    Self::store(&module, path, key);
+                }
+                (engine, module)
+            }
+        };
+        let mut modules = self.modules.lock().unwrap();
+        Ok(modules.entry(hash).or_insert(compiled).clone())
+    }
+
+    fn artifact(&self, hash: &str) -> Option<(PathBuf, &Key)> {
+        let name = format!("{hash}-{}-metered-{}.bin", self.engine_id, wasmer::VERSION);
+        self.dir.as_ref().map(|(dir, key)| (dir.join(name), key))
+    }
+
+    fn load(&self, path: &Path, key: &Key) -> Option<Compiled> {
+        let file = match fs::read(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                println!("[modcache] {}: {e}, compiling again.", path.display());
+                return None;
+            }
+        };
+        let authentic = file.len() >= MAC_LEN && {
+            let (tag, artifact) = file.split_at(MAC_LEN);
+            // compared in constant time
+            hmac(key).chain_update(artifact).verify_slice(tag).is_ok()
+        };
+        if !authentic {
+            println!("[modcache] {}: bad MAC, compiling again.", path.display());
+            return None;
+        }
+        // Safety: the MAC shows the artifact was serialized by store, with
+        // the key of this gateway, from a module compiled by an engine of
+        // these limits (deserialize also checks the wasmer version of the
+        // artifact)
+        let engine = limits::engine(&self.limits);
+        match unsafe { Module::deserialize(&engine, &file[MAC_LEN..]) } {
+            Ok(module) => {
+                println!("[modcache] loaded {}.", path.display());
+                Some((engine, module))
+            }
+            Err(e) => {
+                println!("[modcache] {}: {e}, compiling again.", path.display());
+                None
+            }
+        }
+    }
+
+    // Failures only cost a compilation at the next gateway start
+    fn store(module: &Module, path: &Path, key: &Key) {
+        let partial = path.with_extension("part");
+        let stored = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .map_err(|e| e.to_string())
+            .and_then(|_| module.serialize().map_err(|e| e.to_string()))
+            .and_then(|artifact| {
+                let mut file = mac(key, &artifact).to_vec();
+                file.extend_from_slice(&artifact);
+                fs::write(&partial, file).map_err(|e| e.to_string())
+            })
+            .and_then(|_| fs::rename(&partial, path).map_err(|e| e.to_string()));
+        match stored {
+            Ok(()) => println!("[modcache] stored {}.", path.display()),
+            Err(e) => println!("[modcache] {}: {e}", path.display()),
+        }
+    }
+}
+
+// Key of the gateway, in hex, created on first use
+fn load_key(path: &Path) -> Result<Key, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => return decode_array(text.trim()).ok_or("bad key".to_string()),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e.to_string()),
+    }
+    let mut key = Key::default();
+    rand::rngs::OsRng.fill_bytes(&mut key);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| e.to_string())?;
+    file.write_all(hex::encode(key).as_bytes())
+        .map_err(|e| e.to_string())?;
+    println!("[modcache] new key in {}.", path.display());
+    Ok(key)
+}
+
+fn hmac(key: &Key) -> Hmac<Sha256> {
+    Hmac::new_from_slice(key).expect("HMAC takes keys of any size")
+}
+
+fn mac(key: &Key, bytes: &[u8]) -> [u8; MAC_LEN] {
+    hmac(key).chain_update(bytes).finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Empty module
+    const WASM: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn hmac() {
+        // RFC 4231, test case 2 with the key zero padded, as HMAC does
+        let mut key = Key::default();
+        key[..4].copy_from_slice(b"Jefe");
+        assert_eq!(
+            hex::encode(mac(&key, b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn authenticated_artifacts() {
+        let dir = std::env::temp_dir().join(format!("modcache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = || ModuleCache::new(Limits::default(), Some(dir.clone()));
+        cache().get(WASM).unwrap();
+        let (path, _) = cache().artifact(&trust::module_hash(WASM)).unwrap();
+        let (_, key) = cache().dir.unwrap();
+        assert!(cache().load(&path, &key).is_some());
+
+        let mut file = fs::read(&path).unwrap();
+        let last = file.len() - 1;
+        file[last] ^= 1;
+        fs::write(&path, file).unwrap();
+        assert!(cache().load(&path, &key).is_none());
+        // compiled and stored again
+        cache().get(WASM).unwrap();
+        assert!(cache().load(&path, &key).is_some());
+
+        let mut key_file = dir.clone().into_os_string();
+        key_file.push(".key");
+        fs::remove_file(&key_file).unwrap();
+        // a new key, previous artifacts are compiled again
+        assert!(cache().load(&path, &cache().dir.unwrap().1).is_none());
+        fs::remove_file(&key_file).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_compiles() {
+        let cache = ModuleCache::new(Limits::default(), None);
+        // modules of different globals, compiled at once
+        let compiled: Vec<_> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|n| {
+                    let cache = &cache;
+                    scope.spawn(move || {
+                        let globals = "(global (mut i32) (i32.const 0))".repeat(n);
+                        let wat =
+                            format!("(module {globals} (func (export \"f\") (loop $l (br $l))))");
+                        cache.get(wat.as_bytes())
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        for (n, compiled) in compiled.into_iter().enumerate() {
+            let (engine, module) = compiled.unwrap();
+            let exports: Vec<_> = module
+                .exports()
+                .globals()
+                .map(|g| g.name().to_string())
+                .collect();
+            assert_eq!(exports.len(), 2, "module {n}");
+            // an engine compiles a single module
+            assert!(Module::new(&engine, "(module (func (export \"f\")))").is_err());
+        }
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// WASI capabilities of a driver
+//
+// Each driver entry of a registry may give its driver a policy, so drivers
+// only get the capabilities they need, and their configuration without being
+// rebuilt:
+//
+//   "policy": { "dirs": ["/state", "/data"], "env": { "UNITS": "si" },
+//               "args": ["--verbose"], "clock": true, "random": false,
+//               "network": false }
+//
+// - dirs: directories preopened for the driver, private to the end device
+//   (only with a state directory, see vdctrl): /state is <state_dir>/<addr>
+//   on the gateway, as before policies, and the others are subdirectories of
+//   it, e.g. /data is <state_dir>/<addr>/data (also /state/data);
+// - env, args: environment variables and arguments (after the name of the
+//   virtual device) of the driver;
+// - clock, random, network: whether the driver may read the clocks, get
+//   random bytes and use sockets. The host functions of the capabilities
+//   denied fail with ENOTCAPABLE.
+// Besides these, drivers only get the functions of the file descriptors and
+// preopened directories, their arguments and environment, polling, yielding
+// and exiting: the other host functions (e.g. the wasix ones spawning
+// processes or threads) are denied.
+// Drivers without a policy get the default one: /state only, clock and
+// random access, no networking.
+//
+// Policies of remote registries are not authenticated: networking, the
+// environment and the arguments, which could turn a trusted driver against
+// the gateway, are stripped from them unless the gateway grants them (see
+// Grants).
+//
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+// Where virtual devices find their state directory, by default
+pub const STATE_DIR: &str = "/state";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub dirs: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub args: Vec<String>,
+    pub clock: bool,
+    pub random: bool,
+    pub network: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            dirs: vec![STATE_DIR.to_string()],
+            env: BTreeMap::new(),
+            args: Vec::new(),
+            clock: true,
+            random: true,
+            network: false,
+        }
+    }
+}
+
+impl Policy {
+    // Checked when the policy is read, dirs become host paths
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(dir) = self.dirs.iter().find(|dir| relative(dir).is_none()) {
+            return Err(format!("bad directory {dir}"));
+        }
+        if let Some(key) = self
+            .env
+            .keys()
+            .find(|key| key.is_empty() || key.contains(['=', '\0']))
+        {
+            return Err(format!("bad environment variable {key}"));
+        }
+        Ok(())
+    }
+
+    // Host directory of each directory of the driver: /state is root itself
+    pub fn dirs(&self, root: &Path) -> Vec<(String, PathBuf)> {
+        let state = relative(STATE_DIR).expect("bad state directory");
+        self.dirs
+            .iter()
+            .filter_map(|dir| {
+                let path = relative(dir)?;
+                let path = path.strip_prefix(&state).unwrap_or(&path);
+                Some((dir.clone(), root.join(path)))
+            })
+            .collect()
+    }
+
+    // The policy without the capabilities not granted
+    pub fn capped(mut self, grants: Grants) -> Self {
+        self.network &= grants.network;
+        if !grants.env {
+            self.env.clear();
+        }
+        if !grants.args {
+            self.args.clear();
+        }
+        self
+    }
+
+    // Whether the driver may import a WASI function
+    pub fn allows(&self, function: &str) -> bool {
+        let prefixed = |prefixes: &[&str]| prefixes.iter().any(|p| function.starts_with(p));
+        if function.starts_with("clock_") {
+            self.clock
+        } else if function == "random_get" {
+            self.random
+        } else if prefixed(&["sock_", "port_", "resolve", "http_"]) {
+            self.network
+        } else {
+            prefixed(&["fd_", "path_", "args_", "environ_"])
+                || ["poll_oneoff", "sched_yield", "proc_exit", "proc_raise"].contains(&function)
+        }
+    }
+}
+
+// Capabilities remote registries may give their drivers, none by default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Grants {
+    pub network: bool,
+    pub env: bool,
+    pub args: bool,
+}
+
+impl FromStr for Grants {
+    type Err = String;
+
+    // Comma separated "network", "env" and "args"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut grants = Self::default();
+        for grant in s.split(',').filter(|g| !g.is_empty()) {
+            match grant {
+                "network" => grants.network = true,
+                "env" => grants.env = true,
+                "args" => grants.args = true,
+                _ => return Err(format!("bad grant {grant}")),
+            }
+        }
+        Ok(grants)
+    }
+}
+
+// "/data" as "data", if absolute and without "." nor ".."
+fn relative(dir: &str) -> Option<PathBuf> {
+    let mut components = Path::new(dir).components();
+    if components.next() != Some(Component::RootDir) {
+        return None;
+    }
+    let path: PathBuf = components
+        .map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirs() {
+        let policy = Policy {
+            dirs: ["/state", "/data", "/state/logs", "/../etc", "data"]
+                .map(String::from)
+                .to_vec(),
+            ..Policy::default()
+        };
+        assert!(policy.check().is_err());
+        let root = Path::new("/var/vd/00000001");
+        assert_eq!(
+            policy.dirs(root),
+            vec![
+                ("/state".to_string(), root.to_path_buf()),
+                ("/data".to_string(), root.join("data")),
+                ("/state/logs".to_string(), root.join("logs")),
+            ]
+        );
+    }
+
+    #[test]
+    fn grants() {
+        let policy = Policy {
+            env: BTreeMap::from([("A".to_string(), "1".to_string())]),
+            args: vec!["-v".to_string()],
+            network: true,
+            ..Policy::default()
+        };
+        let none = policy.clone().capped(Grants::default());
+        assert_eq!(
+            none,
+            Policy {
+                dirs: policy.dirs.clone(),
+                ..Policy::default()
+            }
+        );
+        let all = "network,env,args".parse().unwrap();
+        assert_eq!(policy.clone().capped(all), policy);
+        let env = policy.clone().capped("env".parse().unwrap());
+        assert_eq!((env.env.len(), env.args.len(), env.network), (1, 0, false));
+        assert!("network,disk".parse::<Grants>().is_err());
+    }
+
+    #[test]
+    fn allows() {
+        let policy = Policy::default();
+        for function in [
+            "fd_read",
+            "fd_write",
+            "path_open",
+            "args_get",
+            "environ_sizes_get",
+            "poll_oneoff",
+            "proc_exit",
+            "clock_time_get",
+            "random_get",
+        ] {
+            assert!(policy.allows(function), "{function}");
+        }
+        // processes, threads and the rest of wasix
+        for function in [
+            "proc_spawn",
+            "proc_exec",
+            "proc_exec2",
+            "proc_fork",
+            "proc_join",
+            "proc_signal",
+            "thread_spawn",
+            "thread_spawn_v2",
+            "futex_wait",
+            "tty_set",
+            "chdir",
+            "callback_signal",
+            "stack_restore",
+            "sock_open",
+            "resolve",
+            "",
+        ] {
+            assert!(!policy.allows(function), "{function}");
+        }
+
+        let policy = Policy {
+            clock: false,
+            random: false,
+            network: true,
+            ..Policy::default()
+        };
+        assert!(!policy.allows("clock_time_get"));
+        assert!(!policy.allows("random_get"));
+        assert!(policy.allows("sock_open") && policy.allows("port_route_add"));
+        assert!(!policy.allows("proc_spawn"));
+    }
+}
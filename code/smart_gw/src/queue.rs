@@ -0,0 +1,543 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Bounded queue of frames between the broker and a subscriber
+//
+// wasmer_wasix::Pipe buffers without limit, so subscribers read straight from
+// a queue of at most `capacity` frames instead: QueueReader is both a
+// blocking std::io::Read and a VirtualFile to be used as wasm stdin. When the
+// queue is full the overflow policy decides what to drop.
+//
+// The queues of the virtual devices are configured with
+// "<capacity>[:<overflow>]", overflow being "drop-oldest" (default),
+// "drop-newest", "block:<ms>" or "disconnect".
+//
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Read, SeekFrom};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use wasmer_wasix::{FsError, VirtualFile};
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    // make room by discarding the oldest queued frame
+    DropOldest,
+    // discard the frame being published
+    DropNewest,
+    // wait up to the timeout for room, then discard the frame being published
+    Block(Duration),
+    // remove the subscription
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            overflow: Overflow::DropOldest,
+        }
+    }
+}
+
+impl FromStr for QueueConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || Error::BadQueueConfig(s.to_string());
+        let (capacity, overflow) = s.split_once(':').unwrap_or((s, "drop-oldest"));
+        let capacity = match capacity.parse() {
+            Ok(0) | Err(_) => return Err(bad()),
+            Ok(capacity) => capacity,
+        };
+        let overflow = match overflow.split_once(':') {
+            None if overflow == "drop-oldest" => Overflow::DropOldest,
+            None if overflow == "drop-newest" => Overflow::DropNewest,
+            None if overflow == "disconnect" => Overflow::Disconnect,
+            Some(("block", ms)) => {
+                Overflow::Block(Duration::from_millis(ms.parse().map_err(|_| bad())?))
+            }
+            _ => return Err(bad()),
+        };
+        Ok(Self { capacity, overflow })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct QueueStats {
+    pub delivered: u64, // frames taken by the reader
+    pub dropped: u64,   // frames discarded on overflow
+    pub lag: usize,     // frames waiting in the queue
+    pub max_lag: usize,
+}
+
+// Outcome of a push that did not queue the frame
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushError {
+    Dropped,
+    // the queue overflowed under Overflow::Disconnect
+    Overflow,
+    // the reader is gone
+    Dead(io::ErrorKind),
+}
+
+struct State {
+    frames: VecDeque<Arc<[u8]>>,
+    current: Option<(Arc<[u8]>, usize)>, // frame being read, read offset
+    stats: QueueStats,
+    closed: bool,
+    reader_gone: bool,
+    waker: Option<Waker>,
+}
+
+impl State {
+    // Bytes ready to be read, refilling from the queue if needed
+    fn available(&mut self) -> Option<&[u8]> {
+        if matches!(self.current, Some((ref frame, offset)) if offset == frame.len()) {
+            self.current = None;
+        }
+        if self.current.is_none() {
+            let frame = self.frames.pop_front()?;
+            self.current = Some((frame, 0));
+            self.stats.delivered += 1;
+            self.stats.lag = self.frames.len();
+        }
+        self.current
+            .as_ref()
+            .map(|(frame, offset)| &frame[*offset..])
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let data = self.available()?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        if let Some((_, ref mut offset)) = self.current {
+            *offset += len;
+        }
+        Some(len)
+    }
+}
+
+pub struct Queue {
+    config: QueueConfig,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Queue {
+    pub fn new(config: QueueConfig) -> (Arc<Self>, QueueReader) {
+        let queue = Arc::new(Self {
+            config,
+            state: Mutex::new(State {
+                frames: VecDeque::with_capacity(config.capacity),
+                current: None,
+                stats: QueueStats::default(),
+                closed: false,
+                reader_gone: false,
+                waker: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let reader = QueueReader {
+            queue: queue.clone(),
+        };
+        (queue, reader)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    pub fn push(&self, frame: Arc<[u8]>) -> Result<(), PushError> {
+        let mut state = self.state.lock().unwrap();
+        if state.reader_gone {
+            return Err(PushError::Dead(io::ErrorKind::BrokenPipe));
+        }
+
+        let capacity = self.config.capacity;
+        if state.frames.len() >= capacity {
+            match self.config.overflow {
+                Overflow::DropOldest => {
+                    state.frames.pop_front();
+                    state.stats.dropped += 1;
+                }
+                Overflow::DropNewest => {
+                    state.stats.dropped += 1;
+                    return Err(PushError::Dropped);
+                }
+                Overflow::Block(timeout) => {
+                    state = self
+                        .changed
+                        .wait_timeout_while(state, timeout, |s| {
+                            s.frames.len() >= capacity && !s.reader_gone
+                        })
+                        .unwrap()
+                        .0;
+                    if state.frames.len() >= capacity {
+                        state.stats.dropped += 1;
+                        return Err(PushError::Dropped);
+                    }
+                }
+                Overflow::Disconnect => return Err(PushError::Overflow),
+            }
+        }
+
+        state.frames.push_back(frame);
+        state.stats.lag = state.frames.len();
+        state.stats.max_lag = state.stats.max_lag.max(state.stats.lag);
+        self.notify(&mut state);
+        Ok(())
+    }
+
+    // No more frames, the reader gets EOF once it drained the queue
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.notify(&mut state);
+    }
+
+    fn notify(&self, state: &mut State) {
+        self.changed.notify_all();
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Reading end of a queue, the stream of the queued frames
+pub struct QueueReader {
+    queue: Arc<Queue>,
+}
+
+impl QueueReader {
+    // Non-blocking read, registers the waker when there is nothing to read
+    fn poll_read_bytes(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<usize> {
+        let mut state = self.queue.state.lock().unwrap();
+        match state.read(buf) {
+            Some(len) => {
+                self.queue.changed.notify_all();
+                Poll::Ready(len)
+            }
+            None if state.closed => Poll::Ready(0),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for QueueReader {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.reader_gone = true;
+        state.frames.clear();
+        self.queue.changed.notify_all();
+    }
+}
+
+impl fmt::Debug for QueueReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueReader")
+            .field("config", &self.queue.config)
+            .field("stats", &self.queue.stats())
+            .finish()
+    }
+}
+
+impl Read for QueueReader {
+    // Blocks until a frame is queued, 0 once the queue is closed and drained
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(len) = state.read(buf) {
+                self.queue.changed.notify_all();
+                return Ok(len);
+            }
+            if state.closed {
+                return Ok(0);
+            }
+            state = self.queue.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl AsyncRead for QueueReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let read = self.poll_read_bytes(cx, buf.initialize_unfilled());
+        read.map(|len| {
+            buf.advance(len);
+            Ok(())
+        })
+    }
+}
+
+// Subscribers only read
+impl AsyncWrite for QueueReader {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for QueueReader {
+    fn start_seek(self: Pin<&mut Self>, _position: SeekFrom) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+impl VirtualFile for QueueReader {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn unlink(&mut self) -> Pin<Box<dyn Future<Output = Result<(), FsError>> + Send + 'static>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn is_open(&self) -> bool {
+        !self.queue.state.lock().unwrap().closed
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut state = self.queue.state.lock().unwrap();
+        match state.available().map(<[u8]>::len) {
+            Some(len) => Poll::Ready(Ok(len)),
+            None if state.closed => Poll::Ready(Ok(0)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+    use std::thread;
+
+    fn queue(capacity: usize, overflow: Overflow) -> (Arc<Queue>, QueueReader) {
+        Queue::new(QueueConfig { capacity, overflow })
+    }
+
+    fn frame(b: u8) -> Arc<[u8]> {
+        Arc::from(vec![b; 2])
+    }
+
+    // Frames left in the queue, read after closing it
+    fn drain(queue: &Queue, reader: &mut QueueReader) -> Vec<u8> {
+        queue.close();
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (queue, mut reader) = queue(2, Overflow::DropOldest);
+        for b in 1..=3 {
+            assert_eq!(queue.push(frame(b)), Ok(()));
+        }
+        let stats = queue.stats();
+        assert_eq!((stats.dropped, stats.lag, stats.max_lag), (1, 2, 2));
+        assert_eq!(drain(&queue, &mut reader), [2, 2, 3, 3]);
+        assert_eq!(queue.stats().delivered, 2);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (queue, mut reader) = queue(2, Overflow::DropNewest);
+        assert_eq!(queue.push(frame(1)), Ok(()));
+        assert_eq!(queue.push(frame(2)), Ok(()));
+        assert_eq!(queue.push(frame(3)), Err(PushError::Dropped));
+        assert_eq!(queue.stats().dropped, 1);
+        assert_eq!(drain(&queue, &mut reader), [1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn block() {
+        let (queue, mut reader) = queue(1, Overflow::Block(Duration::from_millis(20)));
+        assert_eq!(queue.push(frame(1)), Ok(()));
+        // nobody reads: dropped after the timeout
+        assert_eq!(queue.push(frame(2)), Err(PushError::Dropped));
+
+        // room made by the reader meanwhile
+        let mut buf = [0; 2];
+        let pushed = thread::scope(|s| {
+            let push = s.spawn(|| queue.push(frame(3)));
+            thread::sleep(Duration::from_millis(5));
+            reader.read_exact(&mut buf).unwrap();
+            push.join().unwrap()
+        });
+        assert_eq!((pushed, buf), (Ok(()), [1, 1]));
+        assert_eq!(drain(&queue, &mut reader), [3, 3]);
+        assert_eq!(queue.stats().dropped, 1);
+    }
+
+    #[test]
+    fn block_until_reader_gone() {
+        let (queue, reader) = queue(1, Overflow::Block(Duration::from_secs(60)));
+        assert_eq!(queue.push(frame(1)), Ok(()));
+        let pushed = thread::scope(|s| {
+            let push = s.spawn(|| queue.push(frame(2)));
+            thread::sleep(Duration::from_millis(5));
+            drop(reader);
+            push.join().unwrap()
+        });
+        // the dropped reader wakes the publisher, which finds room
+        assert!(pushed.is_ok());
+        assert_eq!(
+            queue.push(frame(3)),
+            Err(PushError::Dead(io::ErrorKind::BrokenPipe))
+        );
+    }
+
+    #[test]
+    fn disconnect() {
+        let (queue, mut reader) = queue(1, Overflow::Disconnect);
+        assert_eq!(queue.push(frame(1)), Ok(()));
+        assert_eq!(queue.push(frame(2)), Err(PushError::Overflow));
+        // the broker then closes the queue
+        assert_eq!(drain(&queue, &mut reader), [1, 1]);
+    }
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn virtual_file() {
+        let (queue, mut reader) = queue(4, Overflow::DropOldest);
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut reader = Pin::new(&mut reader);
+        assert!(reader.as_mut().poll_read_ready(&mut cx).is_pending());
+        queue.push(Arc::from(&b"hello"[..])).unwrap();
+        assert!(flag.0.load(Ordering::SeqCst), "not woken");
+        assert!(matches!(
+            reader.as_mut().poll_read_ready(&mut cx),
+            Poll::Ready(Ok(5))
+        ));
+
+        // frames are read in parts
+        let mut buf = [0; 3];
+        let mut read = ReadBuf::new(&mut buf);
+        assert!(reader.as_mut().poll_read(&mut cx, &mut read).is_ready());
+        assert_eq!(read.filled(), b"hel");
+        let mut read = ReadBuf::new(&mut buf);
+        assert!(reader.as_mut().poll_read(&mut cx, &mut read).is_ready());
+        assert_eq!(read.filled(), b"lo");
+
+        let mut read = ReadBuf::new(&mut buf);
+        assert!(reader.as_mut().poll_read(&mut cx, &mut read).is_pending());
+        assert!(reader.is_open());
+        queue.close();
+        assert!(!reader.is_open());
+        // end of file
+        assert!(matches!(
+            reader.as_mut().poll_read_ready(&mut cx),
+            Poll::Ready(Ok(0))
+        ));
+        let mut read = ReadBuf::new(&mut buf);
+        assert!(reader.as_mut().poll_read(&mut cx, &mut read).is_ready());
+        assert!(read.filled().is_empty());
+    }
+
+    #[test]
+    fn config() {
+        let config = |s: &str| s.parse::<QueueConfig>().map(|c| (c.capacity, c.overflow));
+        assert_eq!(config("16").unwrap(), (16, Overflow::DropOldest));
+        assert_eq!(config("8:drop-newest").unwrap(), (8, Overflow::DropNewest));
+        assert_eq!(
+            config("4:block:50").unwrap(),
+            (4, Overflow::Block(Duration::from_millis(50)))
+        );
+        assert_eq!(config("1:disconnect").unwrap(), (1, Overflow::Disconnect));
+        for bad in [
+            "",
+            "0",
+            "x",
+            "4:block",
+            "4:block:x",
+            "4:drop",
+            "4:disconnect:1",
+        ] {
+            assert!(config(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Reassembly of fragmented messages, buffers of incomplete messages are
+// dropped once they outlive the timeout, or when their device starts more than
+// MAX_PENDING messages at once (the oldest one goes). MAX_PENDING_TOTAL bounds
+// the buffers of all devices, so that fragments from many (possibly spoofed)
+// addresses cannot exhaust the memory of the gateway.
+
+use msg::frag::{Assembly, Fragment};
+use msg::Msg;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Incomplete messages buffered per device
+pub const MAX_PENDING: usize = 4;
+
+// Incomplete messages buffered overall
+pub const MAX_PENDING_TOTAL: usize = 1024;
+
+struct Pending {
+    started: Instant,
+    confirmed: bool, // any fragment asked for an Ack
+    assembly: Assembly,
+}
+
+pub struct Reassembler {
+    timeout: Duration,
+    pending: HashMap<(u64, u32), Pending>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Returns the whole message once reassembled, and whether it expects an Ack
+    pub fn push(&mut self, frag: Fragment, confirmed: bool) -> Option<(Msg, bool)> {
+        self.expire();
+
+        let key = (frag.addr, frag.fcnt);
+        if !self.pending.contains_key(&key) {
+            self.evict(frag.addr);
+            self.evict_oldest();
+        }
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            started: Instant::now(),
+            confirmed: false,
+            assembly: Assembly::new(&frag),
+        });
+        pending.confirmed |= confirmed;
+
+        let msg = pending.assembly.push(frag)?;
+        let confirmed = pending.confirmed;
+        self.pending.remove(&key);
+        Some((msg, confirmed))
+    }
+
+    // Make room for a new message of addr
+    fn evict(&mut self, addr: u64) {
+        let mut started: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|((a, _), _)| *a == addr)
+            .map(|(&key, pending)| (pending.started, key))
+            .collect();
+        if started.len() < MAX_PENDING {
+            return;
+        }
+        started.sort_unstable();
+        for (_, key) in &started[..=started.len() - MAX_PENDING] {
+            println!(
+                "[reasm] drop incomplete msg {:08x}/{} (too many).",
+                key.0, key.1
+            );
+            self.pending.remove(key);
+        }
+    }
+
+    // Make room for a new message of any device
+    fn evict_oldest(&mut self) {
+        if self.pending.len() < MAX_PENDING_TOTAL {
+            return;
+        }
+        let oldest = self
+            .pending
+            .iter()
+            .map(|(&key, pending)| (pending.started, key))
+            .min()
+            .map(|(_, key)| key);
+        if let Some(key) = oldest {
+            println!(
+                "[reasm] drop incomplete msg {:08x}/{} (too many overall).",
+                key.0, key.1
+            );
+            self.pending.remove(&key);
+        }
+    }
+
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        self.pending.retain(|(addr, fcnt), pending| {
+            let alive = pending.started.elapsed() < timeout;
+            if !alive {
+                println!("[reasm] drop incomplete msg {addr:08x}/{fcnt} (timeout).");
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msg::frag;
+
+    // Fragments of a message of addr
+    fn fragments(addr: u64, fcnt: u32) -> Vec<Fragment> {
+        let msg = Msg {
+            addr,
+            fcnt,
+            port: 1,
+            payload: vec![fcnt as u8; 32],
+        };
+        frag::fragment(&msg, 16, 1).unwrap()
+    }
+
+    fn reassembler() -> Reassembler {
+        Reassembler::new(Duration::from_secs(60))
+    }
+
+    #[test]
+    fn reassembly() {
+        let mut reasm = reassembler();
+        let mut frags = fragments(1, 1).into_iter();
+        assert!(reasm.push(frags.next().unwrap(), true).is_none());
+        let (msg, confirmed) = frags.find_map(|f| reasm.push(f, false)).unwrap();
+        assert_eq!(msg.payload, vec![1; 32]);
+        assert!(confirmed);
+        assert!(reasm.pending.is_empty());
+    }
+
+    #[test]
+    fn timeout() {
+        let mut reasm = Reassembler::new(Duration::ZERO);
+        assert!(reasm.push(fragments(1, 1).remove(0), false).is_none());
+        assert_eq!(reasm.pending.len(), 1);
+        reasm.expire();
+        assert!(reasm.pending.is_empty());
+    }
+
+    #[test]
+    fn max_pending() {
+        let mut reasm = reassembler();
+        for fcnt in 0..MAX_PENDING as u32 + 2 {
+            reasm.push(fragments(1, fcnt).remove(0), false);
+        }
+        reasm.push(fragments(2, 0).remove(0), false);
+        assert_eq!(reasm.pending.len(), MAX_PENDING + 1);
+        // the oldest ones went
+        assert!(!reasm.pending.contains_key(&(1, 1)));
+        assert!(reasm.pending.contains_key(&(1, 2)));
+    }
+
+    #[test]
+    fn max_pending_total() {
+        let mut reasm = reassembler();
+        for addr in 0..MAX_PENDING_TOTAL as u64 + 10 {
+            reasm.push(fragments(addr, 0).remove(0), false);
+        }
+        assert_eq!(reasm.pending.len(), MAX_PENDING_TOTAL);
+        assert!(!reasm.pending.contains_key(&(9, 0)));
+        assert!(reasm.pending.contains_key(&(10, 0)));
+
+        // the messages left still complete
+        let frags = fragments(10, 0);
+        let (msg, _) = frags
+            .into_iter()
+            .find_map(|f| reasm.push(f, false))
+            .unwrap();
+        assert_eq!(msg.addr, 10);
+    }
+}
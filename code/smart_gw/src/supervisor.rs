@@ -0,0 +1,365 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Supervision of long running tasks, i.e. the virtual devices
+//
+// A task runs in its own thread until it returns, fails or panics (a wasm
+// trap included). Failures and panics are crashes: crashed tasks are
+// restarted after an exponential backoff, and disabled after too many
+// consecutive crashes. A task returning cleanly (e.g. a virtual device whose
+// stdin was closed by the broker) is restarted after the initial backoff,
+// without counting as a crash. Tasks stopped on purpose are not restarted:
+// once running, they register how to be stopped. Every state change is
+// reported to a watcher.
+//
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RestartPolicy {
+    // consecutive crashes after which the task is disabled
+    pub max_crashes: u32,
+    // wait before the first restart, doubled at each consecutive crash
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // a task running this long is healthy again, its crashes are forgotten
+    pub healthy_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_crashes: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            healthy_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RestartPolicy {
+    fn backoff(&self, crashes: u32) -> Duration {
+        let factor = 2u32.saturating_pow(crashes.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    // being set up, e.g. loading and compiling its wasm module
+    Starting,
+    Running,
+    // waiting for the backoff before a restart
+    Crashed,
+    // returned without error, waiting for the initial backoff before a restart
+    Exited,
+    // crashed too many times, not restarted anymore
+    Disabled,
+    // stopped on purpose
+    Stopped,
+}
+
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub state: State,
+    pub crashes: u32, // consecutive
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+type Stopper = Box<dyn FnOnce() + Send>;
+type Watcher = Box<dyn Fn(&Status) + Send + Sync>;
+
+struct Control {
+    stopping: bool,
+    stopper: Option<Stopper>,
+}
+
+struct Shared {
+    status: Mutex<Status>,
+    control: Mutex<Control>,
+    stopped: Condvar,
+    watcher: Watcher,
+}
+
+impl Shared {
+    fn set_state(&self, status: &mut Status, state: State) {
+        status.state = state;
+        (self.watcher)(status);
+    }
+}
+
+// Given to the task to report its progress
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    // The task is set up (e.g. its module compiled), stop makes it return
+    pub fn running<F>(&self, stop: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut status = self.shared.status.lock().unwrap();
+        self.shared.set_state(&mut status, State::Running);
+        drop(status);
+        let mut control = self.shared.control.lock().unwrap();
+        if control.stopping {
+            stop()
+        } else {
+            control.stopper = Some(Box::new(stop));
+        }
+    }
+}
+
+pub struct Supervisor {
+    shared: Arc<Shared>,
+    thread: JoinHandle<()>,
+}
+
+impl Supervisor {
+    pub fn spawn<F, W>(name: String, policy: RestartPolicy, mut task: F, watcher: W) -> Self
+    where
+        F: FnMut(&Handle) -> Result<(), String> + Send + 'static,
+        W: Fn(&Status) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            status: Mutex::new(Status {
+                state: State::Starting,
+                crashes: 0,
+                restarts: 0,
+                last_error: None,
+            }),
+            control: Mutex::new(Control {
+                stopping: false,
+                stopper: None,
+            }),
+            stopped: Condvar::new(),
+            watcher: Box::new(watcher),
+        });
+        let handle = Handle {
+            shared: shared.clone(),
+        };
+        let thread = thread::spawn(move || loop {
+            let started = Instant::now();
+            let error = match panic::catch_unwind(AssertUnwindSafe(|| task(&handle))) {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e),
+                Err(payload) => Some(panic_message(payload)),
+            };
+
+            let mut control = handle.shared.control.lock().unwrap();
+            control.stopper = None;
+            let shared = &handle.shared;
+            let mut status = shared.status.lock().unwrap();
+            if control.stopping {
+                shared.set_state(&mut status, State::Stopped);
+                break;
+            }
+            if started.elapsed() >= policy.healthy_after {
+                status.crashes = 0;
+            }
+            let backoff = match error {
+                // a clean exit ends the run of consecutive crashes
+                None => {
+                    status.crashes = 0;
+                    shared.set_state(&mut status, State::Exited);
+                    let backoff = policy.backoff(0);
+                    println!("[supervisor] {name} exited, restart in {backoff:?}.");
+                    backoff
+                }
+                Some(error) => {
+                    status.crashes += 1;
+                    status.last_error = Some(error.clone());
+                    if status.crashes >= policy.max_crashes {
+                        shared.set_state(&mut status, State::Disabled);
+                        println!(
+                            "[supervisor] {name} disabled after {} crashes: {error}",
+                            status.crashes
+                        );
+                        break;
+                    }
+                    shared.set_state(&mut status, State::Crashed);
+                    let backoff = policy.backoff(status.crashes);
+                    println!("[supervisor] {name} crashed: {error}, restart in {backoff:?}.");
+                    backoff
+                }
+            };
+            drop(status);
+
+            let (control, _) = shared
+                .stopped
+                .wait_timeout_while(control, backoff, |c| !c.stopping)
+                .unwrap();
+            let mut status = shared.status.lock().unwrap();
+            if control.stopping {
+                shared.set_state(&mut status, State::Stopped);
+                break;
+            }
+            status.restarts += 1;
+            shared.set_state(&mut status, State::Starting);
+        });
+        Self { shared, thread }
+    }
+
+    pub fn status(&self) -> Status {
+        self.shared.status.lock().unwrap().clone()
+    }
+
+    // Stop the task without waiting for it to return, it is not restarted
+    pub fn stop(&self) {
+        let mut control = self.shared.control.lock().unwrap();
+        control.stopping = true;
+        if let Some(stop) = control.stopper.take() {
+            stop()
+        }
+        self.shared.stopped.notify_all();
+    }
+
+    // Stop the task and wait for it to return
+    pub fn join(self) {
+        self.stop();
+        // the task panicking is caught, only the watcher may
+        if self.thread.join().is_err() {
+            println!("[supervisor] watcher panicked.");
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => format!("panicked: {s}"),
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(s) => format!("panicked: {s}"),
+            Err(_) => "panicked".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: RestartPolicy = RestartPolicy {
+        max_crashes: 3,
+        backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+        healthy_after: Duration::from_secs(60),
+    };
+
+    // Runs a task returning the given results in turn, then stops it
+    fn supervise(results: Vec<Result<(), String>>) -> Status {
+        let count = results.len();
+        let mut results = results.into_iter();
+        let (runs, ran) = std::sync::mpsc::channel();
+        let supervisor = Supervisor::spawn(
+            "test".to_string(),
+            POLICY,
+            move |_| {
+                let result = results.next().unwrap_or(Ok(()));
+                runs.send(()).unwrap();
+                result
+            },
+            |_| (),
+        );
+        for _ in 0..count {
+            ran.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(20));
+        let status = supervisor.status();
+        supervisor.stop();
+        status
+    }
+
+    #[test]
+    fn crashes_disable() {
+        let status = supervise(vec![Err("a".into()), Err("b".into()), Err("c".into())]);
+        assert_eq!(status.state, State::Disabled);
+        assert_eq!(status.crashes, 3);
+        assert_eq!(status.last_error.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn clean_exits_are_not_crashes() {
+        let status = supervise(vec![
+            Err("a".into()),
+            Ok(()),
+            Err("b".into()),
+            Ok(()),
+            Ok(()),
+        ]);
+        assert_ne!(status.state, State::Disabled);
+        assert!(status.restarts >= 5);
+    }
+
+    #[test]
+    fn clean_exit_resets_crashes() {
+        let mut results =
+            vec![Err("a".into()), Ok(()), Err("b".into()), Err("c".into())].into_iter();
+        let (crashes, crashed) = std::sync::mpsc::channel();
+        let supervisor = Supervisor::spawn(
+            "test".to_string(),
+            POLICY,
+            // then blocks until stopped
+            move |h| match results.next() {
+                Some(result) => result,
+                None => {
+                    let (stop, stopped) = std::sync::mpsc::channel();
+                    h.running(move || stop.send(()).unwrap());
+                    stopped.recv().unwrap();
+                    Ok(())
+                }
+            },
+            move |status| {
+                if status.state == State::Crashed {
+                    crashes.send(status.crashes).unwrap();
+                }
+            },
+        );
+        let crashes: Vec<_> = (0..3)
+            .map(|_| crashed.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(crashes, [1, 1, 2]);
+        supervisor.join();
+    }
+
+    #[test]
+    fn join_waits_for_the_task() {
+        let (returned, has_returned) = std::sync::mpsc::channel();
+        let (running, is_running) = std::sync::mpsc::channel();
+        let supervisor = Supervisor::spawn(
+            "test".to_string(),
+            POLICY,
+            move |h| {
+                let (stop, stopped) = std::sync::mpsc::channel();
+                h.running(move || stop.send(()).unwrap());
+                running.send(()).unwrap();
+                stopped.recv().unwrap();
+                // slow to return once stopped
+                thread::sleep(Duration::from_millis(50));
+                returned.send(()).unwrap();
+                Ok(())
+            },
+            |_| (),
+        );
+        is_running.recv_timeout(Duration::from_secs(5)).unwrap();
+        supervisor.join();
+        assert!(has_returned.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Hierarchical topics with MQTT-style wildcards
+//
+// Sources:
+//  - [MQTT Version 3.1.1, Sec. 4.7]
+//
+// Topics are '/' separated levels, e.g. "uplink/00000001/1". In filters '+'
+// matches exactly one level and '#', only allowed as the last level, matches
+// any number of levels (including none, so "uplink/#" matches "uplink").
+// Filters are stored in a trie with one node per level, so matching a topic
+// only walks the branches that can match it.
+//
+
+use std::collections::HashMap;
+
+use crate::{Error, Result};
+
+pub const SEP: char = '/';
+pub const ANY_LEVEL: &str = "+";
+pub const ANY_LEVELS: &str = "#";
+
+pub fn validate_filter(filter: &str) -> Result<()> {
+    let bad = || Err(Error::BadTopicFilter(filter.to_string()));
+    let levels: Vec<&str> = filter.split(SEP).collect();
+    for (i, level) in levels.iter().enumerate() {
+        let wildcard = level.contains(['+', '#']);
+        match *level {
+            ANY_LEVEL => (),
+            ANY_LEVELS if i == levels.len() - 1 => (),
+            _ if wildcard => return bad(),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    values: Vec<T>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Self {
+            children: HashMap::new(),
+            values: Vec::new(),
+        }
+    }
+
+    fn visit_mut<F>(&mut self, levels: &[&str], f: &mut F)
+    where
+        F: FnMut(&mut T),
+    {
+        if let Some(node) = self.children.get_mut(ANY_LEVELS) {
+            node.values.iter_mut().for_each(&mut *f);
+        }
+        match levels.split_first() {
+            None => self.values.iter_mut().for_each(f),
+            Some((level, rest)) => {
+                if let Some(node) = self.children.get_mut(*level) {
+                    node.visit_mut(rest, f);
+                }
+                if let Some(node) = self.children.get_mut(ANY_LEVEL) {
+                    node.visit_mut(rest, f);
+                }
+            }
+        }
+    }
+
+    fn retain<F>(&mut self, f: &mut F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.values.retain(|v| f(v));
+        self.children.retain(|_, node| {
+            node.retain(f);
+            !node.values.is_empty() || !node.children.is_empty()
+        });
+    }
+
+    fn remove_if<F>(&mut self, levels: &[&str], f: &mut F) -> usize
+    where
+        F: FnMut(&T) -> bool,
+    {
+        match levels.split_first() {
+            None => {
+                let len = self.values.len();
+                self.values.retain(|v| !f(v));
+                len - self.values.len()
+            }
+            Some((level, rest)) => {
+                let Some(node) = self.children.get_mut(*level) else {
+                    return 0;
+                };
+                let removed = node.remove_if(rest, f);
+                if node.values.is_empty() && node.children.is_empty() {
+                    self.children.remove(*level);
+                }
+                removed
+            }
+        }
+    }
+
+    fn for_each<F>(&self, f: &mut F)
+    where
+        F: FnMut(&T),
+    {
+        self.values.iter().for_each(&mut *f);
+        self.children.values().for_each(|node| node.for_each(f));
+    }
+
+    fn len(&self) -> usize {
+        self.values.len() + self.children.values().map(Node::len).sum::<usize>()
+    }
+}
+
+// Values subscribed with topic filters
+pub struct TopicTrie<T> {
+    root: Node<T>,
+}
+
+impl<T> Default for TopicTrie<T> {
+    fn default() -> Self {
+        Self { root: Node::new() }
+    }
+}
+
+impl<T> TopicTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, filter: &str, value: T) -> Result<()> {
+        validate_filter(filter)?;
+        let node = filter.split(SEP).fold(&mut self.root, |node, level| {
+            node.children
+                .entry(level.to_string())
+                .or_insert_with(Node::new)
+        });
+        node.values.push(value);
+        Ok(())
+    }
+
+    // Call f on every value whose filter matches the topic
+    pub fn visit_mut<F>(&mut self, topic: &str, mut f: F)
+    where
+        F: FnMut(&mut T),
+    {
+        let levels: Vec<&str> = topic.split(SEP).collect();
+        self.root.visit_mut(&levels, &mut f)
+    }
+
+    // Remove the values subscribed with filter for which f is true,
+    // returns how many were removed
+    pub fn remove_if<F>(&mut self, filter: &str, mut f: F) -> usize
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let levels: Vec<&str> = filter.split(SEP).collect();
+        self.root.remove_if(&levels, &mut f)
+    }
+
+    // Keep only the values for which f is true, pruning empty branches
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.root.retain(&mut f)
+    }
+
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&T),
+    {
+        self.root.for_each(&mut f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(filters: &[&str], topic: &str) -> Vec<usize> {
+        let mut trie = TopicTrie::new();
+        for (i, filter) in filters.iter().enumerate() {
+            trie.insert(filter, i).unwrap();
+        }
+        let mut matched = Vec::new();
+        trie.visit_mut(topic, |i| matched.push(*i));
+        matched.sort_unstable();
+        matched
+    }
+
+    #[test]
+    fn exact() {
+        let filters = ["uplink/00000001/1", "uplink/00000001/2"];
+        assert_eq!(matches(&filters, "uplink/00000001/1"), [0]);
+        assert_eq!(matches(&filters, "uplink/00000001"), [] as [usize; 0]);
+        assert_eq!(matches(&filters, "uplink/00000001/1/x"), [] as [usize; 0]);
+    }
+
+    #[test]
+    fn any_level() {
+        let filters = ["uplink/+/1", "+/00000001/+", "uplink/+"];
+        assert_eq!(matches(&filters, "uplink/00000001/1"), [0, 1]);
+        assert_eq!(matches(&filters, "uplink/00000002/1"), [0]);
+        assert_eq!(matches(&filters, "ack/00000001/3"), [1]);
+        assert_eq!(matches(&filters, "uplink/00000001"), [2]);
+        assert_eq!(matches(&filters, "uplink"), [] as [usize; 0]);
+    }
+
+    #[test]
+    fn any_levels() {
+        let filters = ["uplink/#", "#", "out/+/downlink/#"];
+        assert_eq!(matches(&filters, "uplink/00000001/1"), [0, 1]);
+        // '#' also matches its parent level
+        assert_eq!(matches(&filters, "uplink"), [0, 1]);
+        assert_eq!(matches(&filters, "gwctrl"), [1]);
+        assert_eq!(matches(&filters, "out/00000001/downlink"), [1, 2]);
+        assert_eq!(
+            matches(&filters, "out/00000001/downlink/00000001/2"),
+            [1, 2]
+        );
+        assert_eq!(matches(&filters, "out/00000001/readings/00000001"), [1]);
+    }
+
+    #[test]
+    fn bad_filters() {
+        for filter in ["uplink/#/1", "uplink/0000+", "uplink/#x", "##"] {
+            assert!(validate_filter(filter).is_err(), "{filter}");
+        }
+        for filter in ["#", "+", "+/+/#", "uplink/00000001/1"] {
+            assert!(validate_filter(filter).is_ok(), "{filter}");
+        }
+    }
+
+    #[test]
+    fn remove_and_prune() {
+        let mut trie = TopicTrie::new();
+        trie.insert("uplink/+/1", 1).unwrap();
+        trie.insert("uplink/+/1", 2).unwrap();
+        trie.insert("ack/#", 3).unwrap();
+        assert_eq!(trie.remove_if("uplink/+/1", |v| *v == 1), 1);
+        assert_eq!(trie.len(), 2);
+        trie.retain(|v| *v != 3);
+        assert_eq!(trie.len(), 1);
+        assert!(!trie.root.children.contains_key("ack"));
+    }
+}
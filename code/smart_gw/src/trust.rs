@@ -0,0 +1,187 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Verification of the wasm driver modules before they run
+//
+// Drivers are published by community members for each other's gateways: a
+// module only runs if it matches the SHA-256 hash announced by its registry,
+// if any, and, when the gateway has a list of trusted publishers, if it
+// carries an Ed25519 signature of one of them. Signatures are detached, in
+// hex, in <module>.sig next to the module. The publishers file lists one
+// "<name> <public key>" pair per line (lines starting with '#' are ignored,
+// a name may only be listed once):
+//
+//   # name  public key
+//   alice   fecdd0ee61f017754a347e54015f0645aa7c29485415964fded75a3793a77b44
+//
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use msg::crypto::parse_key_table;
+use sha2::{Digest, Sha256};
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Result};
+
+pub fn module_hash(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+pub fn verify_hash(bytes: &[u8], expected: &str) -> Result<()> {
+    let hash = module_hash(bytes);
+    if !hash.eq_ignore_ascii_case(expected) {
+        return Err(Error::ModuleRefused(format!(
+            "hash {hash} instead of {expected}"
+        )));
+    }
+    Ok(())
+}
+
+// Where the signature of a module is found
+pub fn signature_path(module: &Path) -> PathBuf {
+    let mut path = OsString::from(module);
+    path.push(".sig");
+    path.into()
+}
+
+pub fn sign(key: &SigningKey, bytes: &[u8]) -> String {
+    hex::encode(key.sign(bytes).to_bytes())
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Publishers {
+    keys: Vec<(String, VerifyingKey)>,
+}
+
+impl Publishers {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(Error::Io)?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let keys = parse_key_table(text, |name, key| {
+            let key = VerifyingKey::from_bytes(&decode_array(key)?).ok()?;
+            Some((name.to_string(), key))
+        })
+        .map_err(Error::Msg)?;
+        Ok(Self {
+            keys: keys.into_iter().collect(),
+        })
+    }
+
+    // Name of the trusted publisher whose signature the module carries
+    pub fn verify(&self, bytes: &[u8], signature: Option<&str>) -> Result<&str> {
+        let refused = |why: &str| Error::ModuleRefused(why.to_string());
+        let signature = signature.ok_or_else(|| refused("unsigned"))?;
+        let signature = decode_array(signature.trim()).ok_or_else(|| refused("bad signature"))?;
+        let signature = Signature::from_bytes(&signature);
+        self.keys
+            .iter()
+            .find(|(_, key)| key.verify(bytes, &signature).is_ok())
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(|| refused("not signed by a trusted publisher"))
+    }
+}
+
+pub(crate) fn decode_array<const N: usize>(s: &str) -> Option<[u8; N]> {
+    hex::decode(s).ok()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn publishers(keys: &[(&str, &SigningKey)]) -> Publishers {
+        let text: String = keys
+            .iter()
+            .map(|(name, key)| format!("{name} {}\n", hex::encode(key.verifying_key().as_bytes())))
+            .collect();
+        Publishers::parse(&format!("# name key\n\n{text}")).unwrap()
+    }
+
+    #[test]
+    fn signed() {
+        let (alice, bob) = (key(1), key(2));
+        let publishers = publishers(&[("alice", &alice), ("bob", &bob)]);
+        let signature = sign(&bob, MODULE);
+        assert_eq!(publishers.verify(MODULE, Some(&signature)).unwrap(), "bob");
+        // as read from a .sig file
+        let signature = format!("{}\n", sign(&alice, MODULE));
+        assert_eq!(
+            publishers.verify(MODULE, Some(&signature)).unwrap(),
+            "alice"
+        );
+    }
+
+    #[test]
+    fn refused() {
+        let publishers = publishers(&[("alice", &key(1))]);
+        let refused = |result: Result<&str>| matches!(result, Err(Error::ModuleRefused(_)));
+        assert!(refused(publishers.verify(MODULE, None)));
+        assert!(refused(publishers.verify(MODULE, Some("not hex"))));
+        // signed by someone else
+        let signature = sign(&key(2), MODULE);
+        assert!(refused(publishers.verify(MODULE, Some(&signature))));
+        // signed, then tampered with
+        let signature = sign(&key(1), MODULE);
+        assert!(refused(
+            publishers.verify(b"\0asm\x01\0\0\x01", Some(&signature))
+        ));
+        assert!(refused(
+            Publishers::default().verify(MODULE, Some(&signature))
+        ));
+    }
+
+    #[test]
+    fn hashes() {
+        let hash = module_hash(MODULE);
+        assert_eq!(hash.len(), 64);
+        assert!(verify_hash(MODULE, &hash).is_ok());
+        assert!(verify_hash(MODULE, &hash.to_uppercase()).is_ok());
+        assert!(matches!(
+            verify_hash(b"\0asm\x01\0\0\x01", &hash),
+            Err(Error::ModuleRefused(_))
+        ));
+        assert_eq!(
+            signature_path(Path::new("/cache/driver.wasm")),
+            Path::new("/cache/driver.wasm.sig")
+        );
+    }
+
+    #[test]
+    fn bad_publishers() {
+        let key = hex::encode(key(1).verifying_key().as_bytes());
+        for (text, line) in [
+            (format!("alice {key} extra"), 1),
+            (format!("alice\n{key}"), 1),
+            (format!("# short key\nalice {}", &key[2..]), 2),
+            (format!("alice {key}\nalice {key}"), 2),
+        ] {
+            match Publishers::parse(&text) {
+                Err(Error::Msg(msg::Error::BadKeyTable(n))) => assert_eq!(n, line, "{text}"),
+                other => panic!("{text}: {other:?}"),
+            }
+        }
+    }
+}
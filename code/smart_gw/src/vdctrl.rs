@@ -13,66 +13,829 @@
 // limitations under the License.
 //
 
-use crate::broker::Broker;
+// Control of the virtual devices: a wasm driver per end device, reading the
+// uplinks of its end device from stdin and writing framed messages (readings,
+// events, downlinks, commands) to stdout, which are published back on its
+// output topics (see demux::output_topic). Virtual devices that crash are
+// restarted by their supervisor. Idle virtual devices, and the least recently
+// used beyond a maximum, are stopped to bound memory, and instantiated again
+// on their next uplink. The control plane tracks the activity of the end
+// devices, and manages their virtual devices on GwCtrl commands. The driver
+// of each end device is found by service discovery when its virtual device
+// starts, so a failed lookup is a crash retried with backoff, as a module
+// refused by verification (see trust). Compiled modules are shared by the
+// virtual devices running the same driver (see modcache). Virtual devices
+// exceeding their CPU, memory or time limits are terminated (see limits).
+// Drivers only get the WASI capabilities of their policy (see policy).
+//
+
+use crate::broker::{Broker, Subscription};
+use crate::demux;
+use crate::discovery::{Service, ServiceDiscovery};
+use crate::limits::{self, Limits};
+use crate::modcache::ModuleCache;
+use crate::queue::QueueConfig;
+use crate::supervisor::{Handle, RestartPolicy, State, Status, Supervisor};
+use crate::trust::{self, Publishers};
+use crate::{Error, Result};
 
-use std::collections::HashMap;
-use std::io::{BufReader, Read};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use wasmer::{Module, Store};
+use msg::message::{self, GwCmd, Message, MessageRef};
+use msg::stream::FrameReader;
+use msg::uplink::RxMeta;
+use tokio::runtime::Runtime;
+use wasmer::Store;
+use wasmer_wasix::virtual_fs::host_fs;
 use wasmer_wasix::{Pipe, WasiEnv};
 
+// Topics of the control plane
+const UPLINKS: &str = "uplink/#";
+const GW_CTRL: &str = "gwctrl";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub restart: RestartPolicy,
+    // virtual devices without uplinks for this long are stopped
+    pub idle_timeout: Duration,
+    // most virtual devices running at once, the least recently used are
+    // stopped beyond
+    pub max_instances: usize,
+    // if set, each virtual device finds the directories of its policy, e.g.
+    // <state_dir>/<addr> at /state, kept across evictions and restarts
+    pub state_dir: Option<PathBuf>,
+    // if set, only modules signed by these publishers run
+    pub publishers: Option<Arc<Publishers>>,
+    // if set, compiled modules are kept there across gateway restarts
+    pub module_cache: Option<PathBuf>,
+    // CPU, memory and time limits of each virtual device
+    pub limits: Limits,
+    // stdin queue of each virtual device
+    pub queue: QueueConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            restart: RestartPolicy::default(),
+            idle_timeout: Duration::from_secs(600),
+            max_instances: 256,
+            state_dir: None,
+            publishers: None,
+            module_cache: None,
+            limits: Limits::default(),
+            queue: QueueConfig::default(),
+        }
+    }
+}
+
+// Activity of an end device, as seen from its uplinks
+#[derive(Clone, Debug)]
+pub struct DevStats {
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub uplinks: u64,
+    // smoothed time between two uplinks
+    pub interval: Option<Duration>,
+    // smoothed link quality
+    pub rssi: f32, // in dBm
+    pub snr: f32,  // in dB
+}
+
+impl DevStats {
+    fn new(now: Instant, meta: &RxMeta) -> Self {
+        Self {
+            first_seen: now,
+            last_seen: now,
+            uplinks: 1,
+            interval: None,
+            rssi: meta.rssi as f32,
+            snr: meta.snr as f32,
+        }
+    }
+
+    fn update(&mut self, now: Instant, meta: &RxMeta) {
+        let interval = now.duration_since(self.last_seen);
+        self.interval = Some(match self.interval {
+            Some(avg) => avg.mul_f32(1.0 - SMOOTHING) + interval.mul_f32(SMOOTHING),
+            None => interval,
+        });
+        self.rssi += (meta.rssi as f32 - self.rssi) * SMOOTHING;
+        self.snr += (meta.snr as f32 - self.snr) * SMOOTHING;
+        self.last_seen = now;
+        self.uplinks += 1;
+    }
+
+    // Uplinks per minute
+    pub fn rate(&self) -> Option<f32> {
+        self.interval
+            .filter(|i| !i.is_zero())
+            .map(|i| 60.0 / i.as_secs_f32())
+    }
+}
+
+// Weight of the last uplink in the smoothed statistics
+const SMOOTHING: f32 = 0.25;
+
+// Statistics of end devices not heard for this long are forgotten
+const STATS_EXPIRY: Duration = Duration::from_secs(24 * 3600);
+
+// Bounds of the period of the idle eviction timer, a fraction of the idle
+// timeout
+const MIN_EVICTION_PERIOD: Duration = Duration::from_millis(10);
+const MAX_EVICTION_PERIOD: Duration = Duration::from_secs(10);
+
+struct Instance {
+    supervisor: Supervisor,
+    last_used: Instant,
+}
+
+// What virtual devices need to run, shared by all of them
+struct Launcher {
+    broker: Broker,
+    discovery: Arc<dyn ServiceDiscovery>,
+    publishers: Option<Arc<Publishers>>,
+    modules: ModuleCache,
+    state_dir: Option<PathBuf>,
+    limits: Limits,
+    // wasix runs on this runtime, also used by host_fs, shared by all the
+    // virtual devices and their restarts
+    runtime: Runtime,
+}
+
+struct Ctrl {
+    broker: Broker,
+    config: Config,
+    launcher: Arc<Launcher>,
+    registry: HashMap<u64, Instance>,
+    paused: HashSet<u64>,
+    // not instantiated until their previous virtual device returned
+    reloading: HashSet<u64>,
+    stats: HashMap<u64, DevStats>,
+}
+
+// Shared by the demultiplexer, which instantiates virtual devices on
+// uplinks, and the control plane listener
 pub struct VirtDevCtrl {
-    registry: HashMap<u64, thread::JoinHandle<()>>,
-    _listener: JoinHandle<()>,
+    ctrl: Arc<Mutex<Ctrl>>,
+    _listeners: Vec<JoinHandle<()>>,
 }
 
 impl VirtDevCtrl {
-    pub fn new(sub_pipe: Pipe) -> Self {
-        Self {
+    pub fn new(
+        broker: &Broker,
+        config: Config,
+        discovery: Arc<dyn ServiceDiscovery>,
+    ) -> Result<Self> {
+        let launcher = Arc::new(Launcher {
+            broker: broker.clone(),
+            discovery,
+            publishers: config.publishers.clone(),
+            modules: ModuleCache::new(config.limits, config.module_cache.clone()),
+            state_dir: config.state_dir.clone(),
+            limits: config.limits,
+            runtime: tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .map_err(Error::Io)?,
+        });
+        let idle_timeout = config.idle_timeout;
+        let ctrl = Arc::new(Mutex::new(Ctrl {
+            broker: broker.clone(),
+            config,
+            launcher,
             registry: HashMap::new(),
-            _listener: Self::listen(sub_pipe),
-        }
+            paused: HashSet::new(),
+            reloading: HashSet::new(),
+            stats: HashMap::new(),
+        }));
+        let period = (idle_timeout / 4).clamp(MIN_EVICTION_PERIOD, MAX_EVICTION_PERIOD);
+        let listeners = vec![
+            Self::listen(broker.subscribe(UPLINKS)?, ctrl.clone()),
+            Self::listen(broker.subscribe(GW_CTRL)?, ctrl.clone()),
+            Self::evict_idle(Arc::downgrade(&ctrl), period),
+        ];
+        Ok(Self {
+            ctrl,
+            _listeners: listeners,
+        })
     }
 
-    fn listen(sub_pipe: Pipe) -> JoinHandle<()> {
-        let mut receiver = BufReader::new(sub_pipe);
-        thread::spawn(move || loop {
-            let msg = msg::deserialize_from(receiver.by_ref()).expect("failed to read from pipe");
-            println!("[vdctrl] recv: {:?}", msg);
-            let todo = true; // TODO: Implement vdctrl logic on msg
-            thread::sleep(Duration::from_millis(20));
+    // Control plane: uplinks update the device statistics, GwCtrl commands
+    // (only on the gateway topic, not from virtual devices) manage them
+    fn listen(mut sub: Subscription, ctrl: Arc<Mutex<Ctrl>>) -> JoinHandle<()> {
+        let mut receiver = FrameReader::new(sub.take_reader().expect("reader already taken"));
+        thread::spawn(move || {
+            // unsubscribes when the thread ends
+            let _sub = sub;
+            loop {
+                // every uplink goes through here, decoded without copying its payload
+                let msg = match receiver.next_frame().map(message::deserialize_ref) {
+                    Ok(Ok(msg)) => msg,
+                    Ok(Err(_)) => continue,
+                    Err(msg::Error::Closed) => break,
+                    Err(e) => {
+                        eprintln!("[vdctrl] {e}");
+                        break;
+                    }
+                };
+                match msg {
+                    MessageRef::Uplink(up) | MessageRef::ConfirmedUplink(up) => {
+                        ctrl.lock().unwrap().seen(up.msg.addr, &up.meta)
+                    }
+                    MessageRef::GwCtrl(cmd) => {
+                        println!("[vdctrl] command: {cmd:?}");
+                        Ctrl::command(&ctrl, cmd)
+                    }
+                    _ => (),
+                }
+            }
         })
     }
 
-    pub fn instantiate_if_new(&mut self, broker: &mut Broker, deveui: u64) {
-        if !self.registry.contains_key(&deveui) {
-            let handle = {
-                let wasm_path = self.discover_service(deveui);
-                let sub_pipe = broker.subscribe(format!("{deveui:08x}"));
-                self.run_virt_dev(sub_pipe, deveui, wasm_path)
+    // Idle virtual devices are stopped on a timer, not only on the next
+    // uplink of any end device. The timer ends with the controller.
+    fn evict_idle(ctrl: Weak<Mutex<Ctrl>>, period: Duration) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            thread::sleep(period);
+            let Some(ctrl) = ctrl.upgrade() else {
+                break;
             };
-            self.registry.insert(deveui, handle);
+            let mut ctrl = ctrl.lock().unwrap();
+            ctrl.evict_idle(Instant::now());
+        })
+    }
+
+    // Supervision status of the virtual device of an end device
+    pub fn status(&self, deveui: u64) -> Option<Status> {
+        let ctrl = self.ctrl.lock().unwrap();
+        ctrl.registry.get(&deveui).map(|i| i.supervisor.status())
+    }
+
+    pub fn statuses(&self) -> Vec<(u64, Status)> {
+        let ctrl = self.ctrl.lock().unwrap();
+        let mut statuses: Vec<_> = ctrl
+            .registry
+            .iter()
+            .map(|(deveui, i)| (*deveui, i.supervisor.status()))
+            .collect();
+        statuses.sort_by_key(|(deveui, _)| *deveui);
+        statuses
+    }
+
+    pub fn stats(&self, deveui: u64) -> Option<DevStats> {
+        self.ctrl.lock().unwrap().stats.get(&deveui).cloned()
+    }
+
+    pub fn is_paused(&self, deveui: u64) -> bool {
+        self.ctrl.lock().unwrap().paused.contains(&deveui)
+    }
+
+    // Called on every uplink: evicted virtual devices are instantiated again,
+    // paused ones are not
+    pub fn instantiate_if_new(&mut self, deveui: u64) {
+        self.ctrl.lock().unwrap().instantiate_if_new(deveui)
+    }
+}
+
+impl Ctrl {
+    fn seen(&mut self, deveui: u64, meta: &RxMeta) {
+        let now = Instant::now();
+        match self.stats.get_mut(&deveui) {
+            Some(stats) => stats.update(now, meta),
+            None => {
+                self.stats
+                    .retain(|_, s| now.duration_since(s.last_seen) < STATS_EXPIRY);
+                self.stats.insert(deveui, DevStats::new(now, meta));
+            }
+        }
+    }
+
+    // Reload waits for the previous virtual device to return before starting
+    // the new one, e.g. not to share its state directory, without holding
+    // the controller meanwhile. Uplinks of the end device are not delivered
+    // until then.
+    fn command(ctrl: &Mutex<Ctrl>, cmd: GwCmd) {
+        let mut this = ctrl.lock().unwrap();
+        match cmd {
+            GwCmd::Load(deveui) => {
+                if this.paused.remove(&deveui) {
+                    this.lifecycle(deveui, "resumed", "");
+                }
+                this.instantiate_if_new(deveui)
+            }
+            GwCmd::Unload(deveui) => {
+                this.stop(deveui, "unloaded");
+            }
+            GwCmd::Reload(deveui) => {
+                this.reloading.insert(deveui);
+                let previous = this.stop(deveui, "reloaded");
+                drop(this);
+                if let Some(previous) = previous {
+                    previous.join();
+                }
+                let mut this = ctrl.lock().unwrap();
+                this.reloading.remove(&deveui);
+                this.instantiate_if_new(deveui)
+            }
+            GwCmd::Pause(deveui) => {
+                this.stop(deveui, "paused");
+                if this.paused.insert(deveui) {
+                    this.lifecycle(deveui, "paused", "");
+                }
+            }
+            GwCmd::Resume(deveui) => {
+                if this.paused.remove(&deveui) {
+                    this.lifecycle(deveui, "resumed", "");
+                    this.instantiate_if_new(deveui)
+                }
+            }
+        }
+    }
+
+    // Lifecycle events of the virtual devices are published by the gateway
+    // as Events of their end device, e.g. on "event/00000001/running"
+    fn lifecycle(&self, deveui: u64, name: &str, reason: &str) {
+        publish_lifecycle(&self.broker, deveui, name, reason)
+    }
+
+    fn instantiate_if_new(&mut self, deveui: u64) {
+        let now = Instant::now();
+        self.evict_idle(now);
+        if self.paused.contains(&deveui) || self.reloading.contains(&deveui) {
+            return;
+        }
+        if let Some(instance) = self.registry.get_mut(&deveui) {
+            instance.last_used = now;
+            return;
+        }
+        if self.registry.len() >= self.config.max_instances {
+            self.evict_lru();
+        }
+
+        // subscribe now, not to miss the uplink being dispatched
+        let queue = self.config.queue;
+        let mut sub = Some(
+            self.broker
+                .subscribe_with(&demux::uplink_topic(deveui), queue)
+                .expect("bad uplink topic"),
+        );
+        self.lifecycle(deveui, "loaded", "");
+        let launcher = self.launcher.clone();
+        let watcher = {
+            let broker = self.broker.clone();
+            move |status: &Status| {
+                let name = format!("{:?}", status.state).to_lowercase();
+                let reason = match status.state {
+                    State::Crashed | State::Disabled => status.last_error.as_deref(),
+                    _ => None,
+                };
+                publish_lifecycle(&broker, deveui, &name, reason.unwrap_or_default())
+            }
+        };
+        let supervisor = Supervisor::spawn(
+            format!("vd-{deveui:08x}"),
+            self.config.restart,
+            move |h| {
+                let sub = match sub.take() {
+                    Some(sub) => sub,
+                    None => launcher
+                        .broker
+                        .subscribe_with(&demux::uplink_topic(deveui), queue)
+                        .map_err(|e| e.to_string())?,
+                };
+                launcher.run_virt_dev(sub, deveui, h)
+            },
+            watcher,
+        );
+        self.registry.insert(
+            deveui,
+            Instance {
+                supervisor,
+                last_used: now,
+            },
+        );
+    }
+
+    fn evict_idle(&mut self, now: Instant) {
+        let idle: Vec<u64> = self
+            .registry
+            .iter()
+            .filter(|(_, i)| now.duration_since(i.last_used) >= self.config.idle_timeout)
+            .map(|(deveui, _)| *deveui)
+            .collect();
+        for deveui in idle {
+            self.stop(deveui, "idle");
+        }
+    }
+
+    fn evict_lru(&mut self) {
+        let lru = self
+            .registry
+            .iter()
+            .min_by_key(|(_, i)| i.last_used)
+            .map(|(deveui, _)| *deveui);
+        if let Some(deveui) = lru {
+            self.stop(deveui, "least recently used");
+        }
+    }
+
+    // Closing its stdin stops the virtual device, without waiting for it
+    // unless its supervisor is joined
+    fn stop(&mut self, deveui: u64, reason: &str) -> Option<Supervisor> {
+        let instance = self.registry.remove(&deveui)?;
+        println!("[vdctrl] stop vd-{deveui:08x} ({reason}).");
+        instance.supervisor.stop();
+        self.lifecycle(deveui, "unloaded", reason);
+        Some(instance.supervisor)
+    }
+}
+
+impl Launcher {
+    // Blocks until the virtual device exits. The subscription ends with it.
+    fn run_virt_dev(
+        &self,
+        mut sub: Subscription,
+        deveui: u64,
+        handle: &Handle,
+    ) -> std::result::Result<(), String> {
+        let service = self.discovery.discover(deveui).map_err(|e| e.to_string())?;
+        let path = &service.module;
+        println!(
+            "[vdctrl] vd-{deveui:08x}: {} {} by {}.",
+            path.display(),
+            service.version,
+            service.owner
+        );
+        let wasm_bytes = fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        // the bytes verified are the bytes compiled
+        self.verify_module(deveui, &service, &wasm_bytes)
+            .map_err(|e| {
+                println!("[vdctrl] vd-{deveui:08x}: {e}.");
+                e.to_string()
+            })?;
+        let (engine, module) = self.modules.get(&wasm_bytes)?;
+        let mut store = Store::new(engine);
+
+        let runtime = self.runtime.handle();
+        let _guard = runtime.enter();
+
+        let policy = &service.policy;
+        let mut builder = WasiEnv::builder(format!("vd-{:08x}", deveui))
+            .args(&policy.args)
+            .envs(&policy.env);
+        match self.state_dir {
+            Some(ref dir) if !policy.dirs.is_empty() => {
+                let root = dir.join(format!("{deveui:08x}"));
+                builder = builder.fs(Box::new(host_fs::FileSystem::new(runtime.clone())));
+                for (guest, host) in policy.dirs(&root) {
+                    fs::create_dir_all(&host).map_err(|e| format!("{}: {e}", host.display()))?;
+                    builder = builder.map_dir(&guest, host).map_err(|e| e.to_string())?;
+                }
+            }
+            _ => (),
+        }
+
+        let (stdout, output) = Pipe::channel();
+        let publisher = Self::publish_output(self.broker.clone(), deveui, output);
+        let closer = sub.closer();
+        handle.running(move || closer.close());
+        let result = builder
+            .stdin(Box::new(sub.take_reader().expect("reader already taken")))
+            .stdout(Box::new(stdout))
+            .finalize(&mut store)
+            .map_err(|e| e.to_string())
+            .and_then(|wasi| limits::run(&mut store, &module, wasi, &self.limits, policy));
+        // the stdout of the virtual device goes with its store: the rest of
+        // its output is published before it is restarted
+        drop(store);
+        if publisher.join().is_err() {
+            println!("[vdctrl] vd-{deveui:08x}: output thread panicked.");
+        }
+        result.map_err(|e| {
+            println!("[vdctrl] vd-{deveui:08x}: {e}.");
+            e
+        })
+    }
+
+    fn verify_module(&self, deveui: u64, service: &Service, bytes: &[u8]) -> Result<()> {
+        if let Some(ref sha256) = service.sha256 {
+            trust::verify_hash(bytes, sha256)?;
         }
+        if let Some(ref publishers) = self.publishers {
+            let signature = fs::read_to_string(trust::signature_path(&service.module)).ok();
+            let publisher = publishers.verify(bytes, signature.as_deref())?;
+            println!("[vdctrl] vd-{deveui:08x}: signed by {publisher}.");
+        }
+        Ok(())
     }
 
-    fn run_virt_dev(&mut self, sub_pipe: Pipe, deveui: u64, wasm_path: String) -> JoinHandle<()> {
+    // Publish the messages written by a virtual device until it exits. A
+    // virtual device speaks only for its own end device, and cannot forge
+    // uplinks.
+    fn publish_output<R>(broker: Broker, deveui: u64, output: R) -> JoinHandle<()>
+    where
+        R: Read + Send + 'static,
+    {
         thread::spawn(move || {
-            let wasm_bytes = std::fs::read(&wasm_path)
-                .expect(format!("Wasm binary expected at {}", wasm_path).as_str());
-            let mut store = Store::default();
-            let module = Module::new(&store, wasm_bytes).expect("failed to create wasm module");
-            WasiEnv::builder(format!("vd-{:08x}", deveui))
-                .stdin(Box::new(sub_pipe))
-                .run_with_store(module, &mut store)
-                .expect("failed to run virt dev wasm");
+            let mut output = FrameReader::new(output);
+            loop {
+                let msg = match output.read_message() {
+                    Ok(msg) => msg,
+                    Err(msg::Error::Closed) => break,
+                    Err(e) => {
+                        eprintln!("[vdctrl] vd-{deveui:08x} output: {e}");
+                        break;
+                    }
+                };
+                let allowed = match msg {
+                    Message::Uplink(_) | Message::ConfirmedUplink(_) => false,
+                    Message::Event { ref name, .. } if name.contains(['/', '+', '#']) => false,
+                    _ => msg.addr().map_or(true, |addr| addr == deveui),
+                };
+                if !allowed {
+                    println!("[vdctrl] vd-{deveui:08x} output rejected: {msg:?}");
+                    continue;
+                }
+                broker.publish(demux::output_topic(deveui, &msg), msg);
+            }
+            if output.skipped() > 0 {
+                println!(
+                    "[vdctrl] vd-{deveui:08x} wrote {} bytes of unframed output.",
+                    output.skipped()
+                );
+            }
+        })
+    }
+}
+
+// Commands of the operator, one per line, e.g. "reload 0000abcd"
+pub fn parse_command(line: &str) -> Result<GwCmd> {
+    let bad = || Error::BadCommand(line.to_string());
+    let mut words = line.split_whitespace();
+    let (Some(cmd), Some(addr), None) = (words.next(), words.next(), words.next()) else {
+        return Err(bad());
+    };
+    let deveui = u64::from_str_radix(addr, 16).map_err(|_| bad())?;
+    match cmd {
+        "load" => Ok(GwCmd::Load(deveui)),
+        "unload" => Ok(GwCmd::Unload(deveui)),
+        "reload" => Ok(GwCmd::Reload(deveui)),
+        "pause" => Ok(GwCmd::Pause(deveui)),
+        "resume" => Ok(GwCmd::Resume(deveui)),
+        _ => Err(bad()),
+    }
+}
+
+// Publish the commands read from the operator (e.g. the stdin of the
+// gateway) on the gwctrl topic, until the end of its input
+pub fn read_commands<R: BufRead>(broker: &Broker, input: R) {
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("[vdctrl] commands: {e}");
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_command(&line) {
+            Ok(cmd) => {
+                let msg = Message::GwCtrl(cmd);
+                broker.publish(demux::topic(&msg), msg)
+            }
+            Err(e) => println!("[vdctrl] {e}, expected <load|unload|reload|pause|resume> <addr>."),
+        }
+    }
+}
+
+fn publish_lifecycle(broker: &Broker, deveui: u64, name: &str, reason: &str) {
+    let event = Message::Event {
+        addr: deveui,
+        name: name.to_string(),
+        data: reason.as_bytes().to_vec(),
+    };
+    broker.publish(demux::topic(&event), event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueReader;
+
+    // Virtual devices of these tests crash at once, looking for their
+    // driver, and are not restarted before the end of the test
+    struct NoDriver;
+
+    impl ServiceDiscovery for NoDriver {
+        fn discover(&self, deveui: u64) -> Result<Service> {
+            Err(Error::NoDriver(deveui))
+        }
+    }
+
+    fn config(idle_timeout: Duration, max_instances: usize) -> Config {
+        Config {
+            restart: RestartPolicy {
+                backoff: Duration::from_secs(60),
+                ..RestartPolicy::default()
+            },
+            idle_timeout,
+            max_instances,
+            ..Config::default()
+        }
+    }
+
+    fn running(vdctrl: &VirtDevCtrl) -> Vec<u64> {
+        vdctrl
+            .statuses()
+            .into_iter()
+            .map(|(deveui, _)| deveui)
+            .collect()
+    }
+
+    type Events = FrameReader<QueueReader>;
+
+    // Lifecycle events of an end device, until the subscription is dropped
+    fn lifecycle(broker: &Broker, deveui: u64) -> (Subscription, Events) {
+        let mut sub = broker.subscribe(&format!("event/{deveui:08x}/+")).unwrap();
+        let events = FrameReader::new(sub.take_reader().unwrap());
+        (sub, events)
+    }
+
+    // Name and reason of the next lifecycle event
+    fn next_event(events: &mut Events) -> (String, String) {
+        match events.read_message().unwrap() {
+            Message::Event { name, data, .. } => (name, String::from_utf8(data).unwrap()),
+            msg => panic!("not an event: {msg:?}"),
+        }
+    }
+
+    // Reason of the next unloaded event
+    fn unloaded(events: &mut Events) -> String {
+        loop {
+            let (name, reason) = next_event(events);
+            if name == "unloaded" {
+                return reason;
+            }
+        }
+    }
+
+    #[test]
+    fn idle_eviction() {
+        let broker = Broker::new();
+        let config = config(Duration::from_millis(100), 256);
+        let mut vdctrl = VirtDevCtrl::new(&broker, config, Arc::new(NoDriver)).unwrap();
+        let (_sub, mut events) = lifecycle(&broker, 1);
+        vdctrl.instantiate_if_new(1);
+        assert_eq!(running(&vdctrl), [1]);
+
+        // without any other uplink
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !running(&vdctrl).is_empty() {
+            assert!(Instant::now() < deadline, "idle virtual device not evicted");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(unloaded(&mut events), "idle");
+
+        // instantiated again on its next uplink
+        vdctrl.instantiate_if_new(1);
+        assert_eq!(running(&vdctrl), [1]);
+    }
+
+    #[test]
+    fn lru_cap() {
+        let broker = Broker::new();
+        let config = config(Duration::from_secs(600), 2);
+        let mut vdctrl = VirtDevCtrl::new(&broker, config, Arc::new(NoDriver)).unwrap();
+        let (_sub, mut events) = lifecycle(&broker, 2);
+        for deveui in [1, 2] {
+            vdctrl.instantiate_if_new(deveui);
+            thread::sleep(Duration::from_millis(2));
+        }
+        // an uplink of 1 makes 2 the least recently used
+        vdctrl.instantiate_if_new(1);
+        thread::sleep(Duration::from_millis(2));
+        vdctrl.instantiate_if_new(3);
+        assert_eq!(running(&vdctrl), [1, 3]);
+        assert_eq!(unloaded(&mut events), "least recently used");
+
+        vdctrl.instantiate_if_new(2);
+        assert_eq!(running(&vdctrl), [2, 3]);
+    }
+
+    fn uplink(deveui: u64, rssi: i32, snr: i32) -> Message {
+        Message::Uplink(msg::uplink::Uplink {
+            meta: RxMeta {
+                gateway: 0,
+                time: 0,
+                rssi,
+                snr,
+                freq: 868100000,
+                sf: 7,
+            },
+            msg: msg::Msg {
+                addr: deveui,
+                fcnt: 0,
+                port: 1,
+                payload: Vec::new(),
+            },
         })
     }
 
-    /// Call to hyperledger to get binary
-    fn discover_service(&self, _deveui: u64) -> String {
-        let todo = true; // TODO: Implement service discovery
-        "./target/wasm32-wasi/release/virt_dev.wasm".to_string()
+    // Waits for the listeners of the controller to handle what was published
+    fn until(what: &str, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "{what}");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let broker = Broker::new();
+        let vdctrl = VirtDevCtrl::new(&broker, Config::default(), Arc::new(NoDriver)).unwrap();
+        for (rssi, snr) in [(-100, -4), (-60, 8)] {
+            let up = uplink(1, rssi, snr);
+            broker.publish(demux::topic(&up), up);
+            thread::sleep(Duration::from_millis(20));
+        }
+        until("uplinks not seen", || {
+            vdctrl.stats(1).is_some_and(|s| s.uplinks == 2)
+        });
+        let stats = vdctrl.stats(1).unwrap();
+        assert!(stats.last_seen > stats.first_seen);
+        assert!(stats.interval.unwrap() >= Duration::from_millis(20));
+        assert!(stats.rate().unwrap() > 0.0);
+        // the last uplink weighs a quarter
+        assert_eq!(stats.rssi, -90.0);
+        assert_eq!(stats.snr, -1.0);
+        assert!(vdctrl.stats(2).is_none());
+    }
+
+    #[test]
+    fn commands() {
+        let broker = Broker::new();
+        let mut vdctrl = VirtDevCtrl::new(&broker, Config::default(), Arc::new(NoDriver)).unwrap();
+        let (_sub, mut events) = lifecycle(&broker, 1);
+        let commands = "load 1\n\npause 1\nbogus 1\n";
+        read_commands(&broker, commands.as_bytes());
+        until("not paused", || vdctrl.is_paused(1));
+        assert_eq!(unloaded(&mut events), "paused");
+        // uplinks of paused devices are not delivered
+        vdctrl.instantiate_if_new(1);
+        assert!(running(&vdctrl).is_empty());
+
+        read_commands(&broker, "resume 1\n".as_bytes());
+        until("not resumed", || running(&vdctrl) == [1]);
+        assert!(!vdctrl.is_paused(1));
+
+        read_commands(&broker, "unload 1\n".as_bytes());
+        until("not unloaded", || running(&vdctrl).is_empty());
+        assert_eq!(unloaded(&mut events), "unloaded");
+    }
+
+    #[test]
+    fn reload() {
+        let broker = Broker::new();
+        let mut vdctrl = VirtDevCtrl::new(&broker, Config::default(), Arc::new(NoDriver)).unwrap();
+        vdctrl.instantiate_if_new(1);
+        let (_sub, mut events) = lifecycle(&broker, 1);
+        read_commands(&broker, "reload 1\n".as_bytes());
+        assert_eq!(unloaded(&mut events), "reloaded");
+        // the previous virtual device stopped before the next one is loaded
+        let mut stopped = false;
+        loop {
+            match next_event(&mut events).0.as_str() {
+                "stopped" => stopped = true,
+                "loaded" => break,
+                _ => (),
+            }
+        }
+        assert!(stopped);
+        assert_eq!(running(&vdctrl), [1]);
+    }
+
+    #[test]
+    fn bad_commands() {
+        assert_eq!(
+            parse_command("reload 0000abcd").unwrap(),
+            GwCmd::Reload(0xabcd)
+        );
+        assert_eq!(parse_command(" pause  1 ").unwrap(), GwCmd::Pause(1));
+        for line in ["", "load", "load xyz", "load 1 2", "start 1"] {
+            assert!(
+                matches!(parse_command(line), Err(Error::BadCommand(_))),
+                "{line}"
+            );
+        }
     }
 }
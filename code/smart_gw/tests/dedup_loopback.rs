@@ -0,0 +1,176 @@
+// Copyright 2024 University of Bologna
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Multi-process test of the cross-gateway deduplication on loopback sockets
+//
+//   cargo test -p smart_gw --test dedup_loopback
+//
+// The test spawns one process per gateway, running this test binary again
+// with the id of the gateway in GATEWAY_ENV. Gateways hear overlapping
+// subsets of the same uplinks with different RSSI, elect the delivering one
+// through smart_gw::dedup, and report what they delivered. The test checks
+// that every uplink was delivered exactly once, by the expected gateway.
+//
+
+use msg::uplink::{RxMeta, Uplink};
+use msg::Msg;
+use smart_gw::dedup::Dedup;
+
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, iter};
+
+// "<gateway id> <start time>" of a gateway process
+const GATEWAY_ENV: &str = "DEDUP_LOOPBACK_GATEWAY";
+
+const GATEWAYS: u64 = 3;
+const UPLINKS: u32 = 12;
+const BASE_PORT: u16 = 47800;
+const PERIOD: Duration = Duration::from_millis(400);
+const WINDOW: Duration = Duration::from_millis(150);
+
+// End device whose home is gateway 1
+const HOME_DEV: u64 = 0x2;
+
+// Reception of uplink k at gateway gw (ids start at 1), if heard at all
+fn reception(gw: u64, k: u32) -> Option<RxMeta> {
+    if (k as u64 + gw) % 4 == 0 {
+        return None;
+    }
+    Some(RxMeta {
+        gateway: gw,
+        time: 0,
+        rssi: -60 - ((k as u64 * 7 + gw * 13) % 50) as i32,
+        snr: 5,
+        freq: 868100000,
+        sf: 7,
+    })
+}
+
+fn addr(k: u32) -> u64 {
+    1 + k as u64 % 2
+}
+
+fn expected_winner(k: u32) -> Option<u64> {
+    let heard: Vec<RxMeta> = (1..=GATEWAYS).filter_map(|gw| reception(gw, k)).collect();
+    if addr(k) == HOME_DEV && heard.iter().any(|m| m.gateway == 1) {
+        return Some(1);
+    }
+    heard
+        .iter()
+        .max_by_key(|m| (m.rssi, m.snr, std::cmp::Reverse(m.gateway)))
+        .map(|m| m.gateway)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn socket(gw: u64) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], BASE_PORT + gw as u16))
+}
+
+fn gateway(gw: u64, start: u64) {
+    let peers = (1..=GATEWAYS).filter(|p| *p != gw).map(socket).collect();
+    let homes: HashSet<u64> = iter::once(HOME_DEV).filter(|_| gw == 1).collect();
+    let mut dedup = Dedup::new(gw, socket(gw), peers, homes, WINDOW).unwrap();
+
+    for k in 0..UPLINKS {
+        let at = start + k as u64 * PERIOD.as_millis() as u64;
+        deliver_until(&mut dedup, at);
+        let Some(meta) = reception(gw, k) else {
+            continue;
+        };
+        let up = Uplink {
+            meta,
+            msg: Msg {
+                addr: addr(k),
+                fcnt: k,
+                port: 1,
+                payload: vec![k as u8],
+            },
+        };
+        dedup.submit(up, false);
+    }
+    let end = start + UPLINKS as u64 * PERIOD.as_millis() as u64;
+    deliver_until(&mut dedup, end);
+}
+
+// Report the uplinks elected until the time at (in ms)
+fn deliver_until(dedup: &mut Dedup, at: u64) {
+    loop {
+        for (up, _) in dedup.poll() {
+            println!("deliver {}", up.msg.fcnt);
+        }
+        if now_ms() >= at {
+            break;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn dedup_loopback() {
+    if let Ok(args) = env::var(GATEWAY_ENV) {
+        let (gw, start) = args.split_once(' ').unwrap();
+        return gateway(gw.parse().unwrap(), start.parse().unwrap());
+    }
+
+    // all the gateways start together, once their sockets are bound
+    let start = now_ms() + 1000;
+    let exe = env::current_exe().unwrap();
+    let children: Vec<_> = (1..=GATEWAYS)
+        .map(|gw| {
+            Command::new(&exe)
+                .args(["--exact", "dedup_loopback", "--nocapture", "--quiet"])
+                .env(GATEWAY_ENV, format!("{gw} {start}"))
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    let mut delivered: Vec<Vec<u64>> = vec![Vec::new(); UPLINKS as usize];
+    for (gw, mut child) in (1..=GATEWAYS).zip(children) {
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        for line in stdout.lines().map(Result::unwrap) {
+            match line.strip_prefix("deliver ") {
+                Some(k) => delivered[k.parse::<usize>().unwrap()].push(gw),
+                None => println!("[gw {gw}] {line}"),
+            }
+        }
+        assert!(child.wait().unwrap().success(), "gateway {gw} failed");
+    }
+
+    let mut ok = true;
+    for (k, by) in delivered.iter().enumerate() {
+        let expected = expected_winner(k as u32);
+        let pass = by.len() == 1 && expected == Some(by[0]);
+        ok &= pass;
+        println!(
+            "uplink {k} ({:08x}): delivered by {by:?}, expected {expected:?} {}",
+            addr(k as u32),
+            if pass { "ok" } else { "FAIL" }
+        );
+    }
+    assert!(ok, "some uplinks were not delivered exactly once");
+}
@@ -13,21 +13,101 @@
 // limitations under the License.
 //
 
-use std::io::{self, Read};
+use msg::crypto::{AppKeys, Dir};
+use msg::lorawan;
+use msg::message::Message;
+use msg::stream::{self, FrameReader};
+use msg::uplink::Uplink;
+use std::io::{self, Write};
+use std::{env, fs, process};
 use std::{thread, time::Duration};
 
+// Application keys of the owner's end devices, one "<addr> <key>" line each,
+// provisioned in the private state directory of the device or wherever the
+// driver policy points CLUES_APP_KEYS to (e.g. "env": { "CLUES_APP_KEYS":
+// "/keys/app_keys" } with "/keys" among its dirs). Payloads of devices
+// without a key are left as they are.
+const APP_KEYS: &str = "/state/app_keys";
+
+// AppSKeys of the owner's LoRaWAN end devices, one "<DevAddr> <AppSKey>" line
+// each, in the same way (CLUES_APP_SKEYS). Their FRMPayload is encrypted as
+// LoRaWAN does, keyed by DevAddr and the full 32 bit FCnt.
+const APP_SKEYS: &str = "/state/app_skeys";
+
+// Uplinks received so far, kept across restarts if the gateway gives the
+// driver a state directory
+const RECEIVED: &str = "/state/received";
+
 fn main() {
-    let mut stdin = io::stdin();
+    let keys = load_keys("CLUES_APP_KEYS", APP_KEYS);
+    let lorawan_keys = load_keys("CLUES_APP_SKEYS", APP_SKEYS);
+    // corrupted frames are skipped, the gateway closes stdin to stop the driver
+    let mut stdin = FrameReader::new(io::stdin());
+    // stdout carries framed messages back to the gateway, logs go to stderr
+    let mut stdout = io::stdout().lock();
+    let mut received: u64 = fs::read_to_string(RECEIVED)
+        .ok()
+        .and_then(|n| n.trim().parse().ok())
+        .unwrap_or(0);
     loop {
-        let msg = match msg::deserialize_from(stdin.by_ref()) {
-            Ok(m) => m,
-            Err(e) => {
-                eprint!("{e}");
+        let Uplink { meta, mut msg } = match stdin.read_message() {
+            Ok(Message::Uplink(up) | Message::ConfirmedUplink(up)) => up,
+            Ok(other) => {
+                eprintln!("unexpected message: {other:?}");
                 continue;
             }
+            Err(msg::Error::Closed) => break,
+            // corrupted frames never get here, I/O errors do not go away
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1)
+            }
         };
-        println!("[vd-{:08x}] recv: {msg:?}", msg.addr);
-        let todo = true; // TODO: Internal driver implementation
+        // FPort 0 carries MAC commands, encrypted with the NwkSKey
+        match (lorawan_keys.get(msg.addr), keys.get(msg.addr)) {
+            (Some(key), _) if msg.port != 0 => {
+                let dev_addr = msg.addr as u32;
+                lorawan::crypt_frm_payload(key, Dir::Up, dev_addr, msg.fcnt, &mut msg.payload)
+            }
+            (None, Some(key)) => msg.decrypt(key, Dir::Up),
+            _ => {}
+        }
+        received += 1;
+        // without a state directory the count is just not kept
+        let _ = fs::write(RECEIVED, received.to_string());
+        eprintln!(
+            "[vd-{:08x}] recv #{received}: {msg:?} (RSSI: {} dBm, SNR: {} dB, gw: {:016x})",
+            msg.addr, meta.rssi, meta.snr, meta.gateway
+        );
+
+        // CayenneLPP readings are republished decoded, and logged as SenML
+        if let Ok(readings) = msg::lpp::decode(&msg.payload) {
+            let base_name = format!("urn:dev:clues:{:08x}:", msg.addr);
+            match msg::senml::to_json(&readings, Some(&base_name)) {
+                Ok(json) => eprintln!("[vd-{:08x}] senml: {json}", msg.addr),
+                Err(e) => eprintln!("{e}"),
+            }
+            let readings = Message::Readings {
+                addr: msg.addr,
+                readings,
+            };
+            // stdout is line buffered, frames are not lines
+            let sent = stream::write_message(&mut stdout, &readings)
+                .and_then(|_| stdout.flush().map_err(msg::Error::Io));
+            if let Err(e) = sent {
+                eprintln!("{e}");
+            }
+        }
+        // TODO: Internal driver implementation
         thread::sleep(Duration::from_millis(20));
     }
 }
+
+// Key table named by var, or at path. Devices have no key without a table.
+fn load_keys(var: &str, path: &str) -> AppKeys {
+    let path = env::var(var).unwrap_or_else(|_| path.to_string());
+    match fs::read_to_string(&path) {
+        Ok(text) => AppKeys::parse(&text).unwrap_or_else(|e| panic!("{path}: {e}")),
+        Err(_) => AppKeys::default(),
+    }
+}